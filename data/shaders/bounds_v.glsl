@@ -0,0 +1,25 @@
+#version 420 core
+#extension GL_ARB_shader_storage_buffer_object : require
+
+// boxes of occluded instances written by cull_c.glsl, as min and max
+layout (std430, binding = 6) readonly buffer Occluded {
+    uvec4 command;
+    vec4 boxes[];
+};
+
+uniform mat4 vp;
+
+// box edges as pairs of corners, corner bits are xyz
+const int edges[24] = int[](
+    0, 1, 2, 3, 4, 5, 6, 7,
+    0, 2, 1, 3, 4, 6, 5, 7,
+    0, 4, 1, 5, 2, 6, 3, 7
+);
+
+void main() {
+    int corner = edges[gl_VertexID];
+    vec3 bmin = boxes[2 * gl_InstanceID].xyz;
+    vec3 bmax = boxes[2 * gl_InstanceID + 1].xyz;
+    vec3 p = mix(bmin, bmax, vec3(corner & 1, (corner >> 1) & 1, (corner >> 2) & 1));
+    gl_Position = vp * vec4(p, 1.0);
+}
@@ -0,0 +1,162 @@
+#version 420 core
+#extension GL_ARB_compute_shader : require
+#extension GL_ARB_shader_storage_buffer_object : require
+
+layout (local_size_x = 64) in;
+
+// same layouts as gpu_cull.rs
+struct Instance {
+    mat4 model;
+    vec4 tint;
+    uvec4 info; // x = model index
+};
+struct Model {
+    mat4 decode;
+    vec4 sphere; // object space center and radius
+    vec4 box_min;
+    vec4 box_max;
+    uvec4 lods; // x = first command, y = detail levels, z = first error
+};
+struct Command {
+    uint count;
+    uint instance_count;
+    uint first_index;
+    int base_vertex;
+    uint base_instance;
+};
+struct DrawInstance {
+    mat4 model;
+    vec4 tint;
+    vec4 decode_scale;
+};
+
+layout (std430, binding = 1) readonly buffer Instances {
+    Instance instances[];
+};
+layout (std430, binding = 2) readonly buffer Models {
+    Model models[];
+};
+layout (std430, binding = 3) readonly buffer LodErrors {
+    float lod_errors[];
+};
+layout (std430, binding = 4) buffer Commands {
+    Command commands[];
+};
+layout (std430, binding = 5) writeonly buffer DrawInstances {
+    DrawInstance draw_instances[];
+};
+// world boxes of occluded instances as min and max, drawn by bounds_v.glsl.
+// The header is a DrawArraysIndirectCommand
+layout (std430, binding = 6) buffer Occluded {
+    uint vertex_count;
+    uint box_count;
+    uint first_vertex;
+    uint base_instance;
+    vec4 boxes[];
+};
+layout (binding = 0) uniform atomic_uint visible_count;
+
+uniform uint instance_count;
+// frustum planes facing inside
+uniform vec4 planes[6];
+uniform vec3 camera_position;
+// pixels per world unit at distance 1
+uniform float lod_scale;
+uniform float near;
+// test against the depth pyramid of the previous frame
+uniform bool occlusion;
+uniform mat4 prev_vp;
+uniform sampler2D depth_pyramid;
+uniform int pyramid_levels;
+
+bool is_visible(vec3 center, float radius, vec3 box_center, vec3 extent) {
+    for (int i = 0; i < 6; i++) {
+        vec4 p = planes[i];
+        if (dot(p.xyz, center) + p.w < -radius) {
+            return false;
+        }
+        if (dot(p.xyz, box_center) + p.w + dot(abs(p.xyz), extent) < 0.0) {
+            return false;
+        }
+    }
+    return true;
+}
+
+bool is_occluded(vec3 bmin, vec3 bmax) {
+    vec3 ndc_min = vec3(1.0);
+    vec3 ndc_max = vec3(-1.0);
+    for (int i = 0; i < 8; i++) {
+        vec3 p = mix(bmin, bmax, vec3(i & 1, (i >> 1) & 1, (i >> 2) & 1));
+        vec4 clip = prev_vp * vec4(p, 1.0);
+        // crosses the near plane, can't be tested
+        if (clip.w <= near) {
+            return false;
+        }
+        vec3 ndc = clip.xyz / clip.w;
+        ndc_min = min(ndc_min, ndc);
+        ndc_max = max(ndc_max, ndc);
+    }
+    vec2 uv_min = clamp(ndc_min.xy * 0.5 + 0.5, 0.0, 1.0);
+    vec2 uv_max = clamp(ndc_max.xy * 0.5 + 0.5, 0.0, 1.0);
+    float depth = ndc_min.z * 0.5 + 0.5;
+
+    // level where the box covers at most 2x2 texels
+    ivec2 size = textureSize(depth_pyramid, 0);
+    vec2 extent = (uv_max - uv_min) * vec2(size);
+    int level = int(ceil(log2(max(max(extent.x, extent.y), 1.0))));
+    level = clamp(level, 0, pyramid_levels - 1);
+    ivec2 level_size = textureSize(depth_pyramid, level);
+    // levels halve rounding down, so texels are mapped from level 0, the last
+    // row and column of a level also cover the leftover texels of odd sizes
+    ivec2 p0 = min(min(ivec2(uv_min * vec2(size)), size - 1) >> level, level_size - 1);
+    ivec2 p1 = min(min(ivec2(uv_max * vec2(size)), size - 1) >> level, level_size - 1);
+    float farthest = max(
+        max(texelFetch(depth_pyramid, p0, level).r, texelFetch(depth_pyramid, ivec2(p1.x, p0.y), level).r),
+        max(texelFetch(depth_pyramid, ivec2(p0.x, p1.y), level).r, texelFetch(depth_pyramid, p1, level).r)
+    );
+    return depth > farthest;
+}
+
+void main() {
+    uint id = gl_GlobalInvocationID.x;
+    if (id >= instance_count) {
+        return;
+    }
+    Instance instance = instances[id];
+    Model m = models[instance.info.x];
+    mat4 mtx = instance.model;
+
+    float scale = max(length(mtx[0].xyz), max(length(mtx[1].xyz), length(mtx[2].xyz)));
+    vec3 center = (mtx * vec4(m.sphere.xyz, 1.0)).xyz;
+    float radius = m.sphere.w * scale;
+    vec3 box_center = (mtx * vec4((m.box_min.xyz + m.box_max.xyz) * 0.5, 1.0)).xyz;
+    vec3 half_size = (m.box_max.xyz - m.box_min.xyz) * 0.5;
+    mat3 abs_mtx = mat3(abs(mtx[0].xyz), abs(mtx[1].xyz), abs(mtx[2].xyz));
+    vec3 extent = abs_mtx * half_size;
+    if (!is_visible(center, radius, box_center, extent)) {
+        return;
+    }
+    if (occlusion && is_occluded(box_center - extent, box_center + extent)) {
+        uint box = atomicAdd(box_count, 1);
+        boxes[2 * box] = vec4(box_center - extent, 1.0);
+        boxes[2 * box + 1] = vec4(box_center + extent, 1.0);
+        return;
+    }
+    atomicCounterIncrement(visible_count);
+
+    // coarsest level with error under a pixel
+    float distance = max(length(center - camera_position) - radius, near);
+    float pixels_per_unit = lod_scale * scale / distance;
+    uint level = 0;
+    while (level + 1 < m.lods.y && lod_errors[m.lods.z + level] * pixels_per_unit <= 1.0) {
+        level++;
+    }
+
+    uint command = m.lods.x + level;
+    uint slot = atomicAdd(commands[command].instance_count, 1);
+    draw_instances[commands[command].base_instance + slot] = DrawInstance(
+        mtx * m.decode,
+        instance.tint,
+        vec4(m.decode[0][0], m.decode[1][1], m.decode[2][2], 0.0)
+    );
+}
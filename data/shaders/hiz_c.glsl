@@ -0,0 +1,48 @@
+#version 420 core
+#extension GL_ARB_compute_shader : require
+
+layout (local_size_x = 8, local_size_y = 8) in;
+
+// depth buffer, read for level 0
+uniform sampler2D depth;
+// previous level, read for every other level
+layout (r32f, binding = 0) uniform readonly image2D src;
+layout (r32f, binding = 1) uniform writeonly image2D dst;
+uniform bool from_depth;
+uniform ivec2 src_size;
+uniform ivec2 dst_size;
+
+float load(ivec2 p) {
+    p = min(p, src_size - 1);
+    return from_depth ? texelFetch(depth, p, 0).r : imageLoad(src, p).r;
+}
+
+void main() {
+    ivec2 p = ivec2(gl_GlobalInvocationID.xy);
+    if (any(greaterThanEqual(p, dst_size))) {
+        return;
+    }
+    if (from_depth) {
+        imageStore(dst, p, vec4(load(p)));
+        return;
+    }
+    // farthest depth of the covered texels
+    ivec2 s = 2 * p;
+    float d = max(
+        max(load(s), load(s + ivec2(1, 0))),
+        max(load(s + ivec2(0, 1)), load(s + ivec2(1, 1)))
+    );
+    // with odd sizes the last texel also covers the extra row or column
+    bool extra_x = (src_size.x & 1) == 1 && p.x == dst_size.x - 1;
+    bool extra_y = (src_size.y & 1) == 1 && p.y == dst_size.y - 1;
+    if (extra_x) {
+        d = max(d, max(load(s + ivec2(2, 0)), load(s + ivec2(2, 1))));
+    }
+    if (extra_y) {
+        d = max(d, max(load(s + ivec2(0, 2)), load(s + ivec2(1, 2))));
+    }
+    if (extra_x && extra_y) {
+        d = max(d, load(s + ivec2(2, 2)));
+    }
+    imageStore(dst, p, vec4(d));
+}
@@ -1,17 +1,65 @@
 #version 420 core
+#ifdef MULTI_DRAW
+#extension GL_ARB_shader_storage_buffer_object : require
+#endif
 
 in vec2 uv;
 in vec3 normal;
+in vec3 world_position;
+in vec4 tint;
 
 layout (location = 0) out vec4 color;
 
 uniform float near;
 uniform float far;
+#ifdef MULTI_DRAW
+// same layout as GpuMaterial
+struct Material {
+    vec4 ambient;
+    vec4 diffuse;
+    vec4 specular;
+    vec4 params;
+    ivec4 opts;
+};
+layout (std430, binding = 0) readonly buffer Materials {
+    Material materials[];
+};
+flat in int draw_id;
+#define ambient_color materials[draw_id].ambient.rgb
+#define diffuse_color materials[draw_id].diffuse.rgb
+#define specular_color materials[draw_id].specular.rgb
+#define shininess materials[draw_id].params.y
+#else
 uniform vec3 ambient_color;
 uniform vec3 diffuse_color;
 uniform vec3 specular_color;
+uniform float shininess;
+#endif
 uniform sampler2D diffuse_texture;
 uniform ivec3 opts;
+uniform vec3 camera_position;
+uniform vec3 light_position;
+uniform float light_power;
+// ambient, diffuse and specular terms, 0 or 1
+uniform ivec3 light_intensity;
+
+// Blinn-Phong lit color of a surface with diffuse color `base`
+vec3 shade(vec3 base) {
+    vec3 n = normalize(normal);
+    if (!gl_FrontFacing) {
+        n = -n;
+    }
+    vec3 to_light = light_position - world_position;
+    float attenuation = light_power / dot(to_light, to_light);
+    vec3 l = normalize(to_light);
+    vec3 v = normalize(camera_position - world_position);
+    vec3 h = normalize(l + v);
+    float n_dot_l = max(dot(n, l), 0.0);
+    float specular = n_dot_l > 0.0 ? pow(max(dot(n, h), 0.0), shininess) : 0.0;
+    return vec3(light_intensity.x) * 0.1 * ambient_color * base
+        + vec3(light_intensity.y) * base * n_dot_l * attenuation
+        + vec3(light_intensity.z) * specular_color * specular * attenuation;
+}
 
 float linear_depth(float depth) {
     float ndc = depth * 2.0 - 1.0;
@@ -24,7 +72,7 @@ void main() {
     if (opts.x == 1) { // depth
         diffuse = vec3(linear_depth(gl_FragCoord.z) / far);
     } else { // x == 0 (normal) or anything other
-        diffuse = diffuse_color * texture(diffuse_texture, uv).rgb;
+        diffuse = shade(tint.rgb * diffuse_color * texture(diffuse_texture, uv).rgb);
     }
     color = vec4(diffuse, 1.0);
 }
@@ -1,16 +1,41 @@
 #version 420 core
+#ifdef MULTI_DRAW
+#extension GL_ARB_shader_draw_parameters : require
+#endif
 // shader inputs
 layout (location = 0) in vec3 position;
 layout (location = 1) in vec2 uv_;
 layout (location = 2) in vec3 normal_;
-// mvp matrix
-uniform mat4 mvp;
+// tangent with bitangent sign in w
+layout (location = 3) in vec4 tangent_;
+// per instance
+layout (location = 4) in mat4 model;
+layout (location = 8) in vec4 tint_;
+// scale of the position decode matrix in model, normals are not encoded
+layout (location = 9) in vec4 decode_scale;
+// view * projection matrix
+uniform mat4 vp;
 
 out vec2 uv;
+// world space
 out vec3 normal;
+out vec3 world_position;
+out vec4 tint;
+#ifdef MULTI_DRAW
+// draw of the multi draw call, offset by earlier calls
+uniform int draw_offset;
+flat out int draw_id;
+#endif
 
 void main() {
-	gl_Position = mvp * vec4(position, 1.0f);
+    vec4 world = model * vec4(position, 1.0f);
+	gl_Position = vp * world;
+    world_position = world.xyz;
     uv = uv_;
-    normal = normal_;
+    // inverse transpose of the model matrix without the decode scale
+    normal = transpose(inverse(mat3(model))) * (decode_scale.xyz * normal_);
+    tint = tint_;
+#ifdef MULTI_DRAW
+    draw_id = draw_offset + gl_DrawIDARB;
+#endif
 }
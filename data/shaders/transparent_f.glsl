@@ -1,17 +1,67 @@
 #version 420 core
+#ifdef MULTI_DRAW
+#extension GL_ARB_shader_storage_buffer_object : require
+#endif
 
 in vec2 uv;
 in vec3 normal;
+in vec3 world_position;
+in vec4 tint;
 
 layout (location = 0) out vec4 accum;
 layout (location = 1) out float reveal;
 
+#ifdef MULTI_DRAW
+// same layout as GpuMaterial
+struct Material {
+    vec4 ambient;
+    vec4 diffuse;
+    vec4 specular;
+    vec4 params;
+    ivec4 opts;
+};
+layout (std430, binding = 0) readonly buffer Materials {
+    Material materials[];
+};
+flat in int draw_id;
+#define ambient_color materials[draw_id].ambient.rgb
+#define diffuse_color materials[draw_id].diffuse.rgb
+#define specular_color materials[draw_id].specular.rgb
+#define dissolve materials[draw_id].params.x
+#define shininess materials[draw_id].params.y
+#define opts materials[draw_id].opts.xyz
+#else
 uniform vec3 ambient_color;
 uniform vec3 diffuse_color;
 uniform vec3 specular_color;
-uniform sampler2D diffuse_texture;
 uniform float dissolve;
+uniform float shininess;
 uniform ivec3 opts;
+#endif
+uniform sampler2D diffuse_texture;
+uniform vec3 camera_position;
+uniform vec3 light_position;
+uniform float light_power;
+// ambient, diffuse and specular terms, 0 or 1
+uniform ivec3 light_intensity;
+
+// Blinn-Phong lit color of a surface with diffuse color `base`
+vec3 shade(vec3 base) {
+    vec3 n = normalize(normal);
+    if (!gl_FrontFacing) {
+        n = -n;
+    }
+    vec3 to_light = light_position - world_position;
+    float attenuation = light_power / dot(to_light, to_light);
+    vec3 l = normalize(to_light);
+    vec3 v = normalize(camera_position - world_position);
+    vec3 h = normalize(l + v);
+    float n_dot_l = max(dot(n, l), 0.0);
+    float specular = n_dot_l > 0.0 ? pow(max(dot(n, h), 0.0), shininess) : 0.0;
+    return vec3(light_intensity.x) * 0.1 * ambient_color * base
+        + vec3(light_intensity.y) * base * n_dot_l * attenuation
+        + vec3(light_intensity.z) * specular_color * specular * attenuation;
+}
 // (texture == & 0b10, color == & 0b1)
 // x = ambient
 // y = diffuse
@@ -26,13 +76,14 @@ void main() {
         texture(diffuse_texture, uv).rgb : vec3(1);
     // vec3 diffuse_tx = texture(diffuse_texture, uv).rgb;
     // vec3 diffuse = diffuse_tx;
-    vec3 diffuse = diffuse_color * diffuse_tx;
+    vec3 diffuse = shade(tint.rgb * diffuse_color * diffuse_tx);
+    float alpha = dissolve * tint.a;
     // vec3 diffuse = vec3(dissolve);
     // vec3 diffuse = vec3(1);
 	// weight function
-	float weight = clamp(pow(min(1.0, dissolve * 10.0) + 0.01, 3.0) * 1e8 * pow(1.0 - gl_FragCoord.z * 0.9, 3.0), 1e-2, 3e3);
+	float weight = clamp(pow(min(1.0, alpha * 10.0) + 0.01, 3.0) * 1e8 * pow(1.0 - gl_FragCoord.z * 0.9, 3.0), 1e-2, 3e3);
 	// store pixel color accumulation
-	accum = vec4(diffuse * dissolve, dissolve) * weight;	
+	accum = vec4(diffuse * alpha, alpha) * weight;	
 	// store pixel revealage threshold
-	reveal = dissolve;
+	reveal = alpha;
 }
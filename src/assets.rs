@@ -0,0 +1,907 @@
+use crate::atlas::{self, Atlas};
+use crate::bounds::Bounds;
+use crate::index_buffer::{encode_indices, EncodedIndices, IndexType};
+use crate::model_registry::{ModelId, ModelRegistry};
+use crate::normals::{generate_normals, obj_smoothing_groups, NormalMode};
+use crate::simplify::simplify;
+use crate::tangents::generate_tangents;
+use crate::uvs::{generate_uvs, UvProjection};
+use crate::vertex_cache::{self, STATS_CACHE_SIZE};
+use crate::vertex_layout::{encode_vertices, EncodedVertices, VertexLayout};
+use crate::{memcast, mesh_cache};
+use std::collections::HashMap;
+use std::path::Path;
+use tobj::load_obj;
+
+#[derive(Debug)]
+pub struct Material {
+    pub name: String,
+    pub ambient: [f32; 3],
+    pub diffuse: [f32; 3],
+    pub specular: [f32; 3],
+    pub shininess: f32,
+    pub dissolve: f32,
+    pub optical_density: f32,
+    pub ambient_texture: Option<image::DynamicImage>,
+    pub diffuse_texture: Option<image::DynamicImage>,
+    pub specular_texture: Option<image::DynamicImage>,
+    pub normal_texture: Option<image::DynamicImage>,
+    pub shininess_texture: Option<image::DynamicImage>,
+    pub dissolve_texture: Option<image::DynamicImage>,
+    pub illumination_model: u8,
+    pub is_transparent: bool,
+}
+
+pub fn has_alpha_channel(img: &image::DynamicImage) -> bool {
+    use image::DynamicImage;
+    matches!(
+        img,
+        DynamicImage::ImageLumaA8(_)
+            | DynamicImage::ImageLumaA16(_)
+            | DynamicImage::ImageRgba8(_)
+            | DynamicImage::ImageRgba16(_)
+            | DynamicImage::ImageRgba32F(_)
+    )
+}
+
+pub fn load_texture_data(path: Option<&String>) -> Option<image::DynamicImage> {
+    use std::fs::File;
+    use std::io::BufReader;
+    let file = File::open(path?).ok()?;
+    let reader = BufReader::new(file);
+    image::load(reader, image::ImageFormat::Png).ok()
+}
+
+pub fn prepare_materials(materials: Vec<tobj::Material>) -> Vec<Material> {
+    let mut res = Vec::with_capacity(materials.len());
+    for mat in materials {
+        let ambient_texture = load_texture_data(mat.ambient_texture.as_ref());
+        let diffuse_texture = load_texture_data(mat.diffuse_texture.as_ref());
+        let specular_texture = load_texture_data(mat.specular_texture.as_ref());
+        let normal_texture = load_texture_data(mat.normal_texture.as_ref());
+        let dissolve_texture = load_texture_data(mat.dissolve_texture.as_ref());
+        let shininess_texture = load_texture_data(mat.shininess_texture.as_ref());
+        let ambient = mat.ambient.unwrap_or([1., 1., 1.]);
+        let diffuse = mat.diffuse.unwrap_or([1., 1., 1.]);
+        let specular = mat.specular.unwrap_or([1., 1., 1.]);
+        let shininess = mat.shininess.unwrap_or(200.);
+        let dissolve = mat.dissolve.unwrap_or(1.);
+        let is_transparent = dissolve < 1.
+            || diffuse_texture
+                .as_ref()
+                .map(has_alpha_channel)
+                .unwrap_or(false);
+        let illumination_model = mat.illumination_model.unwrap_or(0);
+        let optical_density = mat.optical_density.unwrap_or(1.);
+        res.push(Material {
+            name: mat.name,
+            ambient,
+            diffuse,
+            specular,
+            shininess,
+            dissolve,
+            is_transparent,
+            ambient_texture,
+            diffuse_texture,
+            specular_texture,
+            dissolve_texture,
+            shininess_texture,
+            normal_texture,
+            illumination_model,
+            optical_density,
+        });
+    }
+    res
+}
+
+#[derive(Debug)]
+pub struct BakedMeshData {
+    pub vertices: Vec<f32>,
+    pub uvs: Vec<f32>,
+    pub normals: Vec<f32>,
+    // xyz and handedness sign
+    pub tangents: Vec<f32>,
+    pub indices: Vec<u32>,
+    pub offsets: Vec<u32>,
+    pub counts: Vec<u32>,
+    // object space bounds of every range of offsets and counts
+    pub bounds: Vec<Bounds>,
+    // material of every model
+    pub material_ids: Vec<Option<usize>>,
+    // extra detail levels of model `i` are ranges
+    // `models + lod_ranges[i]..models + lod_ranges[i + 1]` of offsets and counts
+    pub lod_ranges: Vec<u32>,
+    // object space error of every extra range
+    pub lod_errors: Vec<f32>,
+    // vertices of model `i` are `vertex_offsets[i]..vertex_offsets[i + 1]`
+    pub vertex_offsets: Vec<u32>,
+    pub opaque: Vec<usize>,
+    pub transparent: Vec<usize>,
+}
+
+impl BakedMeshData {
+    pub fn model_count(&self) -> usize {
+        self.lod_ranges.len().saturating_sub(1)
+    }
+
+    /// Number of detail levels of the model, at least 1
+    pub fn lod_count(&self, model: usize) -> usize {
+        (self.lod_ranges[model + 1] - self.lod_ranges[model]) as usize + 1
+    }
+
+    /// Index into offsets and counts of the detail level, clamped to the coarsest
+    pub fn lod_range(&self, model: usize, level: usize) -> usize {
+        let level = level.min(self.lod_count(model) - 1);
+        if level == 0 {
+            model
+        } else {
+            self.model_count() + self.lod_ranges[model] as usize + level - 1
+        }
+    }
+
+    /// Object space bounds of the detail level
+    pub fn bounds(&self, model: usize, level: usize) -> &Bounds {
+        &self.bounds[self.lod_range(model, level)]
+    }
+
+    /// Bounds of the detail level drawn with model matrix `mtx`
+    pub fn world_bounds(&self, model: usize, level: usize, mtx: &glm::Mat4) -> Bounds {
+        self.bounds(model, level).transformed(mtx)
+    }
+
+    /// Coarsest detail level with error under `max_pixels` on screen.
+    /// `pixels_per_unit` is the projected size of a unit at the model's distance
+    pub fn select_lod(&self, model: usize, pixels_per_unit: f32, max_pixels: f32) -> usize {
+        let first = self.lod_ranges[model] as usize;
+        let errors = &self.lod_errors[first..self.lod_ranges[model + 1] as usize];
+        errors
+            .iter()
+            .take_while(|&&error| error * pixels_per_unit <= max_pixels)
+            .count()
+    }
+}
+
+/// Order models so that same-material models are adjacent,
+/// opaque before transparent. Baked data relies on this order
+pub fn sort_models(models: &mut [ModelData], materials: &[Material]) {
+    // multi draw indirect doesn't need the order, but fewer material
+    // changes still help the per-batch fallback
+    models.sort_by_cached_key(|model| {
+        if let Some(mid) = model.material_id {
+            let is_transparent = materials[mid].is_transparent;
+            (true, is_transparent, mid)
+        } else {
+            (false, false, 0)
+        }
+    });
+}
+
+pub fn bake_meshes(
+    models: &[ModelData],
+    materials: &[Material],
+    atlas: &atlas::Atlas,
+    tatlas: &atlas::Atlas,
+    tx_materials: &std::collections::HashMap<usize, usize>,
+    ttx_materials: &std::collections::HashMap<usize, usize>,
+    options: &BakeOptions,
+) -> BakedMeshData {
+    use std::collections::hash_map::Entry;
+    use std::collections::HashMap;
+    // sums of (ACMR, ATVR) weighted by model size, before and after optimization
+    let mut stats = [(0., 0.); 2];
+
+    let mut vertices = Vec::new();
+    let mut uvs = Vec::new();
+    let mut normals = Vec::new();
+    let mut tangents = Vec::new();
+    let mut indices = Vec::new();
+    let mut counts = Vec::new();
+    let mut offsets = Vec::new();
+    let mut material_ids = Vec::new();
+    // extra ranges of detail levels, appended to offsets and counts at the end
+    let mut lod_ranges = vec![0];
+    let mut vertex_offsets = vec![0];
+    let mut lod_offsets = Vec::new();
+    let mut lod_counts = Vec::new();
+    let mut lod_errors = Vec::new();
+    let mut opaque = Vec::new();
+    let mut transparent = Vec::new();
+    let mut idx = 0;
+    let mut offset = 0;
+
+    let mut cache = HashMap::new();
+    for (model_index, model) in models.iter().enumerate() {
+        cache.clear();
+        {
+            let is_transparent =
+                model.material_id.is_some() && materials[model.material_id.unwrap()].is_transparent;
+            if is_transparent {
+                transparent.push(model_index);
+            } else {
+                opaque.push(model_index);
+            }
+        }
+        material_ids.push(model.material_id);
+
+        let m = &model.mesh;
+
+        let model_uvs0 = model
+            .material_id
+            .map(|mid| {
+                if materials[mid].is_transparent {
+                    (mid, &ttx_materials, &tatlas)
+                } else {
+                    (mid, &tx_materials, &atlas)
+                }
+            })
+            .and_then(|(mid, &a, &b)| {
+                a.get(&mid)
+                    .map(|&midx| atlas::adjust_uvs(&m.uvs, b.map[midx]))
+            });
+        let model_uvs = model_uvs0.as_ref().unwrap_or(&m.uvs);
+        // meshes are validated when loaded, so lengths always match
+        let vs = memcast::as_arrays::<f32, 3>(&m.vertices).unwrap();
+        let us = memcast::as_arrays::<f32, 2>(model_uvs).unwrap();
+        let ns = memcast::as_arrays::<f32, 3>(&m.normals).unwrap();
+        // per index, so vertices on uv seams and mirrored uvs are split
+        let ts = generate_tangents(&m.vertices, &m.normals, model_uvs, &m.indices);
+
+        for (corner, i) in m.indices.iter().enumerate() {
+            let i = *i as usize;
+            let t = &ts[4 * corner..4 * corner + 4];
+            // f32 can't stand as hash key, so bits are used
+            let bits = |x: [f32; 3]| x.map(f32::to_bits);
+            let t_bits: [u32; 4] = std::array::from_fn(|k| t[k].to_bits());
+            let key = (bits(vs[i]), us[i].map(f32::to_bits), bits(ns[i]), t_bits);
+            let entry = cache.entry(key);
+            // find vertex index in cache or else add new and update buffers
+            match entry {
+                Entry::Occupied(e) => {
+                    indices.push(*e.get());
+                },
+                Entry::Vacant(e) => {
+                    let (v, u, n) = (vs[i], us[i], ns[i]);
+                    e.insert(idx);
+                    indices.push(idx);
+                    vertices.extend(v);
+                    uvs.extend(u);
+                    normals.extend(n);
+                    tangents.extend(t);
+                    idx += 1;
+                },
+            }
+        }
+        let model_length = m.indices.len() as u32;
+        if options.optimize {
+            let base = (vertices.len() / 3 - cache.len()) as u32;
+            let model_indices = &mut indices[offset as usize..];
+            let count = cache.len();
+            for i in model_indices.iter_mut() {
+                *i -= base;
+            }
+            let mut add_stats = |k: usize, model_indices: &[u32]| {
+                let (acmr, atvr) =
+                    vertex_cache::cache_stats(model_indices, count, STATS_CACHE_SIZE);
+                stats[k].0 += acmr * (model_length / 3) as f32;
+                stats[k].1 += atvr * count as f32;
+            };
+            add_stats(0, model_indices);
+            vertex_cache::optimize_vertex_cache(model_indices, count);
+            let order = vertex_cache::optimize_vertex_fetch(model_indices, count);
+            add_stats(1, model_indices);
+            let base = base as usize;
+            vertex_cache::remap_attribute(&mut vertices[3 * base..], &order, 3);
+            vertex_cache::remap_attribute(&mut uvs[2 * base..], &order, 2);
+            vertex_cache::remap_attribute(&mut normals[3 * base..], &order, 3);
+            vertex_cache::remap_attribute(&mut tangents[4 * base..], &order, 4);
+            for i in model_indices.iter_mut() {
+                *i += base as u32;
+            }
+        }
+        counts.push(model_length);
+        offsets.push(offset);
+        offset += model_length;
+
+        let base = vertices.len() / 3 - cache.len();
+        let model_indices = indices[indices.len() - model_length as usize..]
+            .iter()
+            .map(|i| i - base as u32)
+            .collect::<Vec<_>>();
+        for (mut lod, error) in generate_lods(&vertices[3 * base..], &model_indices, options.lods) {
+            if options.optimize {
+                vertex_cache::optimize_vertex_cache(&mut lod, cache.len());
+            }
+            lod_offsets.push(offset);
+            lod_counts.push(lod.len() as u32);
+            lod_errors.push(error);
+            offset += lod.len() as u32;
+            indices.extend(lod.iter().map(|i| i + base as u32));
+        }
+        lod_ranges.push(lod_offsets.len() as u32);
+        vertex_offsets.push((vertices.len() / 3) as u32);
+    }
+    offsets.append(&mut lod_offsets);
+    counts.append(&mut lod_counts);
+    let bounds = offsets
+        .iter()
+        .zip(&counts)
+        .map(|(&offset, &count)| {
+            let range = offset as usize..(offset + count) as usize;
+            Bounds::from_indices(&vertices, &indices[range])
+        })
+        .collect::<Vec<_>>();
+
+    if options.optimize {
+        let tris = (counts[..models.len()].iter().sum::<u32>() / 3).max(1) as f32;
+        let vertex_count = (vertices.len() / 3).max(1) as f32;
+        println!(
+            "Vertex cache: ACMR {:.3} -> {:.3}, ATVR {:.3} -> {:.3}",
+            stats[0].0 / tris,
+            stats[1].0 / tris,
+            stats[0].1 / vertex_count,
+            stats[1].1 / vertex_count
+        );
+    }
+
+    vertices.shrink_to_fit();
+    uvs.shrink_to_fit();
+    normals.shrink_to_fit();
+    tangents.shrink_to_fit();
+    indices.shrink_to_fit();
+    lod_ranges.shrink_to_fit();
+    vertex_offsets.shrink_to_fit();
+    lod_errors.shrink_to_fit();
+    counts.shrink_to_fit();
+    offsets.shrink_to_fit();
+    material_ids.shrink_to_fit();
+    opaque.shrink_to_fit();
+    transparent.shrink_to_fit();
+
+    BakedMeshData {
+        vertices,
+        uvs,
+        normals,
+        tangents,
+        indices,
+        offsets,
+        counts,
+        bounds,
+        material_ids,
+        lod_ranges,
+        lod_errors,
+        vertex_offsets,
+        opaque,
+        transparent,
+    }
+}
+
+/// Detail levels of a model as (indices, object space error),
+/// every level has about half the triangles of the previous one
+fn generate_lods(vertices: &[f32], indices: &[u32], max_lods: usize) -> Vec<(Vec<u32>, f32)> {
+    // not worth it for small meshes
+    const MIN_TRIANGLES: usize = 64;
+    let mut res: Vec<(Vec<u32>, f32)> = Vec::new();
+    let (min, max) =
+        vertices
+            .chunks_exact(3)
+            .fold(([f32::MAX; 3], [f32::MIN; 3]), |(min, max), v| {
+                (
+                    [0, 1, 2].map(|k| min[k].min(v[k])),
+                    [0, 1, 2].map(|k| max[k].max(v[k])),
+                )
+            });
+    let extent = (0..3).map(|k| max[k] - min[k]).fold(0., f32::max);
+    let mut prev = indices;
+    for _ in 0..max_lods {
+        if prev.len() / 3 < MIN_TRIANGLES {
+            break;
+        }
+        let target = prev.len() / 6 * 3;
+        let (lod, error) = simplify(vertices, prev, target, 0.1 * extent);
+        // stop when simplification gets stuck on locked vertices
+        if lod.len() * 10 > prev.len() * 9 {
+            break;
+        }
+        let error = res.last().map(|x| x.1).unwrap_or(0.).max(error);
+        res.push((lod, error));
+        prev = &res.last().unwrap().0;
+    }
+    res
+}
+
+#[derive(Debug)]
+pub struct MeshData {
+    pub vertices: Vec<f32>,
+    pub normals: Vec<f32>,
+    pub uvs: Vec<f32>,
+    pub indices: Vec<u32>,
+}
+
+impl MeshData {
+    /// Check that attribute counts match and indices are in range.
+    /// Normals and uvs may be empty
+    pub fn validate(&self) -> Result<(), String> {
+        if !self.vertices.len().is_multiple_of(3) {
+            return Err(format!(
+                "{} position components are not a multiple of 3",
+                self.vertices.len()
+            ));
+        }
+        let count = self.vertices.len() / 3;
+        if !self.normals.is_empty() && self.normals.len() != 3 * count {
+            return Err(format!(
+                "{} normal components for {} vertices",
+                self.normals.len(),
+                count
+            ));
+        }
+        if !self.uvs.is_empty() && self.uvs.len() != 2 * count {
+            return Err(format!(
+                "{} uv components for {} vertices",
+                self.uvs.len(),
+                count
+            ));
+        }
+        if !self.indices.len().is_multiple_of(3) {
+            return Err(format!(
+                "{} indices do not form triangles",
+                self.indices.len()
+            ));
+        }
+        if let Some(i) = self.indices.iter().find(|&&i| i as usize >= count) {
+            return Err(format!("index {} out of range for {} vertices", i, count));
+        }
+        Ok(())
+    }
+
+    /// Give every index its own vertex, indices become 0..n
+    pub fn unindex(&mut self) {
+        let unindex = |data: &[f32], n: usize| {
+            if data.is_empty() {
+                return Vec::new();
+            }
+            self.indices
+                .iter()
+                .flat_map(|&i| &data[n * i as usize..n * (i as usize + 1)])
+                .copied()
+                .collect::<Vec<_>>()
+        };
+        let vertices = unindex(&self.vertices, 3);
+        let normals = unindex(&self.normals, 3);
+        let uvs = unindex(&self.uvs, 2);
+        self.vertices = vertices;
+        self.normals = normals;
+        self.uvs = uvs;
+        self.indices = (0..self.indices.len() as u32).collect();
+    }
+
+    /// Validate and generate missing normals and uvs
+    pub fn complete(
+        &mut self,
+        normals: NormalMode,
+        uvs: UvProjection,
+        smoothing_groups: Option<&[u32]>,
+    ) -> Result<(), String> {
+        self.validate()?;
+        if self.normals.is_empty() {
+            generate_normals(self, normals, smoothing_groups);
+        }
+        if self.uvs.is_empty() {
+            generate_uvs(self, uvs);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct ModelData {
+    pub mesh: MeshData,
+    pub material_id: Option<usize>,
+    pub name: String,
+    pub id: ModelId,
+    // index of the asset path the model was loaded from
+    pub source: usize,
+    // world transforms of scene nodes using this model
+    pub instances: Vec<glm::Mat4>,
+}
+
+#[derive(Debug)]
+pub struct LoadedModels {
+    pub models: Vec<ModelData>,
+    pub materials: Vec<tobj::Material>,
+}
+
+/// Smoothing group of every triangle of consecutive models,
+/// `None` if triangles do not match faces of the file
+fn split_smoothing_groups(faces: &[(u32, usize)], models: &[tobj::Model]) -> Option<Vec<Vec<u32>>> {
+    let mut faces = faces.iter();
+    let mut res = Vec::with_capacity(models.len());
+    for model in models {
+        let tris = model.mesh.indices.len() / 3;
+        let mut groups = Vec::with_capacity(tris);
+        while groups.len() < tris {
+            let &(group, count) = faces.next()?;
+            groups.extend(std::iter::repeat_n(group, count));
+        }
+        if groups.len() != tris {
+            return None;
+        }
+        res.push(groups);
+    }
+    Some(res)
+}
+
+pub fn prepare_objs(
+    paths: &[&std::path::Path],
+    options: &BakeOptions,
+) -> Result<LoadedModels, String> {
+    let load_opts = tobj::LoadOptions {
+        single_index: true,
+        triangulate: true,
+        ..Default::default()
+    };
+    let mut loaded_models = Vec::with_capacity(paths.len());
+    let mut loaded_materials = Vec::with_capacity(paths.len());
+    for (source, path) in paths.iter().enumerate() {
+        let (models, materials) = load_obj(path, &load_opts).map_err(|e| e.to_string())?;
+        let mut materials = materials.map_err(|e| e.to_string())?;
+        let len = loaded_materials.len();
+        let missing_normals = models.iter().any(|x| x.mesh.normals.is_empty());
+        let groups = if missing_normals {
+            let faces = obj_smoothing_groups(path)?;
+            let groups = split_smoothing_groups(&faces, &models);
+            if groups.is_none() {
+                eprintln!(
+                    "{}: faces do not match loaded models, smoothing groups ignored",
+                    path.display()
+                );
+            }
+            groups
+        } else {
+            None
+        };
+        for (i, model) in models.into_iter().enumerate() {
+            let mut mesh = MeshData {
+                vertices: model.mesh.positions,
+                normals: model.mesh.normals,
+                uvs: model.mesh.texcoords,
+                indices: model.mesh.indices,
+            };
+            let groups = groups.as_ref().map(|x| x[i].as_slice());
+            mesh.complete(options.normals, options.uvs, groups)
+                .map_err(|e| format!("{}: {}: {}", path.display(), model.name, e))?;
+            let mid = model.mesh.material_id;
+            let res_model = ModelData {
+                mesh,
+                material_id: mid.map(|i| i + len),
+                name: model.name,
+                id: ModelId(loaded_models.len() as u32),
+                source,
+                instances: Vec::new(),
+            };
+            loaded_models.push(res_model);
+        }
+        loaded_materials.append(&mut materials);
+    }
+    Ok(LoadedModels {
+        models: loaded_models,
+        materials: loaded_materials,
+    })
+}
+
+pub struct PreparedModels {
+    pub models: Vec<ModelData>,
+    pub materials: Vec<Material>,
+}
+
+/// Load models choosing importer by file extension
+pub fn prepare_models(
+    paths: &[&std::path::Path],
+    options: &BakeOptions,
+) -> Result<PreparedModels, String> {
+    let mut models = Vec::new();
+    let mut materials = Vec::new();
+    for (source, path) in paths.iter().enumerate() {
+        let first = models.len();
+        let ext = path
+            .extension()
+            .and_then(|x| x.to_str())
+            .map(|x| x.to_ascii_lowercase());
+        match ext.as_deref() {
+            Some("obj") => {
+                let LoadedModels {
+                    models: mut m,
+                    materials: mats,
+                } = prepare_objs(&[path], options)?;
+                for model in &mut m {
+                    model.material_id = model.material_id.map(|i| i + materials.len());
+                }
+                models.append(&mut m);
+                materials.append(&mut prepare_materials(mats));
+            },
+            Some("gltf" | "glb") => {
+                let (mut m, mut mats) =
+                    crate::gltf_import::load_gltf(path, materials.len(), options.uvs)?;
+                models.append(&mut m);
+                materials.append(&mut mats);
+            },
+            _ => return Err(format!("{}: unsupported model format", path.display())),
+        }
+        for (i, model) in models.iter_mut().enumerate().skip(first) {
+            model.id = ModelId(i as u32);
+            model.source = source;
+        }
+    }
+    Ok(PreparedModels { models, materials })
+}
+
+/// Atlas of diffuse textures of either opaque or transparent materials
+pub struct MaterialAtlas {
+    pub atlas: Atlas,
+    // material index -> texture index in atlas
+    pub materials: HashMap<usize, usize>,
+    // fraction of atlas area covered by placed textures
+    pub occupancy: f32,
+    // names of materials that did not fit
+    pub skipped: Vec<String>,
+}
+
+pub fn prepare_atlas(
+    materials: &[Material],
+    transparent: bool,
+    max_size: usize,
+) -> Result<MaterialAtlas, String> {
+    let tx_mats = materials
+        .iter()
+        .enumerate()
+        .filter(|x| x.1.is_transparent == transparent && x.1.diffuse_texture.is_some())
+        .collect::<Vec<_>>();
+    let textures = tx_mats
+        .iter()
+        .map(|x| x.1.diffuse_texture.clone().unwrap())
+        .collect::<Vec<_>>();
+    let size = atlas::atlas_size_for(&textures, 128, max_size).map_err(|e| {
+        let kind = if transparent { "transparent" } else { "opaque" };
+        format!("{} atlas: {}", kind, e)
+    })?;
+    let levels = atlas::atlas_levels(&textures, size);
+    let (atlas, skipped) = atlas::textures_to_atlas(&textures, size, transparent, levels);
+    let skipped = skipped.unwrap_or_default();
+    let placed_area = textures
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| !skipped.contains(i))
+        .map(|(_, tx)| tx.width() as usize * tx.height() as usize)
+        .sum::<usize>();
+    Ok(MaterialAtlas {
+        occupancy: placed_area as f32 / (atlas.size * atlas.size) as f32,
+        skipped: skipped.iter().map(|&i| tx_mats[i].1.name.clone()).collect(),
+        materials: tx_mats
+            .iter()
+            .map(|x| x.0)
+            .enumerate()
+            .map(|(a, b)| (b, a))
+            .collect(),
+        atlas,
+    })
+}
+
+impl MaterialAtlas {
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        let size = self.atlas.size as u32;
+        let texture = self.atlas.texture.clone();
+        let res = if self.atlas.texture.len() == 4 * self.atlas.size * self.atlas.size {
+            image::RgbaImage::from_raw(size, size, texture).map(|x| x.save(path))
+        } else {
+            image::RgbImage::from_raw(size, size, texture).map(|x| x.save(path))
+        };
+        match res {
+            Some(Ok(())) => Ok(()),
+            Some(Err(e)) => Err(format!("{}: {}", path.display(), e)),
+            None => Err(format!("{}: invalid atlas size", path.display())),
+        }
+    }
+}
+
+/// Parameters that change baked data
+#[derive(Clone, Debug)]
+pub struct BakeOptions {
+    pub normals: NormalMode,
+    pub uvs: UvProjection,
+    // reorder triangles and vertices for the vertex cache
+    pub optimize: bool,
+    // simplified detail levels per model
+    pub lods: usize,
+    // vertex buffer layout, cached buffers are encoded with it.
+    // F16 uvs become Unorm16 for large atlases, see `VertexLayout::for_atlas`
+    pub layout: VertexLayout,
+    pub max_texture_size: usize,
+}
+
+impl Default for BakeOptions {
+    fn default() -> Self {
+        Self {
+            normals: NormalMode::default(),
+            uvs: UvProjection::default(),
+            optimize: true,
+            lods: 3,
+            layout: VertexLayout::default(),
+            max_texture_size: 16384,
+        }
+    }
+}
+
+/// Everything needed to render loaded models
+pub struct BakedAssets {
+    // in baked order
+    pub models: Vec<ModelData>,
+    pub registry: ModelRegistry,
+    pub materials: Vec<Material>,
+    pub atlas: MaterialAtlas,
+    pub transparent_atlas: MaterialAtlas,
+    pub baked: BakedMeshData,
+    pub vertices: EncodedVertices,
+    pub indices: EncodedIndices,
+    pub from_cache: bool,
+}
+
+/// Load models, pack atlases and bake meshes, writing atlases
+/// and baked meshes to `out_dir`. With `use_cache`, valid baked meshes
+/// already in `out_dir` are loaded instead of baking
+pub fn bake_assets(
+    paths: &[&Path],
+    options: &BakeOptions,
+    out_dir: &Path,
+    use_cache: bool,
+) -> Result<BakedAssets, String> {
+    let PreparedModels {
+        mut models,
+        materials,
+    } = prepare_models(paths, options)?;
+    let atlas = prepare_atlas(&materials, false, options.max_texture_size)?;
+    let transparent_atlas = prepare_atlas(&materials, true, options.max_texture_size)?;
+    std::fs::create_dir_all(out_dir).map_err(|e| format!("{}: {}", out_dir.display(), e))?;
+    atlas.save(&out_dir.join("atlas0.png"))?;
+    transparent_atlas.save(&out_dir.join("atlas1.png"))?;
+
+    sort_models(&mut models, &materials);
+    let layout = options
+        .layout
+        .for_atlas(atlas.atlas.size.max(transparent_atlas.atlas.size));
+    let atlas_key = |a: &Atlas| {
+        std::iter::once(a.size)
+            .chain(a.map.iter().copied())
+            .flat_map(|x| (x as u64).to_le_bytes())
+            .collect::<Vec<_>>()
+    };
+    // baked data is in model order, so any change to the asset list
+    // or to the models loaded from it invalidates the cache
+    let mut models_key = Vec::new();
+    let push_str = |key: &mut Vec<u8>, x: &str| {
+        key.extend((x.len() as u64).to_le_bytes());
+        key.extend(x.bytes());
+    };
+    for path in paths {
+        push_str(&mut models_key, &path.to_string_lossy());
+    }
+    for model in &models {
+        let mid = model.material_id.map_or(u64::MAX, |x| x as u64);
+        models_key.extend((model.source as u64).to_le_bytes());
+        models_key.extend(mid.to_le_bytes());
+        push_str(&mut models_key, &model.name);
+    }
+    let cache_key = mesh_cache::cache_key(&[
+        &models_key,
+        &atlas_key(&atlas.atlas),
+        &atlas_key(&transparent_atlas.atlas),
+        &options.normals.key(),
+        &options.uvs.key(),
+        &[options.optimize as u8],
+        &(options.lods as u64).to_le_bytes(),
+        &layout.key(),
+    ]);
+    let cache_path = out_dir.join("meshes.bin");
+    let cached = if use_cache {
+        mesh_cache::MappedMeshCache::open(&cache_path, cache_key)
+            .unwrap_or_else(|e| {
+                eprintln!("{}", e);
+                None
+            })
+            .map(|x| (x.to_baked(), x.encoded(&layout)))
+            .filter(|(x, _)| {
+                x.model_count() == models.len() && x.material_ids.len() == models.len()
+            })
+    } else {
+        None
+    };
+    let from_cache = cached.is_some();
+    let (baked, encoded) = match cached {
+        Some(cached) => cached,
+        None => {
+            let baked = bake_meshes(
+                &models,
+                &materials,
+                &atlas.atlas,
+                &transparent_atlas.atlas,
+                &atlas.materials,
+                &transparent_atlas.materials,
+                options,
+            );
+            let vertices = encode_vertices(&baked, &layout);
+            let indices = encode_indices(&baked);
+            let sources = mesh_cache::model_sources(paths);
+            mesh_cache::write(
+                &cache_path,
+                &baked,
+                &vertices,
+                &indices,
+                &sources,
+                cache_key,
+            )?;
+            (baked, Some((vertices, indices)))
+        },
+    };
+    let (vertices, indices) =
+        encoded.unwrap_or_else(|| (encode_vertices(&baked, &layout), encode_indices(&baked)));
+    let registry = ModelRegistry::new(&models, paths);
+    Ok(BakedAssets {
+        models,
+        registry,
+        materials,
+        atlas,
+        transparent_atlas,
+        baked,
+        vertices,
+        indices,
+        from_cache,
+    })
+}
+
+impl BakedAssets {
+    pub fn print_stats(&self) {
+        let indices = self.baked.indices.len();
+        let vertices = self.baked.vertices.len() / 3;
+        println!(
+            "Models: {} ({} opaque, {} transparent), materials: {}",
+            self.models.len(),
+            self.baked.opaque.len(),
+            self.baked.transparent.len(),
+            self.materials.len()
+        );
+        println!(
+            "Vertices: {} unique for {} indices, reuse ratio {:.2}",
+            vertices,
+            indices,
+            indices as f32 / vertices.max(1) as f32
+        );
+        println!(
+            "Vertex data: {} bytes per vertex in {} buffers, {} bytes total",
+            self.vertices.layout.vertex_size(),
+            self.vertices.streams.len(),
+            self.vertices.streams.iter().map(|x| x.len()).sum::<usize>()
+        );
+        println!(
+            "Index data: {} bytes in {} 16 bit and {} 32 bit ranges, {} bytes as 32 bit",
+            self.indices.data.len(),
+            self.indices.count(IndexType::U16),
+            self.indices.count(IndexType::U32),
+            self.baked.indices.len() * 4
+        );
+        for (name, a) in [
+            ("Opaque", &self.atlas),
+            ("Transparent", &self.transparent_atlas),
+        ] {
+            println!(
+                "{} atlas: {}x{}, {} textures, {:.1}% occupied",
+                name,
+                a.atlas.size,
+                a.atlas.size,
+                a.materials.len() - a.skipped.len(),
+                a.occupancy * 100.
+            );
+            for skipped in &a.skipped {
+                println!("  skipped texture of material {}", skipped);
+            }
+        }
+    }
+}
@@ -187,22 +187,71 @@ fn place_texture(
     atlas: &mut [u8],
     atlas_size: usize,
     texture: &[u8],
-    texture_size: usize,
+    texture_size: (usize, usize),
     texture_position: (usize, usize),
     channels: usize,
 ) {
     let (x, y) = texture_position;
-    let (nx, ny) = (x, atlas_size - y - texture_size);
-    for (i, row) in (ny..ny + texture_size).enumerate() {
-        for (j, col) in (nx..nx + texture_size).enumerate() {
+    let (w, h) = texture_size;
+    let (nx, ny) = (x, atlas_size - y - h);
+    for (i, row) in (ny..ny + h).enumerate() {
+        for (j, col) in (nx..nx + w).enumerate() {
             for k in 0..channels {
                 atlas[channels * row * atlas_size + channels * col + k] =
-                    texture[channels * i * texture_size + channels * j + k];
+                    texture[channels * i * w + channels * j + k];
             }
         }
     }
 }
 
+// side of the square block a texture takes in the atlas
+fn block_size(tx: &image::DynamicImage) -> usize {
+    (tx.width().max(tx.height()) as usize).next_power_of_two()
+}
+
+/// Smallest power of two atlas size of at least `min_size` that can fit all textures.
+/// Fails when that exceeds `max_size`, e.g. the max texture size of the driver
+pub fn atlas_size_for(
+    textures: &[image::DynamicImage],
+    min_size: usize,
+    max_size: usize,
+) -> Result<usize, String> {
+    let area = textures
+        .iter()
+        .map(|tx| tx.width() as usize * tx.height() as usize)
+        .sum::<usize>();
+    let biggest = textures
+        .iter()
+        .map(|tx| tx.width().max(tx.height()) as usize)
+        .max()
+        .unwrap_or(0);
+    let mut size = min_size.max(biggest).next_power_of_two();
+    while size * size < area {
+        size *= 2;
+    }
+    if size > max_size {
+        return Err(format!(
+            "{} textures need a {}x{} atlas, max texture size is {}",
+            textures.len(),
+            size,
+            size,
+            max_size
+        ));
+    }
+    Ok(size)
+}
+
+/// Deepest level of an `atlas_size` atlas that any of `textures` is placed at
+pub fn atlas_levels(textures: &[image::DynamicImage], atlas_size: usize) -> u32 {
+    textures
+        .iter()
+        .map(block_size)
+        .filter(|&size| size <= atlas_size)
+        .map(|size| texture_level_unchecked(size, atlas_size))
+        .max()
+        .unwrap_or(0)
+}
+
 pub fn textures_to_atlas(
     textures: &[image::DynamicImage],
     atlas_size: usize,
@@ -216,8 +265,8 @@ pub fn textures_to_atlas(
     let mut skipped = Vec::new();
     let mut occupied = vec![false; full_level_space(max_level)];
     for (i, tx) in textures.iter().enumerate() {
-        // assume all textures are squares and are size of power of two
-        let size = tx.width() as usize;
+        // every texture takes a power of two square block
+        let size = block_size(tx);
         if size > atlas_size {
             skipped.push(i);
             continue;
@@ -234,7 +283,10 @@ pub fn textures_to_atlas(
             } else {
                 tx.clone().into_rgb8().into_flat_samples()
             };
-            place_texture(&mut atlas, atlas_size, &s.samples, size, tx_pos, channels);
+            let tx_size = (tx.width() as usize, tx.height() as usize);
+            place_texture(
+                &mut atlas, atlas_size, &s.samples, tx_size, tx_pos, channels,
+            );
         } else {
             skipped.push(i);
         }
@@ -254,17 +306,13 @@ pub fn textures_to_atlas(
 }
 
 pub fn adjust_uvs(uvs: &[f32], position: usize) -> Vec<f32> {
-    use crate::memcast;
     let block = position_to_block(position);
     let level = position_to_level(position);
     let scale = 1.0 / level_scale(level) as f32;
     let fblock = (block.0 as f32 * scale, block.1 as f32 * scale);
-    let uvs1 = memcast::slice_cast::<f32, [f32; 2]>(uvs, uvs.len() / 2);
-    let a = uvs1
-        .iter()
-        .flat_map(|[x, y]|
+    uvs.chunks_exact(2)
+        .flat_map(|x|
             // u, 1 - v
-            [x * scale + fblock.0, 1. - (y * scale + fblock.1)])
-        .collect::<_>();
-    a
+            [x[0] * scale + fblock.0, 1. - (x[1] * scale + fblock.1)])
+        .collect::<_>()
 }
@@ -0,0 +1,136 @@
+//! Offline asset baking: packs atlases and bakes meshes into the output
+//! directory, the same files the game loads from `./cache`
+use game::assets::{bake_assets, BakeOptions};
+use game::normals::NormalMode;
+use game::uvs::UvProjection;
+use game::vertex_layout::VertexLayout;
+use std::path::{Path, PathBuf};
+
+const USAGE: &str =
+    "Usage: bake [--verbose] [--out DIR] [--max-texture-size N] [--normals MODE] [--uvs MODE] [--no-optimize] [--lods N] [--layout MODE] [--list FILE] [ASSET...]
+
+  --verbose             print vertex reuse, atlas occupancy and skipped textures
+  --out DIR             output directory, ./cache by default
+  --max-texture-size N  atlas size limit, 16384 by default
+  --normals MODE        normals generated for meshes without them:
+                        flat, smooth (default) or crease angle in degrees
+  --uvs MODE            uvs generated for meshes without them: box (default) or planar
+  --no-optimize         keep source triangle and vertex order
+  --lods N              simplified detail levels per model, 3 by default
+  --layout MODE         vertex layout for stats: compact (default) or full
+  --list FILE           read asset paths from FILE, one per line";
+
+struct Args {
+    verbose: bool,
+    out: PathBuf,
+    options: BakeOptions,
+    assets: Vec<PathBuf>,
+}
+
+fn parse_args() -> Result<Args, String> {
+    let mut res = Args {
+        verbose: false,
+        out: PathBuf::from("./cache"),
+        options: BakeOptions::default(),
+        assets: Vec::new(),
+    };
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("missing value for {}", arg));
+        match arg.as_str() {
+            "--verbose" | "-v" => res.verbose = true,
+            "--out" | "-o" => res.out = PathBuf::from(value()?),
+            "--max-texture-size" => {
+                let v = value()?;
+                res.options.max_texture_size = v
+                    .parse()
+                    .map_err(|_| format!("invalid texture size: {}", v))?;
+            },
+            "--normals" => {
+                res.options.normals = match value()?.as_str() {
+                    "flat" => NormalMode::Flat,
+                    "smooth" => NormalMode::Smooth { crease_angle: None },
+                    v => NormalMode::Smooth {
+                        crease_angle: Some(
+                            v.parse()
+                                .map_err(|_| format!("invalid normals mode: {}", v))?,
+                        ),
+                    },
+                };
+            },
+            "--no-optimize" => res.options.optimize = false,
+            "--lods" => {
+                let v = value()?;
+                res.options.lods = v.parse().map_err(|_| format!("invalid lods: {}", v))?;
+            },
+            "--uvs" => {
+                res.options.uvs = match value()?.as_str() {
+                    "box" => UvProjection::Box,
+                    "planar" => UvProjection::Planar,
+                    v => return Err(format!("invalid uvs mode: {}", v)),
+                };
+            },
+            "--layout" => {
+                res.options.layout = match value()?.as_str() {
+                    "compact" => VertexLayout::compact(),
+                    "full" => VertexLayout::full(),
+                    v => return Err(format!("invalid layout: {}", v)),
+                };
+            },
+            "--list" => {
+                let path = value()?;
+                let list =
+                    std::fs::read_to_string(&path).map_err(|e| format!("{}: {}", path, e))?;
+                res.assets.extend(
+                    list.lines()
+                        .map(|x| x.trim())
+                        .filter(|x| !x.is_empty() && !x.starts_with('#'))
+                        .map(PathBuf::from),
+                );
+            },
+            "--help" | "-h" => {
+                println!("{}", USAGE);
+                std::process::exit(0);
+            },
+            _ if arg.starts_with('-') => return Err(format!("unknown option: {}", arg)),
+            _ => res.assets.push(PathBuf::from(arg)),
+        }
+    }
+    if res.assets.is_empty() {
+        return Err("no assets given".to_string());
+    }
+    Ok(res)
+}
+
+fn main() {
+    let args = match parse_args() {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            std::process::exit(2);
+        },
+    };
+    let paths = args
+        .assets
+        .iter()
+        .map(|x| x.as_path())
+        .collect::<Vec<&Path>>();
+    let start = std::time::Instant::now();
+    match bake_assets(&paths, &args.options, &args.out, false) {
+        Ok(assets) => {
+            println!(
+                "Baked {} assets into {} in {:.2?}",
+                paths.len(),
+                args.out.display(),
+                start.elapsed()
+            );
+            if args.verbose {
+                assets.print_stats();
+            }
+        },
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        },
+    }
+}
@@ -0,0 +1,116 @@
+use crate::glmc::{model_mat_from, Transform};
+
+/// Axis aligned box and bounding sphere of a mesh range
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Bounds {
+    pub min: glm::Vec3,
+    pub max: glm::Vec3,
+    pub center: glm::Vec3,
+    pub radius: f32,
+}
+
+/// Floats per `Bounds` in `to_floats`
+pub const BOUNDS_FLOATS: usize = 10;
+
+impl Bounds {
+    /// Bounds of vertices (xyz) used by `indices`, empty ranges give a point at the origin
+    pub fn from_indices(vertices: &[f32], indices: &[u32]) -> Self {
+        let at = |i: u32| {
+            let i = 3 * i as usize;
+            glm::vec3(vertices[i], vertices[i + 1], vertices[i + 2])
+        };
+        if indices.is_empty() {
+            let zero = glm::vec3(0., 0., 0.);
+            return Self::from_box(zero, zero);
+        }
+        let (min, max) = indices.iter().map(|&i| at(i)).fold(
+            (
+                glm::vec3(f32::MAX, f32::MAX, f32::MAX),
+                glm::vec3(f32::MIN, f32::MIN, f32::MIN),
+            ),
+            |(min, max), p| (glm::min(min, p), glm::max(max, p)),
+        );
+        // sphere around the box center, usually tighter than the box corners
+        let center = (min + max) * 0.5;
+        let radius = indices
+            .iter()
+            .map(|&i| glm::length(at(i) - center))
+            .fold(0., f32::max);
+        Self {
+            min,
+            max,
+            center,
+            radius,
+        }
+    }
+
+    pub fn from_box(min: glm::Vec3, max: glm::Vec3) -> Self {
+        Self {
+            min,
+            max,
+            center: (min + max) * 0.5,
+            radius: glm::length(max - min) * 0.5,
+        }
+    }
+
+    pub fn size(&self) -> glm::Vec3 {
+        self.max - self.min
+    }
+
+    /// Box around the transformed box, sphere scaled by the largest axis scale
+    pub fn transformed(&self, mtx: &glm::Mat4) -> Self {
+        let translation = glm::vec3(mtx[3][0], mtx[3][1], mtx[3][2]);
+        let mut min = translation;
+        let mut max = translation;
+        // Arvo: every matrix element scales one box extent into one axis
+        for c in 0..3 {
+            for r in 0..3 {
+                let a = mtx[c][r] * self.min[c];
+                let b = mtx[c][r] * self.max[c];
+                min[r] += a.min(b);
+                max[r] += a.max(b);
+            }
+        }
+        let scale = (0..3)
+            .map(|c| glm::length(glm::vec3(mtx[c][0], mtx[c][1], mtx[c][2])))
+            .fold(0., f32::max);
+        let center = *mtx * glm::vec4(self.center.x, self.center.y, self.center.z, 1.);
+        Self {
+            min,
+            max,
+            center: glm::vec3(center.x, center.y, center.z),
+            radius: self.radius * scale,
+        }
+    }
+
+    pub fn transformed_by(&self, transform: Transform) -> Self {
+        self.transformed(&model_mat_from(transform))
+    }
+
+    /// Smallest box and sphere around the box containing both
+    pub fn union(&self, other: &Self) -> Self {
+        let min = glm::min(self.min, other.min);
+        let max = glm::max(self.max, other.max);
+        let mut res = Self::from_box(min, max);
+        res.radius = [self, other]
+            .iter()
+            .map(|x| glm::length(x.center - res.center) + x.radius)
+            .fold(0., f32::max)
+            .min(res.radius);
+        res
+    }
+
+    pub fn to_floats(&self) -> [f32; BOUNDS_FLOATS] {
+        let (a, b, c) = (self.min, self.max, self.center);
+        [a.x, a.y, a.z, b.x, b.y, b.z, c.x, c.y, c.z, self.radius]
+    }
+
+    pub fn from_floats(x: &[f32]) -> Self {
+        Self {
+            min: glm::vec3(x[0], x[1], x[2]),
+            max: glm::vec3(x[3], x[4], x[5]),
+            center: glm::vec3(x[6], x[7], x[8]),
+            radius: x[9],
+        }
+    }
+}
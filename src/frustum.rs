@@ -0,0 +1,62 @@
+use crate::bounds::Bounds;
+
+/// View frustum as planes `ax + by + cz + d >= 0` facing inside,
+/// in order left, right, bottom, top, near, far
+#[derive(Clone, Copy, Debug)]
+pub struct Frustum {
+    pub planes: [glm::Vec4; 6],
+}
+
+impl Frustum {
+    /// Planes of a view projection matrix with -1..1 clip depth (Gribb & Hartmann)
+    pub fn from_matrix(vp: &glm::Mat4) -> Self {
+        let row = |r: usize| glm::vec4(vp[0][r], vp[1][r], vp[2][r], vp[3][r]);
+        let w = row(3);
+        let planes = [
+            w + row(0),
+            w - row(0),
+            w + row(1),
+            w - row(1),
+            w + row(2),
+            w - row(2),
+        ]
+        .map(|p| {
+            // normalized so sphere tests work with distances
+            let len = glm::length(glm::vec3(p.x, p.y, p.z));
+            if len > f32::EPSILON {
+                p / len
+            } else {
+                p
+            }
+        });
+        Self { planes }
+    }
+
+    fn distance(plane: &glm::Vec4, p: glm::Vec3) -> f32 {
+        plane.x * p.x + plane.y * p.y + plane.z * p.z + plane.w
+    }
+
+    pub fn intersects_sphere(&self, center: glm::Vec3, radius: f32) -> bool {
+        self.planes
+            .iter()
+            .all(|plane| Self::distance(plane, center) >= -radius)
+    }
+
+    pub fn intersects_box(&self, min: glm::Vec3, max: glm::Vec3) -> bool {
+        self.planes.iter().all(|plane| {
+            // corner furthest along the plane normal
+            let p = glm::vec3(
+                if plane.x >= 0. { max.x } else { min.x },
+                if plane.y >= 0. { max.y } else { min.y },
+                if plane.z >= 0. { max.z } else { min.z },
+            );
+            Self::distance(plane, p) >= 0.
+        })
+    }
+
+    /// World space bounds may be visible, sphere first as the cheaper test
+    pub fn intersects(&self, bounds: &Bounds) -> bool {
+        self.intersects_sphere(bounds.center, bounds.radius)
+            && self.intersects_box(bounds.min, bounds.max)
+    }
+}
@@ -0,0 +1,107 @@
+#![allow(dead_code)]
+use glow::HasContext;
+use std::collections::HashSet;
+
+#[derive(Debug, Clone)]
+pub struct GlCaps {
+    pub vendor: String,
+    pub renderer: String,
+    pub version: String,
+    pub glsl_version: String,
+    pub major: u32,
+    pub minor: u32,
+    pub max_texture_size: u32,
+    pub max_draw_buffers: u32,
+    pub max_color_attachments: u32,
+    pub max_texture_image_units: u32,
+    pub max_vertex_attribs: u32,
+    pub extensions: HashSet<String>,
+    // feature toggles
+    pub multi_draw_indirect: bool,
+    pub shader_draw_parameters: bool,
+    pub shader_storage_buffers: bool,
+    pub compute_shaders: bool,
+    pub bindless_textures: bool,
+}
+
+impl GlCaps {
+    pub unsafe fn query(gl: &glow::Context) -> Self {
+        let version = gl.version();
+        let (major, minor) = (version.major, version.minor);
+        let extensions = gl.supported_extensions().clone();
+        macro_rules! int {
+            ($param:ident) => {
+                gl.get_parameter_i32(glow::$param).max(0) as u32
+            };
+        }
+        let at_least = |ma: u32, mi: u32| (major, minor) >= (ma, mi);
+        let ext = |name: &str| extensions.contains(name);
+        let multi_draw_indirect = at_least(4, 3) || ext("GL_ARB_multi_draw_indirect");
+        let shader_draw_parameters = at_least(4, 6) || ext("GL_ARB_shader_draw_parameters");
+        let shader_storage_buffers = at_least(4, 3) || ext("GL_ARB_shader_storage_buffer_object");
+        let compute_shaders = at_least(4, 3) || ext("GL_ARB_compute_shader");
+        let bindless_textures = ext("GL_ARB_bindless_texture");
+        Self {
+            vendor: gl.get_parameter_string(glow::VENDOR),
+            renderer: gl.get_parameter_string(glow::RENDERER),
+            version: gl.get_parameter_string(glow::VERSION),
+            glsl_version: gl.get_parameter_string(glow::SHADING_LANGUAGE_VERSION),
+            major,
+            minor,
+            max_texture_size: int!(MAX_TEXTURE_SIZE),
+            max_draw_buffers: int!(MAX_DRAW_BUFFERS),
+            max_color_attachments: int!(MAX_COLOR_ATTACHMENTS),
+            max_texture_image_units: int!(MAX_TEXTURE_IMAGE_UNITS),
+            max_vertex_attribs: int!(MAX_VERTEX_ATTRIBS),
+            multi_draw_indirect,
+            shader_draw_parameters,
+            shader_storage_buffers,
+            compute_shaders,
+            bindless_textures,
+            extensions,
+        }
+    }
+
+    pub fn has_extension(&self, name: &str) -> bool {
+        self.extensions.contains(name)
+    }
+
+    /// Clamp requested texture size to what the driver can allocate
+    pub fn clamp_texture_size(&self, size: usize) -> usize {
+        size.min(self.max_texture_size as usize)
+    }
+
+    /// Check that a framebuffer with `color_attachments` MRT outputs can be created
+    pub fn check_draw_buffers(&self, color_attachments: u32) -> Result<(), String> {
+        if color_attachments > self.max_draw_buffers
+            || color_attachments > self.max_color_attachments
+        {
+            return Err(format!(
+                "{} color attachments requested, but only {} draw buffers and {} color attachments are supported",
+                color_attachments, self.max_draw_buffers, self.max_color_attachments
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn log(&self) {
+        println!("GL vendor: {}", self.vendor);
+        println!("GL renderer: {}", self.renderer);
+        println!(
+            "GL version: {} ({}.{})",
+            self.version, self.major, self.minor
+        );
+        println!("GLSL version: {}", self.glsl_version);
+        println!("Max texture size: {}", self.max_texture_size);
+        println!("Max draw buffers: {}", self.max_draw_buffers);
+        println!("Max color attachments: {}", self.max_color_attachments);
+        println!("Max texture image units: {}", self.max_texture_image_units);
+        println!("Max vertex attribs: {}", self.max_vertex_attribs);
+        println!("Extensions: {}", self.extensions.len());
+        println!("Multi draw indirect: {}", self.multi_draw_indirect);
+        println!("Shader draw parameters: {}", self.shader_draw_parameters);
+        println!("Shader storage buffers: {}", self.shader_storage_buffers);
+        println!("Compute shaders: {}", self.compute_shaders);
+        println!("Bindless textures: {}", self.bindless_textures);
+    }
+}
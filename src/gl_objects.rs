@@ -0,0 +1,343 @@
+#![allow(dead_code)]
+use crate::gl_utils::{rebind_framebuffer, GLDrawBuffer, GLTextureAttachment, TextureParams};
+use game::memcast;
+use glow::HasContext;
+use std::cell::{Cell, RefCell};
+use std::marker::PhantomData;
+use std::rc::Rc;
+
+enum GlObject {
+    Buffer(glow::Buffer),
+    Texture(glow::Texture),
+    Framebuffer(glow::Framebuffer),
+    VertexArray(glow::VertexArray),
+    Program(glow::Program),
+}
+
+/// GL context shared by all owned objects.
+/// Dropped objects are queued and deleted in `collect_garbage`
+pub struct Gl {
+    gl: glow::Context,
+    garbage: RefCell<Vec<GlObject>>,
+    // NOTE: dropped after `gl`, so queued objects can still be deleted
+    _context: sdl2::video::GLContext,
+}
+pub type GlRef = Rc<Gl>;
+
+impl Gl {
+    pub fn new(gl: glow::Context, context: sdl2::video::GLContext) -> GlRef {
+        Rc::new(Self {
+            gl,
+            garbage: RefCell::new(Vec::new()),
+            _context: context,
+        })
+    }
+
+    fn schedule(&self, object: GlObject) {
+        self.garbage.borrow_mut().push(object);
+    }
+
+    pub fn pending_deletions(&self) -> usize {
+        self.garbage.borrow().len()
+    }
+
+    pub unsafe fn collect_garbage(&self) {
+        let gl = &self.gl;
+        for object in self.garbage.borrow_mut().drain(..) {
+            match object {
+                GlObject::Buffer(x) => gl.delete_buffer(x),
+                GlObject::Texture(x) => gl.delete_texture(x),
+                GlObject::Framebuffer(x) => gl.delete_framebuffer(x),
+                GlObject::VertexArray(x) => gl.delete_vertex_array(x),
+                GlObject::Program(x) => gl.delete_program(x),
+            }
+        }
+    }
+}
+
+impl std::ops::Deref for Gl {
+    type Target = glow::Context;
+    fn deref(&self) -> &Self::Target {
+        &self.gl
+    }
+}
+
+impl Drop for Gl {
+    fn drop(&mut self) {
+        unsafe { self.collect_garbage() }
+    }
+}
+
+macro_rules! gl_object {
+    ($name:ident, $raw:ty, $variant:ident) => {
+        impl $name {
+            pub fn raw(&self) -> $raw {
+                self.raw
+            }
+        }
+        impl Drop for $name {
+            fn drop(&mut self) {
+                self.gl.schedule(GlObject::$variant(self.raw));
+            }
+        }
+    };
+}
+
+pub struct Texture2D {
+    gl: GlRef,
+    raw: glow::Texture,
+    size: Cell<(u32, u32)>,
+}
+gl_object!(Texture2D, glow::Texture, Texture);
+
+impl Texture2D {
+    pub unsafe fn new(gl: &GlRef) -> Result<Self, String> {
+        Ok(Self {
+            gl: gl.clone(),
+            raw: gl.create_texture()?,
+            size: Cell::new((0, 0)),
+        })
+    }
+
+    /// Create texture with uninitialized storage
+    pub unsafe fn with_storage(
+        gl: &GlRef,
+        params: &TextureParams,
+        width: u32,
+        height: u32,
+    ) -> Result<Self, String> {
+        let tx = Self::new(gl)?;
+        tx.reset(params, width, height);
+        Ok(tx)
+    }
+
+    pub fn size(&self) -> (u32, u32) {
+        self.size.get()
+    }
+
+    /// Reallocate storage, contents are undefined afterwards
+    pub unsafe fn reset(&self, params: &TextureParams, width: u32, height: u32) {
+        crate::gl_utils::reset_texture(&self.gl, self.raw, params, width, height);
+        self.size.set((width, height));
+    }
+
+    pub unsafe fn upload<T>(&self, params: &TextureParams, width: u32, height: u32, data: &[T]) {
+        let gl = &self.gl;
+        gl.bind_texture(glow::TEXTURE_2D, Some(self.raw));
+        gl.tex_image_2d(
+            glow::TEXTURE_2D,
+            0,
+            params.internal_format as i32,
+            width as i32,
+            height as i32,
+            0,
+            params.format,
+            params.data_type,
+            Some(memcast::as_bytes(data)),
+        );
+        if let Some(min_filter) = params.min_filter {
+            gl.tex_parameter_i32(
+                glow::TEXTURE_2D,
+                glow::TEXTURE_MIN_FILTER,
+                min_filter as i32,
+            );
+        }
+        if let Some(mag_filter) = params.mag_filter {
+            gl.tex_parameter_i32(
+                glow::TEXTURE_2D,
+                glow::TEXTURE_MAG_FILTER,
+                mag_filter as i32,
+            );
+        }
+        gl.bind_texture(glow::TEXTURE_2D, None);
+        self.size.set((width, height));
+    }
+
+    pub unsafe fn bind(&self, unit: u32) {
+        self.gl.active_texture(glow::TEXTURE0 + unit);
+        self.gl.bind_texture(glow::TEXTURE_2D, Some(self.raw));
+    }
+}
+
+pub struct Framebuffer {
+    gl: GlRef,
+    raw: glow::Framebuffer,
+}
+gl_object!(Framebuffer, glow::Framebuffer, Framebuffer);
+
+impl Framebuffer {
+    pub unsafe fn new(gl: &GlRef) -> Result<Self, String> {
+        Ok(Self {
+            gl: gl.clone(),
+            raw: gl.create_framebuffer()?,
+        })
+    }
+
+    pub unsafe fn attach(
+        &self,
+        textures: &[(GLTextureAttachment, &Texture2D)],
+        draw_buffers: Option<&[GLDrawBuffer]>,
+    ) -> Result<(), String> {
+        let textures = textures
+            .iter()
+            .map(|(a, tx)| (*a, tx.raw))
+            .collect::<Vec<_>>();
+        rebind_framebuffer(&self.gl, self.raw, &textures, draw_buffers)
+    }
+
+    pub unsafe fn bind(&self) {
+        self.gl.bind_framebuffer(glow::FRAMEBUFFER, Some(self.raw));
+    }
+}
+
+pub type GLBufferTarget = u32;
+pub type GLBufferUsage = u32;
+pub struct Buffer<T> {
+    gl: GlRef,
+    raw: glow::Buffer,
+    target: GLBufferTarget,
+    len: Cell<usize>,
+    _type: PhantomData<T>,
+}
+
+impl<T> Buffer<T> {
+    pub unsafe fn new(gl: &GlRef, target: GLBufferTarget) -> Result<Self, String> {
+        Ok(Self {
+            gl: gl.clone(),
+            raw: gl.create_buffer()?,
+            target,
+            len: Cell::new(0),
+            _type: PhantomData,
+        })
+    }
+
+    pub unsafe fn with_data(
+        gl: &GlRef,
+        target: GLBufferTarget,
+        data: &[T],
+        usage: GLBufferUsage,
+    ) -> Result<Self, String> {
+        let buf = Self::new(gl, target)?;
+        buf.upload(data, usage);
+        Ok(buf)
+    }
+
+    pub fn raw(&self) -> glow::Buffer {
+        self.raw
+    }
+
+    /// Number of elements of `T` in the buffer
+    pub fn len(&self) -> usize {
+        self.len.get()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len.get() == 0
+    }
+
+    pub unsafe fn bind(&self) {
+        self.gl.bind_buffer(self.target, Some(self.raw));
+    }
+
+    /// Reallocate storage and fill it with `data`.
+    /// Leaves the buffer bound to its target
+    pub unsafe fn upload(&self, data: &[T], usage: GLBufferUsage) {
+        self.bind();
+        self.gl
+            .buffer_data_u8_slice(self.target, memcast::as_bytes(data), usage);
+        self.len.set(data.len());
+    }
+
+    /// Reallocate storage for `len` elements without data.
+    /// Leaves the buffer bound to its target
+    pub unsafe fn allocate(&self, len: usize, usage: GLBufferUsage) {
+        self.bind();
+        self.gl
+            .buffer_data_size(self.target, (len * std::mem::size_of::<T>()) as i32, usage);
+        self.len.set(len);
+    }
+
+    /// Bind to an indexed binding point of `target`, e.g. a storage buffer binding
+    pub unsafe fn bind_base(&self, target: GLBufferTarget, index: u32) {
+        self.gl.bind_buffer_base(target, index, Some(self.raw));
+    }
+
+    /// Overwrite part of the buffer starting at element `offset`
+    pub unsafe fn update(&self, offset: usize, data: &[T]) -> Result<(), String> {
+        if offset + data.len() > self.len.get() {
+            return Err(format!(
+                "Buffer update out of bounds: {}..{} of {}",
+                offset,
+                offset + data.len(),
+                self.len.get()
+            ));
+        }
+        self.bind();
+        self.gl.buffer_sub_data_u8_slice(
+            self.target,
+            (offset * std::mem::size_of::<T>()) as i32,
+            memcast::as_bytes(data),
+        );
+        Ok(())
+    }
+}
+
+impl<T> Drop for Buffer<T> {
+    fn drop(&mut self) {
+        self.gl.schedule(GlObject::Buffer(self.raw));
+    }
+}
+
+pub struct VertexArray {
+    gl: GlRef,
+    raw: glow::VertexArray,
+}
+gl_object!(VertexArray, glow::VertexArray, VertexArray);
+
+impl VertexArray {
+    pub unsafe fn new(gl: &GlRef) -> Result<Self, String> {
+        Ok(Self {
+            gl: gl.clone(),
+            raw: gl.create_vertex_array()?,
+        })
+    }
+
+    pub unsafe fn bind(&self) {
+        self.gl.bind_vertex_array(Some(self.raw));
+    }
+}
+
+pub struct Program {
+    gl: GlRef,
+    raw: glow::Program,
+}
+gl_object!(Program, glow::Program, Program);
+
+impl Program {
+    pub unsafe fn from_files(
+        gl: &GlRef,
+        shaders: &[(crate::loader::GLShaderType, &std::path::Path)],
+    ) -> Result<Self, String> {
+        Self::from_files_with_defines(gl, shaders, &[])
+    }
+
+    /// Compile with `#define`s inserted after the `#version` line
+    pub unsafe fn from_files_with_defines(
+        gl: &GlRef,
+        shaders: &[(crate::loader::GLShaderType, &std::path::Path)],
+        defines: &[&str],
+    ) -> Result<Self, String> {
+        Ok(Self {
+            gl: gl.clone(),
+            raw: crate::loader::load_shaders(gl, shaders, defines)?,
+        })
+    }
+
+    pub unsafe fn uniform_location(&self, name: &str) -> Option<glow::UniformLocation> {
+        self.gl.get_uniform_location(self.raw, name)
+    }
+
+    pub unsafe fn bind(&self) {
+        self.gl.use_program(Some(self.raw));
+    }
+}
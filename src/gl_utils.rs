@@ -9,6 +9,7 @@ pub unsafe fn link_program(gl: &glow::Context, program: glow::Program) -> Result
     }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct TextureParams {
     pub internal_format: u32,
     pub format: u32,
@@ -35,6 +36,9 @@ pub unsafe fn reset_texture(
         params.data_type,
         None,
     );
+    // only level 0 is allocated, without this the default
+    // mipmapped min filter leaves the texture incomplete
+    gl.tex_parameter_i32(glow::TEXTURE_2D, glow::TEXTURE_MAX_LEVEL, 0);
     if let Some(min_filter) = params.min_filter {
         gl.tex_parameter_i32(
             glow::TEXTURE_2D,
@@ -73,9 +77,60 @@ pub unsafe fn rebind_framebuffer(
     if let Some(draw_buffers) = draw_buffers {
         gl.draw_buffers(draw_buffers);
     }
-    if gl.check_framebuffer_status(glow::FRAMEBUFFER) != glow::FRAMEBUFFER_COMPLETE {
-        return Err(format!("GL Error {}", gl.get_error()));
-    }
+    let status = FramebufferStatus::from(gl.check_framebuffer_status(glow::FRAMEBUFFER));
     gl.bind_framebuffer(glow::FRAMEBUFFER, None);
+    if status != FramebufferStatus::Complete {
+        return Err(format!("Framebuffer is not complete: {}", status));
+    }
     Ok(())
 }
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FramebufferStatus {
+    Complete,
+    Undefined,
+    IncompleteAttachment,
+    IncompleteMissingAttachment,
+    IncompleteDrawBuffer,
+    IncompleteReadBuffer,
+    Unsupported,
+    IncompleteMultisample,
+    IncompleteLayerTargets,
+    Unknown(u32),
+}
+
+impl From<u32> for FramebufferStatus {
+    fn from(status: u32) -> Self {
+        match status {
+            glow::FRAMEBUFFER_COMPLETE => Self::Complete,
+            glow::FRAMEBUFFER_UNDEFINED => Self::Undefined,
+            glow::FRAMEBUFFER_INCOMPLETE_ATTACHMENT => Self::IncompleteAttachment,
+            glow::FRAMEBUFFER_INCOMPLETE_MISSING_ATTACHMENT => Self::IncompleteMissingAttachment,
+            glow::FRAMEBUFFER_INCOMPLETE_DRAW_BUFFER => Self::IncompleteDrawBuffer,
+            glow::FRAMEBUFFER_INCOMPLETE_READ_BUFFER => Self::IncompleteReadBuffer,
+            glow::FRAMEBUFFER_UNSUPPORTED => Self::Unsupported,
+            glow::FRAMEBUFFER_INCOMPLETE_MULTISAMPLE => Self::IncompleteMultisample,
+            glow::FRAMEBUFFER_INCOMPLETE_LAYER_TARGETS => Self::IncompleteLayerTargets,
+            x => Self::Unknown(x),
+        }
+    }
+}
+
+impl std::fmt::Display for FramebufferStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Complete => write!(f, "FRAMEBUFFER_COMPLETE"),
+            Self::Undefined => write!(f, "FRAMEBUFFER_UNDEFINED"),
+            Self::IncompleteAttachment => write!(f, "FRAMEBUFFER_INCOMPLETE_ATTACHMENT"),
+            Self::IncompleteMissingAttachment => {
+                write!(f, "FRAMEBUFFER_INCOMPLETE_MISSING_ATTACHMENT")
+            },
+            Self::IncompleteDrawBuffer => write!(f, "FRAMEBUFFER_INCOMPLETE_DRAW_BUFFER"),
+            Self::IncompleteReadBuffer => write!(f, "FRAMEBUFFER_INCOMPLETE_READ_BUFFER"),
+            Self::Unsupported => write!(f, "FRAMEBUFFER_UNSUPPORTED"),
+            Self::IncompleteMultisample => write!(f, "FRAMEBUFFER_INCOMPLETE_MULTISAMPLE"),
+            Self::IncompleteLayerTargets => write!(f, "FRAMEBUFFER_INCOMPLETE_LAYER_TARGETS"),
+            Self::Unknown(x) => write!(f, "unknown status 0x{:x}", x),
+        }
+    }
+}
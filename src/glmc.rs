@@ -42,31 +42,318 @@ pub const MAT4_ONE: Mat4 = mat4!(
     vec4!(0., 0., 0., 1.)
 );
 
+/// Rotation quaternion, `w` is the scalar part
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Quat {
+    pub x: f32,
+    pub y: f32,
+    pub z: f32,
+    pub w: f32,
+}
+
+impl Quat {
+    pub const IDENTITY: Quat = Quat {
+        x: 0.,
+        y: 0.,
+        z: 0.,
+        w: 1.,
+    };
+
+    pub fn from_axis_angle(axis: Vec3, radians: f32) -> Self {
+        let n = glm::normalize(axis);
+        let (s, c) = (radians / 2.).sin_cos();
+        Self {
+            x: n.x * s,
+            y: n.y * s,
+            z: n.z * s,
+            w: c,
+        }
+    }
+
+    /// Euler angles in degrees applied like `model_mat_from` did:
+    /// the matrix is Rx * Ry * Rz, so Z rotates the object first
+    pub fn from_euler(degrees: Vec3) -> Self {
+        let axis = |x, y, z, angle: f32| Self::from_axis_angle(vec3!(x, y, z), angle.to_radians());
+        axis(1., 0., 0., degrees.x) * axis(0., 1., 0., degrees.y) * axis(0., 0., 1., degrees.z)
+    }
+
+    /// Rotation taking -Z to `forward` and +Y towards `up`, like a camera looking along `forward`
+    pub fn look_rotation(forward: Vec3, up: Vec3) -> Self {
+        let f = glm::normalize(forward);
+        let mut right = glm::cross(f, up);
+        if glm::length(right) < 1e-6 {
+            // up is parallel to forward, any perpendicular will do
+            let other = if f.x.abs() < 0.9 {
+                vec3!(1., 0., 0.)
+            } else {
+                vec3!(0., 1., 0.)
+            };
+            right = glm::cross(f, other);
+        }
+        let right = glm::normalize(right);
+        let up = glm::cross(right, f);
+        Self::from_rotation_axes(right, up, -f)
+    }
+
+    /// Quaternion of the rotation matrix with orthonormal columns `c0`, `c1` and `c2`
+    pub fn from_rotation_axes(c0: Vec3, c1: Vec3, c2: Vec3) -> Self {
+        let (m00, m10, m20) = (c0.x, c0.y, c0.z);
+        let (m01, m11, m21) = (c1.x, c1.y, c1.z);
+        let (m02, m12, m22) = (c2.x, c2.y, c2.z);
+        let trace = m00 + m11 + m22;
+        // largest of w, x, y, z first for precision
+        let res = if trace > 0. {
+            let s = (trace + 1.).sqrt() * 2.;
+            Self {
+                x: (m21 - m12) / s,
+                y: (m02 - m20) / s,
+                z: (m10 - m01) / s,
+                w: 0.25 * s,
+            }
+        } else if m00 > m11 && m00 > m22 {
+            let s = (1. + m00 - m11 - m22).sqrt() * 2.;
+            Self {
+                x: 0.25 * s,
+                y: (m01 + m10) / s,
+                z: (m02 + m20) / s,
+                w: (m21 - m12) / s,
+            }
+        } else if m11 > m22 {
+            let s = (1. + m11 - m00 - m22).sqrt() * 2.;
+            Self {
+                x: (m01 + m10) / s,
+                y: 0.25 * s,
+                z: (m12 + m21) / s,
+                w: (m02 - m20) / s,
+            }
+        } else {
+            let s = (1. + m22 - m00 - m11).sqrt() * 2.;
+            Self {
+                x: (m02 + m20) / s,
+                y: (m12 + m21) / s,
+                z: 0.25 * s,
+                w: (m10 - m01) / s,
+            }
+        };
+        res.normalize()
+    }
+
+    pub fn dot(self, other: Self) -> f32 {
+        self.x * other.x + self.y * other.y + self.z * other.z + self.w * other.w
+    }
+
+    pub fn length(self) -> f32 {
+        self.dot(self).sqrt()
+    }
+
+    pub fn normalize(self) -> Self {
+        let len = self.length();
+        if len == 0. {
+            return Self::IDENTITY;
+        }
+        self.scaled(1. / len)
+    }
+
+    fn scaled(self, k: f32) -> Self {
+        Self {
+            x: self.x * k,
+            y: self.y * k,
+            z: self.z * k,
+            w: self.w * k,
+        }
+    }
+
+    pub fn conjugate(self) -> Self {
+        Self {
+            x: -self.x,
+            y: -self.y,
+            z: -self.z,
+            w: self.w,
+        }
+    }
+
+    pub fn inverse(self) -> Self {
+        self.conjugate().scaled(1. / self.dot(self))
+    }
+
+    /// Spherical interpolation along the shortest arc, `t` in 0..1
+    pub fn slerp(self, other: Self, t: f32) -> Self {
+        let mut other = other;
+        let mut cos = self.dot(other);
+        // q and -q are the same rotation
+        if cos < 0. {
+            other = other.scaled(-1.);
+            cos = -cos;
+        }
+        let (a, b) = if cos > 0.9995 {
+            // nearly parallel, sin(theta) is too small to divide by
+            (1. - t, t)
+        } else {
+            let theta = cos.acos();
+            let sin = theta.sin();
+            (((1. - t) * theta).sin() / sin, (t * theta).sin() / sin)
+        };
+        Self {
+            x: self.x * a + other.x * b,
+            y: self.y * a + other.y * b,
+            z: self.z * a + other.z * b,
+            w: self.w * a + other.w * b,
+        }
+        .normalize()
+    }
+
+    /// Columns of the rotation matrix, `self` must be normalized
+    pub fn to_axes(self) -> [Vec3; 3] {
+        let Self { x, y, z, w } = self;
+        [
+            vec3!(
+                1. - 2. * (y * y + z * z),
+                2. * (x * y + w * z),
+                2. * (x * z - w * y)
+            ),
+            vec3!(
+                2. * (x * y - w * z),
+                1. - 2. * (x * x + z * z),
+                2. * (y * z + w * x)
+            ),
+            vec3!(
+                2. * (x * z + w * y),
+                2. * (y * z - w * x),
+                1. - 2. * (x * x + y * y)
+            ),
+        ]
+    }
+
+    pub fn to_mat4(self) -> Mat4 {
+        let [c0, c1, c2] = self.to_axes();
+        Mat4::new(
+            glm::vec4(c0.x, c0.y, c0.z, 0.),
+            glm::vec4(c1.x, c1.y, c1.z, 0.),
+            glm::vec4(c2.x, c2.y, c2.z, 0.),
+            glm::vec4(0., 0., 0., 1.),
+        )
+    }
+
+    /// Rotate vector `v`
+    pub fn rotate(self, v: Vec3) -> Vec3 {
+        let q = vec3!(self.x, self.y, self.z);
+        let t = glm::cross(q, v) * 2.;
+        v + t * self.w + glm::cross(q, t)
+    }
+}
+
+impl std::ops::Mul for Quat {
+    type Output = Quat;
+
+    /// Rotation by `rhs` followed by `self`
+    fn mul(self, rhs: Quat) -> Quat {
+        let (a, b) = (self, rhs);
+        Quat {
+            x: a.w * b.x + a.x * b.w + a.y * b.z - a.z * b.y,
+            y: a.w * b.y - a.x * b.z + a.y * b.w + a.z * b.x,
+            z: a.w * b.z + a.x * b.y - a.y * b.x + a.z * b.w,
+            w: a.w * b.w - a.x * b.x - a.y * b.y - a.z * b.z,
+        }
+    }
+}
+
+/// Scale, then rotation, then translation.
+/// Composition and inverse are exact for uniform scale only,
+/// like any TRS decomposition
 #[derive(Clone, Copy, Debug)]
 pub struct Transform {
-    position: Vec3,
-    rotation: Vec3,
-    scale: Vec3,
+    pub position: Vec3,
+    pub rotation: Quat,
+    pub scale: Vec3,
 }
 impl Transform {
+    pub const IDENTITY: Transform = Transform {
+        position: vec3!(0., 0., 0.),
+        rotation: Quat::IDENTITY,
+        scale: vec3!(1., 1., 1.),
+    };
+
+    /// `rot` are Euler angles in degrees, see `Quat::from_euler`
     pub fn new(pos: Vec3, rot: Vec3, scale: Vec3) -> Self {
+        Self::from_parts(pos, Quat::from_euler(rot), scale)
+    }
+
+    pub fn from_parts(position: Vec3, rotation: Quat, scale: Vec3) -> Self {
         Self {
-            position: pos,
-            rotation: rot,
+            position,
+            rotation,
             scale,
         }
     }
+
+    /// Decompose a matrix without shear or projection.
+    /// Negative determinants are returned as negative x scale
+    pub fn from_mat4(m: &Mat4) -> Self {
+        let col = |i: usize| vec3!(m[i].x, m[i].y, m[i].z);
+        let (c0, c1, c2) = (col(0), col(1), col(2));
+        let mut scale = vec3!(glm::length(c0), glm::length(c1), glm::length(c2));
+        if glm::dot(glm::cross(c0, c1), c2) < 0. {
+            scale.x = -scale.x;
+        }
+        let axis = |c: Vec3, s: f32| if s != 0. { c / s } else { c };
+        Self {
+            position: col(3),
+            rotation: Quat::from_rotation_axes(
+                axis(c0, scale.x),
+                axis(c1, scale.y),
+                axis(c2, scale.z),
+            ),
+            scale,
+        }
+    }
+
+    pub fn to_mat4(&self) -> Mat4 {
+        let [c0, c1, c2] = self.rotation.to_axes();
+        let (c0, c1, c2) = (c0 * self.scale.x, c1 * self.scale.y, c2 * self.scale.z);
+        let p = self.position;
+        Mat4::new(
+            glm::vec4(c0.x, c0.y, c0.z, 0.),
+            glm::vec4(c1.x, c1.y, c1.z, 0.),
+            glm::vec4(c2.x, c2.y, c2.z, 0.),
+            glm::vec4(p.x, p.y, p.z, 1.),
+        )
+    }
+
+    pub fn transform_point(&self, p: Vec3) -> Vec3 {
+        self.position + self.transform_vector(p)
+    }
+
+    /// Direction or offset, not affected by translation
+    pub fn transform_vector(&self, v: Vec3) -> Vec3 {
+        self.rotation.rotate(v * self.scale)
+    }
+
+    pub fn inverse(&self) -> Self {
+        let rotation = self.rotation.inverse();
+        let scale = vec3!(1. / self.scale.x, 1. / self.scale.y, 1. / self.scale.z);
+        Self {
+            position: rotation.rotate(-self.position) * scale,
+            rotation,
+            scale,
+        }
+    }
+}
+
+impl std::ops::Mul for Transform {
+    type Output = Transform;
+
+    /// `rhs` relative to `self`, e.g. parent * child
+    fn mul(self, rhs: Transform) -> Transform {
+        Transform {
+            position: self.transform_point(rhs.position),
+            rotation: (self.rotation * rhs.rotation).normalize(),
+            scale: self.scale * rhs.scale,
+        }
+    }
 }
 
 pub fn model_mat_from(i: Transform) -> glm::Mat4 {
-    use glm::ext::{rotate, scale, translate};
-    let mut res = MAT4_ONE;
-    res = translate(&res, i.position);
-    res = rotate(&res, glm::radians(i.rotation.x), glm::vec3(1., 0., 0.));
-    res = rotate(&res, glm::radians(i.rotation.y), glm::vec3(0., 1., 0.));
-    res = rotate(&res, glm::radians(i.rotation.z), glm::vec3(0., 0., 1.));
-    res = scale(&res, i.scale);
-    res
+    i.to_mat4()
 }
 
 pub struct ComputedMatrices {
@@ -101,3 +388,171 @@ pub fn compute_matrices(
         front,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EPS: f32 = 1e-4;
+
+    fn assert_vec3(a: Vec3, b: Vec3) {
+        assert!(glm::length(a - b) < EPS, "{:?} != {:?}", a, b);
+    }
+
+    fn assert_mat4(a: &Mat4, b: &Mat4) {
+        for c in 0..4 {
+            for r in 0..4 {
+                assert!((a[c][r] - b[c][r]).abs() < EPS, "{:?} != {:?}", a, b);
+            }
+        }
+    }
+
+    // same rotation, q and -q are equivalent
+    fn assert_quat(a: Quat, b: Quat) {
+        assert!(a.dot(b).abs() > 1. - EPS, "{:?} != {:?}", a, b);
+    }
+
+    /// Matrix `model_mat_from` used to build with chained Euler rotations
+    fn euler_mat(position: Vec3, degrees: Vec3, scale: Vec3) -> Mat4 {
+        use glm::ext::{rotate, scale as scaled, translate};
+        let mut res = translate(&MAT4_ONE, position);
+        res = rotate(&res, degrees.x.to_radians(), vec3!(1., 0., 0.));
+        res = rotate(&res, degrees.y.to_radians(), vec3!(0., 1., 0.));
+        res = rotate(&res, degrees.z.to_radians(), vec3!(0., 0., 1.));
+        scaled(&res, scale)
+    }
+
+    fn sample() -> Transform {
+        Transform::new(vec3!(1., -2., 3.), vec3!(30., 45., -60.), vec3!(2., 2., 2.))
+    }
+
+    #[test]
+    fn euler_matches_chained_rotations() {
+        for degrees in [
+            vec3!(0., 0., 0.),
+            vec3!(90., 0., 0.),
+            vec3!(30., 45., -60.),
+            vec3!(170., -90., 10.),
+        ] {
+            let (p, s) = (vec3!(1., 2., 3.), vec3!(1., 2., 0.5));
+            let t = Transform::new(p, degrees, s);
+            assert_mat4(&model_mat_from(t), &euler_mat(p, degrees, s));
+        }
+    }
+
+    #[test]
+    fn axis_angle_rotates_vectors() {
+        let q = Quat::from_axis_angle(vec3!(0., 1., 0.), std::f32::consts::FRAC_PI_2);
+        assert_vec3(q.rotate(vec3!(1., 0., 0.)), vec3!(0., 0., -1.));
+        let q = Quat::from_axis_angle(vec3!(0., 0., 2.), std::f32::consts::PI);
+        assert_vec3(q.rotate(vec3!(1., 1., 0.)), vec3!(-1., -1., 0.));
+    }
+
+    #[test]
+    fn quat_matrix_round_trip() {
+        for q in [
+            Quat::IDENTITY,
+            Quat::from_euler(vec3!(30., 45., -60.)),
+            Quat::from_euler(vec3!(180., 0., 0.)),
+            Quat::from_euler(vec3!(0., 180., 0.)),
+            Quat::from_euler(vec3!(0., 0., 180.)),
+            Quat::from_axis_angle(vec3!(1., 1., 1.), 3.),
+        ] {
+            let [c0, c1, c2] = q.to_axes();
+            assert_quat(Quat::from_rotation_axes(c0, c1, c2), q);
+            let v = vec3!(0.3, -1., 2.);
+            let m = q.to_mat4() * glm::vec4(v.x, v.y, v.z, 0.);
+            assert_vec3(vec3!(m.x, m.y, m.z), q.rotate(v));
+        }
+    }
+
+    #[test]
+    fn normalize_and_inverse() {
+        let q = Quat {
+            x: 1.,
+            y: 2.,
+            z: 3.,
+            w: 4.,
+        };
+        assert!((q.normalize().length() - 1.).abs() < EPS);
+        assert_quat(q * q.inverse(), Quat::IDENTITY);
+        let zero = Quat {
+            x: 0.,
+            y: 0.,
+            z: 0.,
+            w: 0.,
+        };
+        assert_eq!(zero.normalize(), Quat::IDENTITY);
+    }
+
+    #[test]
+    fn slerp_interpolates_shortest_arc() {
+        let a = Quat::IDENTITY;
+        let b = Quat::from_axis_angle(vec3!(0., 0., 1.), std::f32::consts::FRAC_PI_2);
+        assert_quat(a.slerp(b, 0.), a);
+        assert_quat(a.slerp(b, 1.), b);
+        let half = Quat::from_axis_angle(vec3!(0., 0., 1.), std::f32::consts::FRAC_PI_4);
+        assert_quat(a.slerp(b, 0.5), half);
+        // -b is the same rotation, the result must not take the long way
+        assert_quat(a.slerp(b.scaled(-1.), 0.5), half);
+    }
+
+    #[test]
+    fn look_rotation_faces_forward() {
+        let up = vec3!(0., 1., 0.);
+        for forward in [
+            vec3!(0., 0., -1.),
+            vec3!(1., 0., 0.),
+            vec3!(1., 2., 3.),
+            vec3!(0., 1., 0.),
+        ] {
+            let q = Quat::look_rotation(forward, up);
+            assert_vec3(q.rotate(vec3!(0., 0., -1.)), glm::normalize(forward));
+        }
+        let q = Quat::look_rotation(vec3!(1., 0., 0.), up);
+        assert_vec3(q.rotate(up), up);
+    }
+
+    #[test]
+    fn matrix_decomposition_round_trip() {
+        let t = sample();
+        let d = Transform::from_mat4(&t.to_mat4());
+        assert_vec3(d.position, t.position);
+        assert_vec3(d.scale, t.scale);
+        assert_quat(d.rotation, t.rotation);
+
+        // mirrored matrices keep their handedness
+        let m = Transform::new(vec3!(0., 1., 0.), vec3!(0., 30., 0.), vec3!(-1., 2., 3.)).to_mat4();
+        assert_mat4(&Transform::from_mat4(&m).to_mat4(), &m);
+    }
+
+    #[test]
+    fn points_and_vectors_match_matrix() {
+        let t = sample();
+        let m = t.to_mat4();
+        let p = vec3!(0.5, 4., -1.);
+        let mp = m * glm::vec4(p.x, p.y, p.z, 1.);
+        let mv = m * glm::vec4(p.x, p.y, p.z, 0.);
+        assert_vec3(t.transform_point(p), vec3!(mp.x, mp.y, mp.z));
+        assert_vec3(t.transform_vector(p), vec3!(mv.x, mv.y, mv.z));
+    }
+
+    #[test]
+    fn composition_matches_matrix_product() {
+        let parent = sample();
+        let child = Transform::new(vec3!(0., 1., 0.), vec3!(10., 0., 80.), vec3!(0.5, 0.5, 0.5));
+        assert_mat4(
+            &(parent * child).to_mat4(),
+            &(parent.to_mat4() * child.to_mat4()),
+        );
+    }
+
+    #[test]
+    fn inverse_undoes_transform() {
+        let t = sample();
+        let p = vec3!(3., 2., 1.);
+        assert_vec3(t.inverse().transform_point(t.transform_point(p)), p);
+        assert_mat4(&(t * t.inverse()).to_mat4(), &MAT4_ONE);
+        assert_mat4(&t.inverse().to_mat4(), &glm::inverse(&t.to_mat4()));
+    }
+}
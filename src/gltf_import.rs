@@ -0,0 +1,236 @@
+use crate::assets::{Material, MeshData, ModelData};
+use crate::glmc::MAT4_ONE;
+use crate::model_registry::ModelId;
+use crate::normals::NormalMode;
+use crate::uvs::UvProjection;
+use std::path::{Path, PathBuf};
+
+fn mat4_from_cols(m: [[f32; 4]; 4]) -> glm::Mat4 {
+    let c = |i: usize| glm::vec4(m[i][0], m[i][1], m[i][2], m[i][3]);
+    glm::Mat4::new(c(0), c(1), c(2), c(3))
+}
+
+fn image_from_data(data: &gltf::image::Data) -> Option<image::DynamicImage> {
+    use gltf::image::Format;
+    use image::{DynamicImage, ImageBuffer};
+    let (w, h) = (data.width, data.height);
+    let px = data.pixels.clone();
+    let u16s = || {
+        data.pixels
+            .chunks_exact(2)
+            .map(|x| u16::from_ne_bytes([x[0], x[1]]))
+            .collect::<Vec<_>>()
+    };
+    let f32s = || {
+        data.pixels
+            .chunks_exact(4)
+            .map(|x| f32::from_ne_bytes([x[0], x[1], x[2], x[3]]))
+            .collect::<Vec<_>>()
+    };
+    let img = match data.format {
+        Format::R8 => DynamicImage::ImageLuma8(ImageBuffer::from_raw(w, h, px)?),
+        Format::R8G8 => DynamicImage::ImageLumaA8(ImageBuffer::from_raw(w, h, px)?),
+        Format::R8G8B8 => DynamicImage::ImageRgb8(ImageBuffer::from_raw(w, h, px)?),
+        Format::R8G8B8A8 => DynamicImage::ImageRgba8(ImageBuffer::from_raw(w, h, px)?),
+        Format::R16 => DynamicImage::ImageLuma16(ImageBuffer::from_raw(w, h, u16s())?),
+        Format::R16G16 => DynamicImage::ImageLumaA16(ImageBuffer::from_raw(w, h, u16s())?),
+        Format::R16G16B16 => DynamicImage::ImageRgb16(ImageBuffer::from_raw(w, h, u16s())?),
+        Format::R16G16B16A16 => DynamicImage::ImageRgba16(ImageBuffer::from_raw(w, h, u16s())?),
+        Format::R32G32B32FLOAT => DynamicImage::ImageRgb32F(ImageBuffer::from_raw(w, h, f32s())?),
+        Format::R32G32B32A32FLOAT => {
+            DynamicImage::ImageRgba32F(ImageBuffer::from_raw(w, h, f32s())?)
+        },
+    };
+    // atlas expects square power of two textures
+    let size = w.max(h).next_power_of_two();
+    if w != size || h != size {
+        return Some(img.resize_exact(size, size, image::imageops::FilterType::Triangle));
+    }
+    Some(img)
+}
+
+fn prepare_material(mat: gltf::Material, images: &[Option<image::DynamicImage>]) -> Material {
+    let pbr = mat.pbr_metallic_roughness();
+    let [r, g, b, a] = pbr.base_color_factor();
+    let texture = |index: usize| images.get(index).cloned().flatten();
+    let diffuse_texture = pbr
+        .base_color_texture()
+        .and_then(|x| texture(x.texture().source().index()));
+    let normal_texture = mat
+        .normal_texture()
+        .and_then(|x| texture(x.texture().source().index()));
+    let metallic = pbr.metallic_factor();
+    let roughness = pbr.roughness_factor().max(0.01);
+    // approximate Phong parameters from metallic-roughness
+    let specular = [r, g, b].map(|c| 0.04 + (c - 0.04) * metallic);
+    let shininess = (2. / roughness.powi(4) - 2.).clamp(1., 1000.);
+    let is_transparent = mat.alpha_mode() == gltf::material::AlphaMode::Blend;
+    Material {
+        name: mat.name().unwrap_or("<UNNAMED_GLTF_MATERIAL>").to_string(),
+        ambient: [1., 1., 1.],
+        diffuse: [r, g, b],
+        specular,
+        shininess,
+        dissolve: a,
+        // glTF default index of refraction
+        optical_density: 1.5,
+        ambient_texture: None,
+        diffuse_texture,
+        specular_texture: None,
+        normal_texture,
+        shininess_texture: None,
+        dissolve_texture: None,
+        illumination_model: 2,
+        is_transparent,
+    }
+}
+
+fn triangulate(mode: gltf::mesh::Mode, indices: Vec<u32>) -> Option<Vec<u32>> {
+    use gltf::mesh::Mode;
+    match mode {
+        Mode::Triangles => Some(indices),
+        Mode::TriangleStrip => Some(
+            indices
+                .windows(3)
+                .enumerate()
+                .flat_map(|(i, t)| {
+                    // keep winding order consistent
+                    if i % 2 == 0 {
+                        [t[0], t[1], t[2]]
+                    } else {
+                        [t[1], t[0], t[2]]
+                    }
+                })
+                .collect(),
+        ),
+        Mode::TriangleFan => Some(
+            indices
+                .windows(2)
+                .skip(1)
+                .flat_map(|t| [indices[0], t[0], t[1]])
+                .collect(),
+        ),
+        _ => None,
+    }
+}
+
+/// External buffers and images referenced by .gltf file
+pub fn gltf_sources(path: &Path) -> Vec<PathBuf> {
+    let Ok(gltf) = gltf::Gltf::open(path) else {
+        return Vec::new();
+    };
+    let dir = path.parent().unwrap_or(Path::new("."));
+    let buffers = gltf.buffers().filter_map(|x| match x.source() {
+        gltf::buffer::Source::Uri(uri) => Some(uri),
+        gltf::buffer::Source::Bin => None,
+    });
+    let images = gltf.images().filter_map(|x| match x.source() {
+        gltf::image::Source::Uri { uri, .. } => Some(uri),
+        gltf::image::Source::View { .. } => None,
+    });
+    buffers
+        .chain(images)
+        .filter(|uri| !uri.starts_with("data:"))
+        .map(|uri| dir.join(uri))
+        .collect()
+}
+
+/// Load meshes and materials from .gltf or .glb file.
+/// Every primitive becomes separate model with world transforms
+/// of the nodes that reference it as instances.
+/// Material ids start at `material_offset`, missing uvs are generated with `projection`
+pub fn load_gltf(
+    path: &Path,
+    material_offset: usize,
+    projection: UvProjection,
+) -> Result<(Vec<ModelData>, Vec<Material>), String> {
+    let (document, buffers, images) =
+        gltf::import(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let images = images.iter().map(image_from_data).collect::<Vec<_>>();
+    let materials = document
+        .materials()
+        .map(|x| prepare_material(x, &images))
+        .collect::<Vec<_>>();
+
+    let mut models = Vec::new();
+    // range of models created from each mesh
+    let mut mesh_models = Vec::with_capacity(document.meshes().len());
+    for mesh in document.meshes() {
+        mesh_models.push(models.len()..models.len());
+        let mesh_name = mesh
+            .name()
+            .map(|x| x.to_string())
+            .unwrap_or_else(|| format!("mesh{}", mesh.index()));
+        for primitive in mesh.primitives() {
+            let name = format!("{}.{}", mesh_name, primitive.index());
+            let reader = primitive.reader(|buffer| Some(&buffers[buffer.index()]));
+            let Some(positions) = reader.read_positions() else {
+                eprintln!("{}: {}: no positions, skipped", path.display(), name);
+                continue;
+            };
+            let vertices = positions.flatten().collect::<Vec<_>>();
+            let vertex_count = vertices.len() / 3;
+            let normals = reader
+                .read_normals()
+                .map(|x| x.flatten().collect())
+                .unwrap_or_default();
+            // flip v to match obj convention
+            let uvs = reader
+                .read_tex_coords(0)
+                .map(|x| x.into_f32().flat_map(|[u, v]| [u, 1. - v]).collect())
+                .unwrap_or_default();
+            let indices = reader
+                .read_indices()
+                .map(|x| x.into_u32().collect())
+                .unwrap_or_else(|| (0..vertex_count as u32).collect());
+            let Some(indices) = triangulate(primitive.mode(), indices) else {
+                eprintln!(
+                    "{}: {}: unsupported primitive mode {:?}, skipped",
+                    path.display(),
+                    name,
+                    primitive.mode()
+                );
+                continue;
+            };
+            let mut mesh = MeshData {
+                vertices,
+                normals,
+                uvs,
+                indices,
+            };
+            // glTF requires flat normals when they are not provided
+            mesh.complete(NormalMode::Flat, projection, None)
+                .map_err(|e| format!("{}: {}: {}", path.display(), name, e))?;
+            models.push(ModelData {
+                mesh,
+                material_id: primitive.material().index().map(|i| i + material_offset),
+                name,
+                // set by the caller
+                id: ModelId(0),
+                source: 0,
+                instances: Vec::new(),
+            });
+        }
+        mesh_models.last_mut().unwrap().end = models.len();
+    }
+
+    // collect node transforms
+    let scene = document
+        .default_scene()
+        .or_else(|| document.scenes().next());
+    let mut stack = scene
+        .iter()
+        .flat_map(|x| x.nodes())
+        .map(|x| (x, MAT4_ONE))
+        .collect::<Vec<_>>();
+    while let Some((node, parent)) = stack.pop() {
+        let world = parent * mat4_from_cols(node.transform().matrix());
+        if let Some(mesh) = node.mesh() {
+            for model in &mut models[mesh_models[mesh.index()].clone()] {
+                model.instances.push(world);
+            }
+        }
+        stack.extend(node.children().map(|x| (x, world)));
+    }
+    Ok((models, materials))
+}
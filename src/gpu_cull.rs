@@ -0,0 +1,369 @@
+use crate::gl_caps::GlCaps;
+use crate::gl_objects::{Buffer, GlRef, Program, VertexArray};
+use crate::hiz::DepthPyramid;
+use crate::multi_draw::{DrawElementsIndirectCommand, GpuMaterial, MultiDraw, MATERIALS_BINDING};
+use game::assets::BakedMeshData;
+use game::frustum::Frustum;
+use game::index_buffer::{EncodedIndices, IndexType};
+use game::memcast;
+use game::vertex_layout::EncodedVertices;
+use glow::HasContext;
+use std::cell::Cell;
+
+// std430 layouts of cull_c.glsl
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct GpuInstance {
+    model: [f32; 16],
+    tint: [f32; 4],
+    // x = model index
+    info: [u32; 4],
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct GpuModel {
+    decode: [f32; 16],
+    sphere: [f32; 4],
+    box_min: [f32; 4],
+    box_max: [f32; 4],
+    // x = first command, y = detail levels, z = first error
+    lods: [u32; 4],
+}
+
+fn gpu_instances(instances: &[(usize, glm::Mat4, [f32; 4])]) -> Vec<GpuInstance> {
+    instances
+        .iter()
+        .map(|&(i, mtx, tint)| GpuInstance {
+            model: memcast::mat4_as_array(mtx),
+            tint,
+            info: [i as u32, 0, 0, 0],
+        })
+        .collect()
+}
+
+const LOCAL_SIZE: u32 = 64;
+// must match draw instances written by the shader
+const DRAW_INSTANCE_FLOATS: usize = 24;
+// DrawArraysIndirectCommand drawing no boxes yet, 24 vertices are the 12 box edges
+const OCCLUDED_HEADER: [u32; 4] = [24, 0, 0, 0];
+// u32s per occluded box, min and max as vec4
+const OCCLUDED_BOX_WORDS: usize = 8;
+const OCCLUDED_BINDING: u32 = 6;
+// texture unit of the depth pyramid while culling
+const PYRAMID_UNIT: u32 = 0;
+
+/// Frustum culling and LOD selection of opaque instances in a compute shader.
+/// Surviving instances are appended with atomics to one indirect command
+/// per model and detail level, drawn with `MultiDraw::draw_from`.
+/// Instances can also be tested against a depth pyramid of the previous frame
+pub struct GpuCulling {
+    gl: GlRef,
+    program: Program,
+    instances: Buffer<GpuInstance>,
+    models: Buffer<GpuModel>,
+    lod_errors: Buffer<f32>,
+    commands: Buffer<DrawElementsIndirectCommand>,
+    // commands with no instances, uploaded before every dispatch
+    empty_commands: Vec<DrawElementsIndirectCommand>,
+    materials: Buffer<GpuMaterial>,
+    visible_count: Buffer<u32>,
+    // header and boxes of instances culled by occlusion, for debugging
+    occluded: Buffer<u32>,
+    bounds_program: Program,
+    bounds_vao: VertexArray,
+    // copy of `visible_count`, read once `readback_fence` has signaled
+    // so reading it never waits for the GPU
+    readback: Buffer<u32>,
+    readback_fence: Cell<Option<glow::Fence>>,
+    // instances drawn by the latest dispatch whose count has arrived
+    last_visible: Cell<u32>,
+    // (index type, first command, command count)
+    calls: Vec<(IndexType, usize, usize)>,
+    instance_count: usize,
+    // draw instances reserved for all commands
+    output_len: usize,
+}
+
+impl GpuCulling {
+    /// `None` without compute shaders or multi draw.
+    /// `instances` are (model, model matrix, tint) of opaque models
+    #[allow(clippy::too_many_arguments)]
+    pub unsafe fn new(
+        gl: &GlRef,
+        caps: &GlCaps,
+        multi_draw: Option<&MultiDraw>,
+        data: &BakedMeshData,
+        vertices: &EncodedVertices,
+        indices: &EncodedIndices,
+        instances: &[(usize, glm::Mat4, [f32; 4])],
+        material: impl Fn(usize) -> GpuMaterial,
+    ) -> Result<Option<Self>, String> {
+        if multi_draw.is_none() || !caps.compute_shaders {
+            return Ok(None);
+        }
+        let program = Program::from_files(
+            gl,
+            &[(
+                glow::COMPUTE_SHADER,
+                std::path::Path::new("./data/shaders/cull_c.glsl"),
+            )],
+        )?;
+        let bounds_program = Program::from_files(
+            gl,
+            &[
+                (
+                    glow::VERTEX_SHADER,
+                    std::path::Path::new("./data/shaders/bounds_v.glsl"),
+                ),
+                (
+                    glow::FRAGMENT_SHADER,
+                    std::path::Path::new("./data/shaders/bounds_f.glsl"),
+                ),
+            ],
+        )?;
+
+        let mut instance_counts = vec![0u32; data.model_count()];
+        for &(i, _, _) in instances {
+            instance_counts[i] += 1;
+        }
+        // commands grouped by index type, every level has room for all instances
+        let mut gpu_models = Vec::with_capacity(data.model_count());
+        let mut commands = Vec::new();
+        let mut materials = Vec::new();
+        let mut calls = Vec::new();
+        let mut first_commands = vec![0; data.model_count()];
+        let mut output_len = 0;
+        for index_type in [IndexType::U16, IndexType::U32] {
+            let first = commands.len();
+            for model in 0..data.model_count() {
+                let levels = data.lod_count(model);
+                let range = &indices.ranges[model];
+                if instance_counts[model] == 0 || range.index_type != index_type {
+                    continue;
+                }
+                first_commands[model] = commands.len();
+                for level in 0..levels {
+                    let r = &indices.ranges[data.lod_range(model, level)];
+                    commands.push(DrawElementsIndirectCommand {
+                        count: r.count as u32,
+                        instance_count: 0,
+                        first_index: (r.offset / index_type.size()) as u32,
+                        base_vertex: r.base_vertex as i32,
+                        base_instance: output_len,
+                    });
+                    materials.push(material(model));
+                    output_len += instance_counts[model];
+                }
+            }
+            if commands.len() > first {
+                calls.push((index_type, first, commands.len() - first));
+            }
+        }
+        for (model, &first_command) in first_commands.iter().enumerate() {
+            let b = data.bounds(model, 0);
+            let v = |x: glm::Vec3, w: f32| [x.x, x.y, x.z, w];
+            gpu_models.push(GpuModel {
+                decode: memcast::mat4_as_array(vertices.position_decode[model]),
+                sphere: v(b.center, b.radius),
+                box_min: v(b.min, 0.),
+                box_max: v(b.max, 0.),
+                lods: [
+                    first_command as u32,
+                    data.lod_count(model) as u32,
+                    data.lod_ranges[model],
+                    0,
+                ],
+            });
+        }
+        let gpu_instances = gpu_instances(instances);
+
+        let storage = glow::SHADER_STORAGE_BUFFER;
+        let res = Self {
+            gl: gl.clone(),
+            program,
+            instances: Buffer::with_data(gl, storage, &gpu_instances, glow::DYNAMIC_DRAW)?,
+            models: Buffer::with_data(gl, storage, &gpu_models, glow::STATIC_DRAW)?,
+            lod_errors: Buffer::with_data(gl, storage, &data.lod_errors, glow::STATIC_DRAW)?,
+            commands: Buffer::with_data(
+                gl,
+                glow::DRAW_INDIRECT_BUFFER,
+                &commands,
+                glow::DYNAMIC_DRAW,
+            )?,
+            empty_commands: commands,
+            materials: Buffer::with_data(gl, storage, &materials, glow::STATIC_DRAW)?,
+            visible_count: Buffer::with_data(
+                gl,
+                glow::ATOMIC_COUNTER_BUFFER,
+                &[0],
+                glow::DYNAMIC_READ,
+            )?,
+            occluded: Buffer::new(gl, storage)?,
+            bounds_program,
+            bounds_vao: VertexArray::new(gl)?,
+            readback: Buffer::with_data(gl, glow::COPY_WRITE_BUFFER, &[0], glow::STREAM_READ)?,
+            readback_fence: Cell::new(None),
+            last_visible: Cell::new(0),
+            calls,
+            instance_count: instances.len(),
+            output_len: output_len as usize,
+        };
+        // lod errors may be empty, storage buffers can't be
+        if data.lod_errors.is_empty() {
+            res.lod_errors.upload(&[0.], glow::STATIC_DRAW);
+        }
+        res.occluded.allocate(
+            OCCLUDED_HEADER.len() + instances.len() * OCCLUDED_BOX_WORDS,
+            glow::DYNAMIC_DRAW,
+        );
+        Ok(Some(res))
+    }
+
+    /// Replace instance transforms and tints, e.g. after scene nodes moved.
+    /// Instances must use the same models in the same order as in `new`
+    pub unsafe fn update_instances(
+        &self,
+        instances: &[(usize, glm::Mat4, [f32; 4])],
+    ) -> Result<(), String> {
+        if instances.len() != self.instance_count {
+            return Err(format!(
+                "{} instances to cull, {} given",
+                self.instance_count,
+                instances.len()
+            ));
+        }
+        self.instances.update(0, &gpu_instances(instances))
+    }
+
+    pub fn instance_count(&self) -> usize {
+        self.instance_count
+    }
+
+    /// Instances that passed an earlier `cull`, usually a frame or two old
+    pub fn last_visible(&self) -> u32 {
+        self.last_visible.get()
+    }
+
+    /// Cull all instances, writing draw instances into `output`.
+    /// With `occlusion`, instances hidden in the pyramid built with the given
+    /// view projection matrix are culled too
+    #[allow(clippy::too_many_arguments)]
+    pub unsafe fn cull(
+        &self,
+        frustum: &Frustum,
+        camera_position: glm::Vec3,
+        lod_scale: f32,
+        near: f32,
+        occlusion: Option<(&DepthPyramid, &glm::Mat4)>,
+        output: &Buffer<f32>,
+    ) {
+        let gl = &self.gl;
+        if let Some(fence) = self.readback_fence.get() {
+            let status = gl.client_wait_sync(fence, 0, 0);
+            if status == glow::ALREADY_SIGNALED || status == glow::CONDITION_SATISFIED {
+                let mut count = [0u8; 4];
+                self.readback.bind();
+                gl.get_buffer_sub_data(glow::COPY_WRITE_BUFFER, 0, &mut count);
+                self.last_visible.set(u32::from_le_bytes(count));
+                gl.delete_sync(fence);
+                self.readback_fence.set(None);
+            }
+        }
+        self.visible_count.upload(&[0], glow::DYNAMIC_READ);
+        self.commands
+            .upload(&self.empty_commands, glow::DYNAMIC_DRAW);
+        // can't fail, the buffer always has room for the header
+        let _ = self.occluded.update(0, &OCCLUDED_HEADER);
+        output.allocate(self.output_len * DRAW_INSTANCE_FLOATS, glow::STREAM_DRAW);
+
+        self.program.bind();
+        let storage = glow::SHADER_STORAGE_BUFFER;
+        self.instances.bind_base(storage, 1);
+        self.models.bind_base(storage, 2);
+        self.lod_errors.bind_base(storage, 3);
+        self.commands.bind_base(storage, 4);
+        output.bind_base(storage, 5);
+        self.occluded.bind_base(storage, OCCLUDED_BINDING);
+        self.visible_count.bind_base(glow::ATOMIC_COUNTER_BUFFER, 0);
+
+        let u = |name: &str| self.program.uniform_location(name);
+        gl.uniform_1_u32(u("instance_count").as_ref(), self.instance_count as u32);
+        let planes = frustum
+            .planes
+            .iter()
+            .flat_map(|p| [p.x, p.y, p.z, p.w])
+            .collect::<Vec<_>>();
+        gl.uniform_4_f32_slice(u("planes").as_ref(), &planes);
+        let c = camera_position;
+        gl.uniform_3_f32(u("camera_position").as_ref(), c.x, c.y, c.z);
+        gl.uniform_1_f32(u("lod_scale").as_ref(), lod_scale);
+        gl.uniform_1_f32(u("near").as_ref(), near);
+        gl.uniform_1_i32(u("occlusion").as_ref(), occlusion.is_some() as i32);
+        if let Some((pyramid, prev_vp)) = occlusion {
+            pyramid.bind(PYRAMID_UNIT);
+            gl.uniform_1_i32(u("depth_pyramid").as_ref(), PYRAMID_UNIT as i32);
+            gl.uniform_1_i32(u("pyramid_levels").as_ref(), pyramid.level_count() as i32);
+            gl.uniform_matrix_4_f32_slice(
+                u("prev_vp").as_ref(),
+                false,
+                &memcast::mat4_as_array(*prev_vp),
+            );
+        }
+        gl.dispatch_compute((self.instance_count as u32).div_ceil(LOCAL_SIZE), 1, 1);
+        gl.memory_barrier(
+            glow::COMMAND_BARRIER_BIT
+                | glow::VERTEX_ATTRIB_ARRAY_BARRIER_BIT
+                | glow::ATOMIC_COUNTER_BARRIER_BIT
+                | glow::SHADER_STORAGE_BARRIER_BIT
+                | glow::BUFFER_UPDATE_BARRIER_BIT,
+        );
+        // one copy in flight at a time, later counts are skipped until it lands
+        if self.readback_fence.get().is_none() {
+            gl.bind_buffer(glow::COPY_READ_BUFFER, Some(self.visible_count.raw()));
+            gl.bind_buffer(glow::COPY_WRITE_BUFFER, Some(self.readback.raw()));
+            gl.copy_buffer_sub_data(glow::COPY_READ_BUFFER, glow::COPY_WRITE_BUFFER, 0, 0, 4);
+            let fence = gl.fence_sync(glow::SYNC_GPU_COMMANDS_COMPLETE, 0).ok();
+            self.readback_fence.set(fence);
+        }
+    }
+
+    /// Draw world boxes of instances culled by the last occlusion test as lines
+    pub unsafe fn draw_occluded(&self, vp: &glm::Mat4) {
+        let gl = &self.gl;
+        self.bounds_program.bind();
+        gl.uniform_matrix_4_f32_slice(
+            self.bounds_program.uniform_location("vp").as_ref(),
+            false,
+            &memcast::mat4_as_array(*vp),
+        );
+        self.bounds_vao.bind();
+        self.occluded
+            .bind_base(glow::SHADER_STORAGE_BUFFER, OCCLUDED_BINDING);
+        gl.bind_buffer(glow::DRAW_INDIRECT_BUFFER, Some(self.occluded.raw()));
+        gl.draw_arrays_indirect_offset(glow::LINES, 0);
+    }
+
+    /// Draw the culled instances with the bound multi draw program
+    pub unsafe fn draw(
+        &self,
+        multi_draw: &MultiDraw,
+        draw_offset: Option<&glow::UniformLocation>,
+    ) -> u32 {
+        self.materials
+            .bind_base(glow::SHADER_STORAGE_BUFFER, MATERIALS_BINDING);
+        for &(index_type, first, count) in &self.calls {
+            self.gl.uniform_1_i32(draw_offset, first as i32);
+            multi_draw.draw_from(&self.commands, index_type, first, count);
+        }
+        self.calls.len() as u32
+    }
+}
+
+impl Drop for GpuCulling {
+    fn drop(&mut self) {
+        if let Some(fence) = self.readback_fence.take() {
+            unsafe { self.gl.delete_sync(fence) }
+        }
+    }
+}
@@ -0,0 +1,198 @@
+use crate::gl_objects::{GlRef, Program, Texture2D};
+use glow::HasContext;
+use std::cell::RefCell;
+
+const LOCAL_SIZE: u32 = 8;
+
+/// Max depth mip chain of a depth buffer for occlusion tests.
+/// Level 0 has the depth buffer size, every texel of the next level
+/// covers 2x2 texels (3 on odd edges) of the previous one
+pub struct DepthPyramid {
+    gl: GlRef,
+    texture: Texture2D,
+    program: Program,
+    // size of every level
+    levels: RefCell<Vec<(u32, u32)>>,
+}
+
+impl DepthPyramid {
+    pub unsafe fn new(gl: &GlRef, width: u32, height: u32) -> Result<Self, String> {
+        let program = Program::from_files(
+            gl,
+            &[(
+                glow::COMPUTE_SHADER,
+                std::path::Path::new("./data/shaders/hiz_c.glsl"),
+            )],
+        )?;
+        let res = Self {
+            gl: gl.clone(),
+            texture: Texture2D::new(gl)?,
+            program,
+            levels: RefCell::new(Vec::new()),
+        };
+        res.resize(width, height);
+        Ok(res)
+    }
+
+    pub fn level_count(&self) -> usize {
+        self.levels.borrow().len()
+    }
+
+    /// Reallocate all levels, contents are undefined until the next `build`
+    pub unsafe fn resize(&self, width: u32, height: u32) {
+        let gl = &self.gl;
+        let mut levels = self.levels.borrow_mut();
+        levels.clear();
+        let (mut w, mut h) = (width.max(1), height.max(1));
+        loop {
+            levels.push((w, h));
+            if w == 1 && h == 1 {
+                break;
+            }
+            (w, h) = ((w / 2).max(1), (h / 2).max(1));
+        }
+        gl.bind_texture(glow::TEXTURE_2D, Some(self.texture.raw()));
+        for (level, &(w, h)) in levels.iter().enumerate() {
+            gl.tex_image_2d(
+                glow::TEXTURE_2D,
+                level as i32,
+                glow::R32F as i32,
+                w as i32,
+                h as i32,
+                0,
+                glow::RED,
+                glow::FLOAT,
+                None,
+            );
+        }
+        let param = |name, value: u32| gl.tex_parameter_i32(glow::TEXTURE_2D, name, value as i32);
+        param(glow::TEXTURE_MAX_LEVEL, levels.len() as u32 - 1);
+        param(glow::TEXTURE_MIN_FILTER, glow::NEAREST_MIPMAP_NEAREST);
+        param(glow::TEXTURE_MAG_FILTER, glow::NEAREST);
+        param(glow::TEXTURE_WRAP_S, glow::CLAMP_TO_EDGE);
+        param(glow::TEXTURE_WRAP_T, glow::CLAMP_TO_EDGE);
+        gl.bind_texture(glow::TEXTURE_2D, None);
+    }
+
+    /// Rebuild all levels from the depth texture bound to `depth_unit`
+    pub unsafe fn build(&self, depth_unit: u32) {
+        let gl = &self.gl;
+        let levels = self.levels.borrow();
+        self.program.bind();
+        let u = |name: &str| self.program.uniform_location(name);
+        gl.uniform_1_i32(u("depth").as_ref(), depth_unit as i32);
+        let raw = self.texture.raw();
+        for (level, &(w, h)) in levels.iter().enumerate() {
+            let src = level.saturating_sub(1);
+            let (sw, sh) = levels[src];
+            gl.bind_image_texture(0, raw, src as i32, false, 0, glow::READ_ONLY, glow::R32F);
+            gl.bind_image_texture(1, raw, level as i32, false, 0, glow::WRITE_ONLY, glow::R32F);
+            gl.uniform_1_i32(u("from_depth").as_ref(), (level == 0) as i32);
+            gl.uniform_2_i32(u("src_size").as_ref(), sw as i32, sh as i32);
+            gl.uniform_2_i32(u("dst_size").as_ref(), w as i32, h as i32);
+            gl.dispatch_compute(w.div_ceil(LOCAL_SIZE), h.div_ceil(LOCAL_SIZE), 1);
+            gl.memory_barrier(glow::SHADER_IMAGE_ACCESS_BARRIER_BIT);
+        }
+        gl.memory_barrier(glow::TEXTURE_FETCH_BARRIER_BIT);
+    }
+
+    pub unsafe fn bind(&self, unit: u32) {
+        self.texture.bind(unit);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // (width, height, depths) of every level, as built by hiz_c.glsl
+    fn build(depth: &[f32], width: usize, height: usize) -> Vec<(usize, usize, Vec<f32>)> {
+        let mut levels = vec![(width, height, depth.to_vec())];
+        loop {
+            let (sw, sh, src) = levels.last().unwrap();
+            let (sw, sh) = (*sw, *sh);
+            if sw == 1 && sh == 1 {
+                return levels;
+            }
+            let (w, h) = ((sw / 2).max(1), (sh / 2).max(1));
+            // the last row and column also cover the leftovers of odd sizes
+            let covered = |p: usize, size: usize, src_size: usize| {
+                let end = if p == size - 1 { src_size } else { 2 * p + 2 };
+                2 * p..end.min(src_size).max(2 * p + 1)
+            };
+            let mut dst = vec![0.; w * h];
+            for y in 0..h {
+                for x in 0..w {
+                    for sy in covered(y, h, sh) {
+                        for sx in covered(x, w, sw) {
+                            dst[y * w + x] = f32::max(dst[y * w + x], src[sy * sw + sx]);
+                        }
+                    }
+                }
+            }
+            levels.push((w, h, dst));
+        }
+    }
+
+    // `is_occluded` of cull_c.glsl for a screen rectangle and its nearest depth
+    fn is_occluded(
+        levels: &[(usize, usize, Vec<f32>)],
+        uv_min: [f32; 2],
+        uv_max: [f32; 2],
+        depth: f32,
+    ) -> bool {
+        let (w, h, _) = levels[0];
+        let extent = ((uv_max[0] - uv_min[0]) * w as f32).max((uv_max[1] - uv_min[1]) * h as f32);
+        let level = (extent.max(1.).log2().ceil() as usize).min(levels.len() - 1);
+        let (lw, lh, data) = &levels[level];
+        let texel = |uv: [f32; 2]| {
+            let x = ((uv[0] * w as f32) as usize).min(w - 1) >> level;
+            let y = ((uv[1] * h as f32) as usize).min(h - 1) >> level;
+            (x.min(lw - 1), y.min(lh - 1))
+        };
+        let (p0, p1) = (texel(uv_min), texel(uv_max));
+        let farthest = [(p0.0, p0.1), (p1.0, p0.1), (p0.0, p1.1), (p1.0, p1.1)]
+            .iter()
+            .map(|&(x, y)| data[y * lw + x])
+            .fold(0., f32::max);
+        depth > farthest
+    }
+
+    // cleared depth with rectangles drawn in order
+    fn depth_buffer(width: usize, height: usize, rects: &[([f32; 2], [f32; 2], f32)]) -> Vec<f32> {
+        let mut res = vec![1.; width * height];
+        for &(min, max, depth) in rects {
+            for y in 0..height {
+                for x in 0..width {
+                    let uv = [
+                        (x as f32 + 0.5) / width as f32,
+                        (y as f32 + 0.5) / height as f32,
+                    ];
+                    if (0..2).all(|k| min[k] <= uv[k] && uv[k] <= max[k]) {
+                        res[y * width + x] = f32::min(res[y * width + x], depth);
+                    }
+                }
+            }
+        }
+        res
+    }
+
+    #[test]
+    fn wall_hides_only_what_is_behind_it() {
+        let wall = ([0.05, 0.05], [0.95, 0.95], 0.5);
+        let front = ([0.4, 0.4], [0.6, 0.6], 0.3);
+        // odd sizes, so the leftover texels are covered
+        let (w, h) = (101, 75);
+        let levels = build(&depth_buffer(w, h, &[wall, front]), w, h);
+        assert_eq!(levels.last().unwrap().0, 1);
+        assert!(!is_occluded(&levels, front.0, front.1, front.2));
+        assert!(is_occluded(&levels, [0.45, 0.45], [0.55, 0.55], 0.7));
+        assert!(is_occluded(&levels, [0.2, 0.3], [0.3, 0.4], 0.7));
+        // partly beside the wall
+        assert!(!is_occluded(&levels, [0.9, 0.4], [0.99, 0.6], 0.7));
+        // next to the wall at the odd right edge
+        assert!(!is_occluded(&levels, [0.97, 0.1], [1., 0.15], 0.9));
+
+        // an incomplete depth texture reads as 0 and hides everything
+        let levels = build(&vec![0.; w * h], w, h);
+        assert!(is_occluded(&levels, front.0, front.1, front.2));
+    }
+}
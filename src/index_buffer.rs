@@ -0,0 +1,95 @@
+use crate::assets::BakedMeshData;
+use crate::mesh_cache::BufferData;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IndexType {
+    U16,
+    U32,
+}
+
+impl IndexType {
+    pub fn size(&self) -> usize {
+        match self {
+            IndexType::U16 => 2,
+            IndexType::U32 => 4,
+        }
+    }
+
+    pub fn gl_type(&self) -> u32 {
+        match self {
+            IndexType::U16 => glow::UNSIGNED_SHORT,
+            IndexType::U32 => glow::UNSIGNED_INT,
+        }
+    }
+}
+
+/// Arguments of `draw_elements_base_vertex` for one range of offsets and counts
+#[derive(Clone, Copy, Debug)]
+pub struct DrawRange {
+    pub index_type: IndexType,
+    // in bytes
+    pub offset: usize,
+    pub count: usize,
+    pub base_vertex: u32,
+}
+
+/// Element buffer with indices relative to the first vertex of their model
+pub struct EncodedIndices {
+    pub data: BufferData,
+    // same order as `BakedMeshData::offsets`
+    pub ranges: Vec<DrawRange>,
+}
+
+impl EncodedIndices {
+    pub fn count(&self, index_type: IndexType) -> usize {
+        self.ranges
+            .iter()
+            .filter(|x| x.index_type == index_type)
+            .count()
+    }
+}
+
+/// Split indices into base vertex ranges, 16 bit where the model
+/// has fewer than 65536 vertices
+pub fn encode_indices(data: &BakedMeshData) -> EncodedIndices {
+    let mut range_models = vec![0; data.offsets.len()];
+    for model in 0..data.model_count() {
+        for level in 0..data.lod_count(model) {
+            range_models[data.lod_range(model, level)] = model;
+        }
+    }
+
+    let mut res = Vec::with_capacity(data.indices.len() * 2);
+    let mut ranges = Vec::with_capacity(data.offsets.len());
+    for (range, &model) in range_models.iter().enumerate() {
+        let base_vertex = data.vertex_offsets[model];
+        let vertex_count = data.vertex_offsets[model + 1] - base_vertex;
+        let index_type = if vertex_count < 1 << 16 {
+            IndexType::U16
+        } else {
+            IndexType::U32
+        };
+        // offsets must be aligned to the index size
+        res.resize(res.len().next_multiple_of(index_type.size()), 0);
+        let start = data.offsets[range] as usize;
+        let count = data.counts[range] as usize;
+        ranges.push(DrawRange {
+            index_type,
+            offset: res.len(),
+            count,
+            base_vertex,
+        });
+        for &i in &data.indices[start..start + count] {
+            let i = i - base_vertex;
+            match index_type {
+                IndexType::U16 => res.extend((i as u16).to_le_bytes()),
+                IndexType::U32 => res.extend(i.to_le_bytes()),
+            }
+        }
+    }
+    res.shrink_to_fit();
+    EncodedIndices {
+        data: BufferData::Owned(res),
+        ranges,
+    }
+}
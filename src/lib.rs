@@ -0,0 +1,19 @@
+//! Asset processing shared by the game and the offline `bake` tool
+pub mod assets;
+pub mod atlas;
+pub mod bounds;
+pub mod frustum;
+pub mod glmc;
+pub mod gltf_import;
+pub mod index_buffer;
+pub mod memcast;
+pub mod mesh_cache;
+pub mod model_registry;
+pub mod normals;
+pub mod scene;
+pub mod scene_graph;
+pub mod simplify;
+pub mod tangents;
+pub mod uvs;
+pub mod vertex_cache;
+pub mod vertex_layout;
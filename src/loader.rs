@@ -1,20 +1,33 @@
+use crate::gl_objects::{Buffer, Gl, GlRef, Program, VertexArray};
 use crate::gl_utils::link_program;
-use crate::memcast;
-use crate::BakedMeshData;
+use game::index_buffer::EncodedIndices;
+use game::vertex_layout::EncodedVertices;
 use glow::HasContext;
 
 pub struct InitializedWindow {
-    pub gl: glow::Context,
+    pub gl: GlRef,
     pub sdl: sdl2::Sdl,
     pub window: sdl2::video::Window,
     pub event_loop: sdl2::EventPump,
-    #[allow(unused)]
-    pub gl_context: sdl2::video::GLContext,
 }
 pub type GLShaderType = u32;
+/// Insert `#define`s after the `#version` line
+fn with_defines(source: String, defines: &[&str]) -> String {
+    if defines.is_empty() {
+        return source;
+    }
+    let (version, rest) = source.split_once('\n').unwrap_or((&source, ""));
+    let mut res = format!("{}\n", version);
+    for define in defines {
+        res += &format!("#define {}\n", define);
+    }
+    res + rest
+}
+
 pub unsafe fn load_shaders(
     gl: &glow::Context,
     shaders: &[(GLShaderType, &std::path::Path)],
+    defines: &[&str],
 ) -> Result<glow::Program, String> {
     use std::fs::read_to_string;
     let program = gl.create_program()?;
@@ -25,6 +38,7 @@ pub unsafe fn load_shaders(
             .canonicalize()
             .unwrap_or_else(|_| panic!("Cannot load shader: {}", path.display()));
         let source = read_to_string(&path_abs).map_err(|e| e.to_string())?;
+        let source = with_defines(source, defines);
         let shader = gl.create_shader(*shader_type)?;
         gl.shader_source(shader, &source);
         gl.compile_shader(shader);
@@ -36,6 +50,7 @@ pub unsafe fn load_shaders(
 
     for shader in shaders_compiled {
         gl.detach_shader(program, shader);
+        gl.delete_shader(shader);
     }
     Ok(program)
 }
@@ -56,21 +71,28 @@ pub fn init_window(width: u32, height: u32) -> Result<InitializedWindow, String>
         .build()
         .map_err(|e| e.to_string())?;
     let gl_context = window.gl_create_context()?;
-    let gl = unsafe {
+    let mut gl = unsafe {
         glow::Context::from_loader_function(|s| video.gl_get_proc_address(s) as *const _)
     };
+    unsafe {
+        gl.enable(glow::DEBUG_OUTPUT);
+        gl.debug_message_callback(debug_message_callback);
+    }
     let event_loop = sdl.event_pump()?;
     Ok(InitializedWindow {
-        gl,
+        gl: Gl::new(gl, gl_context),
         sdl,
         window,
         event_loop,
-        gl_context,
     })
 }
 
+fn debug_message_callback(_source: u32, _typ: u32, id: u32, _severity: u32, message: &str) {
+    eprintln!("GL error {:0x}: {}", id, message);
+}
+
 pub struct SolidShaderUniforms {
-    pub mvp: Option<glow::UniformLocation>,
+    pub vp: Option<glow::UniformLocation>,
     pub near: Option<glow::UniformLocation>,
     pub far: Option<glow::UniformLocation>,
     pub ambient_color: Option<glow::UniformLocation>,
@@ -78,10 +100,16 @@ pub struct SolidShaderUniforms {
     pub specular_color: Option<glow::UniformLocation>,
     pub diffuse_texture: Option<glow::UniformLocation>,
     pub opts: Option<glow::UniformLocation>,
+    pub draw_offset: Option<glow::UniformLocation>,
+    pub shininess: Option<glow::UniformLocation>,
+    pub camera_position: Option<glow::UniformLocation>,
+    pub light_position: Option<glow::UniformLocation>,
+    pub light_power: Option<glow::UniformLocation>,
+    pub light_intensity: Option<glow::UniformLocation>,
 }
 
 pub struct TransparentShaderUniforms {
-    pub mvp: Option<glow::UniformLocation>,
+    pub vp: Option<glow::UniformLocation>,
     pub near: Option<glow::UniformLocation>,
     pub far: Option<glow::UniformLocation>,
     pub ambient_color: Option<glow::UniformLocation>,
@@ -90,16 +118,32 @@ pub struct TransparentShaderUniforms {
     pub diffuse_texture: Option<glow::UniformLocation>,
     pub dissolve: Option<glow::UniformLocation>,
     pub opts: Option<glow::UniformLocation>,
+    pub draw_offset: Option<glow::UniformLocation>,
+    pub shininess: Option<glow::UniformLocation>,
+    pub camera_position: Option<glow::UniformLocation>,
+    pub light_position: Option<glow::UniformLocation>,
+    pub light_power: Option<glow::UniformLocation>,
+    pub light_intensity: Option<glow::UniformLocation>,
+}
+
+/// Solid and transparent variants with materials indexed by draw id
+pub struct MultiDrawShaders {
+    pub solid: Program,
+    pub solid_u: SolidShaderUniforms,
+    pub transparent: Program,
+    pub transparent_u: TransparentShaderUniforms,
 }
 
 pub struct Shaders {
-    pub solid: glow::Program,
-    pub transparent: glow::Program,
-    pub composite: glow::Program,
-    pub screen: glow::Program,
+    pub solid: Program,
+    pub transparent: Program,
+    pub composite: Program,
+    pub screen: Program,
+    pub multi_draw: Option<MultiDrawShaders>,
 }
 pub unsafe fn init_shaders(
-    gl: &glow::Context,
+    gl: &GlRef,
+    multi_draw: bool,
 ) -> Result<(Shaders, SolidShaderUniforms, TransparentShaderUniforms), String> {
     macro_rules! prefix {
         () => {
@@ -121,44 +165,80 @@ pub unsafe fn init_shaders(
     let composite_shaders = s!("composite");
     let screen_shaders = s!("screen");
 
-    let solid = load_shaders(gl, solid_shaders)?;
-    let transparent = load_shaders(gl, transparent_shaders)?;
-    let composite = load_shaders(gl, composite_shaders)?;
-    let screen = load_shaders(gl, screen_shaders)?;
+    let solid = Program::from_files(gl, solid_shaders)?;
+    let transparent = Program::from_files(gl, transparent_shaders)?;
+    let composite = Program::from_files(gl, composite_shaders)?;
+    let screen = Program::from_files(gl, screen_shaders)?;
 
     macro_rules! u {
         ($ty:tt, $shader:ident, $($uname:ident),+) => {
             $ty {
-            $($uname: gl.get_uniform_location($shader, stringify!($uname))),+
+            $($uname: $shader.uniform_location(stringify!($uname))),+
             }
         };
     }
 
-    let solid_u = u!(
-        SolidShaderUniforms,
-        solid,
-        mvp,
-        near,
-        far,
-        ambient_color,
-        diffuse_color,
-        specular_color,
-        diffuse_texture,
-        opts
-    );
-    let transparent_u = u!(
-        TransparentShaderUniforms,
-        transparent,
-        mvp,
-        near,
-        far,
-        ambient_color,
-        diffuse_color,
-        specular_color,
-        diffuse_texture,
-        dissolve,
-        opts
-    );
+    macro_rules! solid_u {
+        ($shader:ident) => {
+            u!(
+                SolidShaderUniforms,
+                $shader,
+                vp,
+                near,
+                far,
+                ambient_color,
+                diffuse_color,
+                specular_color,
+                diffuse_texture,
+                opts,
+                draw_offset,
+                shininess,
+                camera_position,
+                light_position,
+                light_power,
+                light_intensity
+            )
+        };
+    }
+    macro_rules! transparent_u {
+        ($shader:ident) => {
+            u!(
+                TransparentShaderUniforms,
+                $shader,
+                vp,
+                near,
+                far,
+                ambient_color,
+                diffuse_color,
+                specular_color,
+                diffuse_texture,
+                dissolve,
+                opts,
+                draw_offset,
+                shininess,
+                camera_position,
+                light_position,
+                light_power,
+                light_intensity
+            )
+        };
+    }
+    let solid_u = solid_u!(solid);
+    let transparent_u = transparent_u!(transparent);
+
+    let multi_draw = if multi_draw {
+        let defines = &["MULTI_DRAW"];
+        let solid = Program::from_files_with_defines(gl, solid_shaders, defines)?;
+        let transparent = Program::from_files_with_defines(gl, transparent_shaders, defines)?;
+        Some(MultiDrawShaders {
+            solid_u: solid_u!(solid),
+            transparent_u: transparent_u!(transparent),
+            solid,
+            transparent,
+        })
+    } else {
+        None
+    };
 
     Ok((
         Shaders {
@@ -166,63 +246,96 @@ pub unsafe fn init_shaders(
             transparent,
             composite,
             screen,
+            multi_draw,
         },
         solid_u,
         transparent_u,
     ))
 }
 
+pub struct MainVao {
+    pub vao: VertexArray,
+    // one buffer per stream of the vertex layout
+    #[allow(unused)]
+    pub streams: Vec<Buffer<u8>>,
+    // model matrix and tint of every instance, refilled by every pass
+    pub instances: Buffer<f32>,
+    pub elements: Buffer<u8>,
+}
+
+/// Floats per instance in `MainVao::instances`
+pub const INSTANCE_FLOATS: usize = 24;
+// model matrix columns take 4 locations, tint and decode scale are next
+const INSTANCE_LOCATION: u32 = 4;
 pub unsafe fn init_main_vao(
-    gl: &glow::Context,
-    vao: glow::VertexArray,
-    vertices_buf: glow::Buffer,
-    uvs_buf: glow::Buffer,
-    normals_buf: glow::Buffer,
-    elements_buf: glow::Buffer,
-    data: &BakedMeshData,
-) {
-    gl.bind_vertex_array(Some(vao));
-
-    gl.bind_buffer(glow::ELEMENT_ARRAY_BUFFER, Some(elements_buf));
-    gl.buffer_data_u8_slice(
-        glow::ELEMENT_ARRAY_BUFFER,
-        memcast::as_bytes(&data.indices),
-        glow::STATIC_DRAW,
-    );
+    gl: &GlRef,
+    encoded: &EncodedVertices,
+    indices: &EncodedIndices,
+) -> Result<MainVao, String> {
+    let vao = VertexArray::new(gl)?;
+    vao.bind();
 
-    gl.bind_buffer(glow::ARRAY_BUFFER, Some(vertices_buf));
-    gl.buffer_data_u8_slice(
-        glow::ARRAY_BUFFER,
-        memcast::as_bytes(&data.vertices),
+    let elements = Buffer::with_data(
+        gl,
+        glow::ELEMENT_ARRAY_BUFFER,
+        &indices.data[..],
         glow::STATIC_DRAW,
-    );
-    gl.enable_vertex_attrib_array(0);
-    gl.vertex_attrib_pointer_f32(0, 3, glow::FLOAT, false, 0, 0);
+    )?;
 
-    gl.bind_buffer(glow::ARRAY_BUFFER, Some(uvs_buf));
-    gl.buffer_data_u8_slice(
-        glow::ARRAY_BUFFER,
-        memcast::as_bytes(&data.uvs),
-        glow::STATIC_DRAW,
-    );
-    gl.enable_vertex_attrib_array(1);
-    gl.vertex_attrib_pointer_f32(1, 2, glow::FLOAT, false, 0, 0);
+    let streams = encoded
+        .streams
+        .iter()
+        .map(|x| Buffer::with_data(gl, glow::ARRAY_BUFFER, &x[..], glow::STATIC_DRAW))
+        .collect::<Result<Vec<_>, _>>()?;
+    for desc in &encoded.layout.attributes {
+        let location = desc.attribute as u32;
+        let (typ, size, normalized) = desc.format.gl_type(desc.attribute.components());
+        streams[desc.stream].bind();
+        gl.enable_vertex_attrib_array(location);
+        gl.vertex_attrib_pointer_f32(
+            location,
+            size,
+            typ,
+            normalized,
+            encoded.layout.streams[desc.stream] as i32,
+            desc.offset as i32,
+        );
+    }
 
-    gl.bind_buffer(glow::ARRAY_BUFFER, Some(normals_buf));
-    gl.buffer_data_u8_slice(
-        glow::ARRAY_BUFFER,
-        memcast::as_bytes(&data.normals),
-        glow::STATIC_DRAW,
-    );
-    gl.enable_vertex_attrib_array(2);
-    gl.vertex_attrib_pointer_f32(2, 3, glow::FLOAT, false, 0, 0);
+    const F32S: i32 = std::mem::size_of::<f32>() as i32;
+    let instances = Buffer::new(gl, glow::ARRAY_BUFFER)?;
+    instances.bind();
+    for k in 0..6 {
+        let location = INSTANCE_LOCATION + k;
+        gl.enable_vertex_attrib_array(location);
+        gl.vertex_attrib_pointer_f32(
+            location,
+            4,
+            glow::FLOAT,
+            false,
+            INSTANCE_FLOATS as i32 * F32S,
+            4 * k as i32 * F32S,
+        );
+        gl.vertex_attrib_divisor(location, 1);
+    }
 
+    gl.bind_vertex_array(None);
     gl.bind_buffer(glow::ELEMENT_ARRAY_BUFFER, None);
     gl.bind_buffer(glow::ARRAY_BUFFER, None);
-    gl.bind_vertex_array(None);
+    Ok(MainVao {
+        vao,
+        streams,
+        instances,
+        elements,
+    })
 }
 
-pub unsafe fn init_screen_vao(gl: &glow::Context, vao: glow::VertexArray, buf: glow::Buffer) {
+pub struct ScreenVao {
+    pub vao: VertexArray,
+    #[allow(unused)]
+    pub buf: Buffer<f32>,
+}
+pub unsafe fn init_screen_vao(gl: &GlRef) -> Result<ScreenVao, String> {
     const F32S: i32 = std::mem::size_of::<f32>() as i32;
     const SCREEN_QUAD_DATA: &[f32] = &[
         // x, y, z, u, v
@@ -233,16 +346,13 @@ pub unsafe fn init_screen_vao(gl: &glow::Context, vao: glow::VertexArray, buf: g
         -1.0, 1.0, 0.0, 0.0, 1.0, //
         -1.0, -1.0, 0.0, 0.0, 0.0, //
     ];
-    gl.bind_vertex_array(Some(vao));
-    gl.bind_buffer(glow::ARRAY_BUFFER, Some(buf));
-    gl.buffer_data_u8_slice(
-        glow::ARRAY_BUFFER,
-        memcast::as_bytes(SCREEN_QUAD_DATA),
-        glow::STATIC_DRAW,
-    );
+    let vao = VertexArray::new(gl)?;
+    vao.bind();
+    let buf = Buffer::with_data(gl, glow::ARRAY_BUFFER, SCREEN_QUAD_DATA, glow::STATIC_DRAW)?;
     gl.enable_vertex_attrib_array(0);
     gl.vertex_attrib_pointer_f32(0, 3, glow::FLOAT, false, 5 * F32S, 0);
     gl.enable_vertex_attrib_array(1);
     gl.vertex_attrib_pointer_f32(1, 2, glow::FLOAT, false, 5 * F32S, 3 * F32S);
     gl.bind_vertex_array(None);
+    Ok(ScreenVao { vao, buf })
 }
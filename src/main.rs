@@ -1,118 +1,129 @@
-mod atlas;
+mod gl_caps;
+mod gl_objects;
 mod gl_utils;
-mod glmc;
+mod gpu_cull;
+mod hiz;
 mod loader;
-mod memcast;
+mod multi_draw;
+mod render_graph;
+mod render_target;
+use crate::gl_caps::GlCaps;
+use crate::gl_objects::*;
 use crate::gl_utils::*;
-use crate::glmc::*;
+use crate::gpu_cull::GpuCulling;
+use crate::hiz::DepthPyramid;
 use crate::loader::*;
+use crate::multi_draw::{DrawElementsIndirectCommand, GpuMaterial, MultiDraw};
+use crate::render_graph::*;
+use crate::render_target::*;
+use game::assets::*;
+use game::bounds::Bounds;
+use game::frustum::Frustum;
+use game::glmc::*;
+use game::index_buffer::{DrawRange, EncodedIndices, IndexType};
+use game::scene::Scene;
+use game::scene_graph::{Attachment, SceneGraph};
+use game::vertex_layout::EncodedVertices;
+use game::{atlas, memcast};
 use glow::HasContext;
-use tobj::load_obj;
 
 fn main() {
-    use glm::vec3;
     use std::path::Path;
-    let objs_to_load = [
-        Path::new("./data/objects/dice.obj"),
-        Path::new("./data/objects/box.obj"),
-        Path::new("./data/objects/red_crystal.obj"),
-        Path::new("./data/objects/green_crystal.obj"),
-        Path::new("./data/objects/blue_crystal.obj"),
-    ];
-    let LoadedModels {
-        mut models,
+    let scene_path = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| "./data/scenes/default.toml".to_string());
+    let scene = match Scene::load(Path::new(&scene_path)) {
+        Ok(scene) => scene,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        },
+    };
+    let (width, height): (u32, u32) = (800, 600);
+    let window = init_window(width, height).unwrap();
+    let caps = unsafe { GlCaps::query(&window.gl) };
+    caps.log();
+    let BakedAssets {
+        models,
+        registry,
         materials,
-    } = prepare_objs(&objs_to_load).unwrap();
-    let materials = prepare_materials(materials);
-    let tx_mats = materials
-        .iter()
-        .enumerate()
-        .filter(|x| !x.1.is_transparent && x.1.diffuse_texture.is_some())
-        .collect::<Vec<_>>();
-
-    let (atlas, _skipped) = atlas::textures_to_atlas(
-        &tx_mats
-            .iter()
-            .map(|x| x.1.diffuse_texture.clone().unwrap())
-            .collect::<Vec<_>>(),
-        128,
-        false,
-        3,
-    );
-    std::fs::create_dir_all("./cache").unwrap();
-    // let the_atlas = atlas::materials_to_atlas(&tx_mats, &atlas::AtlasOptions {min_size: 128, ..Default::default()});
-
-    image::RgbImage::from_raw(128, 128, atlas.texture.clone())
-        .unwrap()
-        .save("./cache/atlas0.png")
-        .unwrap();
-    let tx_mats_map = tx_mats
-        .iter()
-        .map(|x| x.0)
-        .enumerate()
-        .map(|(a, b)| (b, a))
-        .collect::<_>();
-    let ttx_mats = materials
-        .iter()
-        .enumerate()
-        .filter(|x| x.1.is_transparent && x.1.diffuse_texture.is_some())
-        .collect::<Vec<_>>();
-    let (transparent_atlas, _skipped1) = atlas::textures_to_atlas(
-        &ttx_mats
-            .iter()
-            .map(|x| x.1.diffuse_texture.clone().unwrap())
-            .collect::<Vec<_>>(),
-        128,
+        atlas,
+        transparent_atlas,
+        baked,
+        vertices,
+        indices,
+        from_cache,
+    } = bake_assets(
+        &scene.asset_paths(),
+        &BakeOptions {
+            max_texture_size: caps.max_texture_size as usize,
+            ..Default::default()
+        },
+        Path::new("./cache"),
         true,
-        3,
-    );
-    // let the_tatlas = atlas::materials_to_atlas(&ttx_mats, &atlas::AtlasOptions {min_size: 128, transparent: true, ..Default::default()});
-    image::RgbaImage::from_raw(128, 128, transparent_atlas.texture.clone())
+    )
+    .unwrap();
+    if from_cache {
+        println!("Loaded baked meshes from ./cache");
+    }
+    // instance tint
+    const WHITE: [f32; 4] = [1., 1., 1., 1.];
+    let mut scene_graph = scene.build_graph(&registry).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    });
+    // node transforms from imported scenes, models are in baked order here
+    for (i, model) in models.iter().enumerate() {
+        for &mtx in &model.instances {
+            let node = scene_graph.add_matrix(None, mtx);
+            scene_graph.attach(
+                node,
+                Attachment::Mesh {
+                    model: i,
+                    tint: WHITE,
+                },
+            );
+        }
+    }
+    scene_graph.update();
+    unsafe {
+        main0(
+            window,
+            &caps,
+            baked,
+            &vertices,
+            &indices,
+            &materials,
+            scene_graph,
+            &scene,
+            &atlas.atlas,
+            &transparent_atlas.atlas,
+        )
         .unwrap()
-        .save("./cache/atlas1.png")
-        .unwrap();
-    let ttx_mats_map = ttx_mats
-        .iter()
-        .map(|x| x.0)
-        .enumerate()
-        .map(|(a, b)| (b, a))
-        .collect::<_>();
-    let baked = bake_meshes(
-        &mut models,
-        &materials,
-        &atlas,
-        &transparent_atlas,
-        &tx_mats_map,
-        &ttx_mats_map,
-    );
-    let z = vec3(0., 0., 0.);
-    let o = vec3(1., 1., 1.);
-    let objects = [
-        (0, Transform::new(vec3(0., 0., 0.), z, o)),
-        (1, Transform::new(vec3(3., 0., 0.), z, o)),
-        (2, Transform::new(vec3(6., 0., 0.), z, o)),
-        (3, Transform::new(vec3(6., 0., 3.), z, o)),
-        (4, Transform::new(vec3(6., 0., 6.), z, o)),
-    ];
-    unsafe { main0(baked, &materials, &objects, &atlas, &transparent_atlas).unwrap() }
+    }
 }
 
+#[allow(clippy::too_many_arguments)]
 unsafe fn main0(
+    window: InitializedWindow,
+    caps: &GlCaps,
     models: BakedMeshData,
+    vertices: &EncodedVertices,
+    indices: &EncodedIndices,
     materials: &[Material],
-    objects: &[(usize, Transform)],
+    scene_graph: SceneGraph,
+    scene: &Scene,
     atlas: &atlas::Atlas,
     transparent_atlas: &atlas::Atlas,
 ) -> Result<(), String> {
     let start = std::time::SystemTime::now();
-    let (mut width, mut height): (u32, u32) = (800, 600);
     let InitializedWindow {
-        mut gl,
+        gl,
         sdl,
         window,
         mut event_loop,
-        gl_context: _gl_context, /* NOTE: should not drop */
-    } = init_window(width, height)?;
+    } = window;
+    let (mut width, mut height) = window.size();
 
     let mouse = sdl.mouse();
     mouse.set_relative_mouse_mode(true);
@@ -144,158 +155,64 @@ unsafe fn main0(
         render_txt!(shaded, fg, bg);
     }
 
-    gl.enable(glow::DEBUG_OUTPUT);
-    gl.debug_message_callback(debug_message_callback);
-
     let mut aspect_ratio = width as f32 / height as f32;
     let fov = glm::radians(45.);
 
-    let (shaders, solid_u, transparent_u) = init_shaders(&gl).unwrap();
-    let main_vao = gl.create_vertex_array()?;
-    let main_vertices = gl.create_buffer()?;
-    let main_uvs = gl.create_buffer()?;
-    let main_normals = gl.create_buffer()?;
-    let main_elements = gl.create_buffer()?;
-    init_main_vao(
-        &gl,
-        main_vao,
-        main_vertices,
-        main_uvs,
-        main_normals,
-        main_elements,
-        &models,
+    let multi_draw = MultiDraw::new(&gl, caps, &sdl.video()?)?;
+    println!(
+        "Multi draw indirect: {}",
+        if multi_draw.is_some() { "on" } else { "off" }
     );
-
-    let screen_vao = gl.create_vertex_array()?;
-    let screen_vbo = gl.create_buffer()?;
-    init_screen_vao(&gl, screen_vao, screen_vbo);
-    // set up framebuffers and their texture attachments
-    let opaque_fbo = gl.create_framebuffer()?;
-    let transparent_fbo = gl.create_framebuffer()?;
-    // attachments opaque
-    let opaque_tx = gl.create_texture()?;
-    let opaque_params = TextureParams {
-        internal_format: glow::RGBA16F,
-        format: glow::RGBA,
-        data_type: glow::HALF_FLOAT,
-        min_filter: Some(glow::LINEAR),
-        mag_filter: Some(glow::LINEAR),
-    };
-    reset_texture(&gl, opaque_tx, &opaque_params, width, height);
-    let depth_tx = gl.create_texture()?;
-    let depth_params = TextureParams {
-        internal_format: glow::DEPTH_COMPONENT,
-        format: glow::DEPTH_COMPONENT,
-        data_type: glow::FLOAT,
-        min_filter: None,
-        mag_filter: None,
+    let (shaders, solid_u, transparent_u) = init_shaders(&gl, multi_draw.is_some()).unwrap();
+    let main_vao = init_main_vao(&gl, vertices, indices)?;
+    let screen_vao = init_screen_vao(&gl)?;
+    let atlas_params = TextureParams {
+        internal_format: glow::RGB,
+        format: glow::RGB,
+        data_type: glow::UNSIGNED_BYTE,
+        min_filter: Some(glow::NEAREST),
+        mag_filter: Some(glow::NEAREST),
     };
-    reset_texture(&gl, depth_tx, &depth_params, width, height);
-    let opaque_fbo_tx = &[
-        (glow::COLOR_ATTACHMENT0, opaque_tx),
-        (glow::DEPTH_ATTACHMENT, depth_tx),
-    ];
-    let opaque_fbo_db = None;
-    rebind_framebuffer(&gl, opaque_fbo, opaque_fbo_tx, opaque_fbo_db)?;
-    // attachments transparent
-    let accum_tx = gl.create_texture()?;
-    let accum_params = TextureParams {
-        internal_format: glow::RGBA16F,
+    let main_atlas_tx = Texture2D::new(&gl)?;
+    main_atlas_tx.upload(
+        &atlas_params,
+        atlas.size as u32,
+        atlas.size as u32,
+        &atlas.texture,
+    );
+    let tatlas_params = TextureParams {
+        internal_format: glow::RGBA,
         format: glow::RGBA,
-        data_type: glow::HALF_FLOAT,
-        min_filter: Some(glow::LINEAR),
-        mag_filter: Some(glow::LINEAR),
-    };
-    reset_texture(&gl, accum_tx, &accum_params, width, height);
-    let reveal_tx = gl.create_texture()?;
-    let reveral_params = TextureParams {
-        internal_format: glow::R8,
-        format: glow::RED,
-        data_type: glow::FLOAT,
-        min_filter: Some(glow::LINEAR),
-        mag_filter: Some(glow::LINEAR),
+        ..atlas_params
     };
-    reset_texture(&gl, reveal_tx, &reveral_params, width, height);
-    let transparent_fbo_tx: &[(GLTextureAttachment, glow::Texture)] = &[
-        (glow::COLOR_ATTACHMENT0, accum_tx),
-        (glow::COLOR_ATTACHMENT1, reveal_tx),
-        (glow::DEPTH_ATTACHMENT, depth_tx),
-    ];
-    let transparent_fbo_db =
-        Some::<&[GLDrawBuffer]>(&[glow::COLOR_ATTACHMENT0, glow::COLOR_ATTACHMENT1]);
-    rebind_framebuffer(&gl, transparent_fbo, transparent_fbo_tx, transparent_fbo_db)?;
-    // transform matrices
-    use std::collections::hash_map::Entry;
-    use std::collections::HashMap;
-    let mut model_transforms: HashMap<usize, Vec<_>> = HashMap::new();
-    for (i, transform) in objects {
-        match model_transforms.entry(*i) {
-            Entry::Occupied(mut e) => e.get_mut().push(model_mat_from(*transform)),
-            Entry::Vacant(e) => {
-                e.insert(vec![model_mat_from(*transform)]);
-            },
-        }
-    }
-    let main_atlas_tx = gl.create_texture()?;
-    gl.bind_texture(glow::TEXTURE_2D, Some(main_atlas_tx));
-    // TODO hardcode
-    gl.tex_image_2d(
-        glow::TEXTURE_2D,
-        0,
-        glow::RGB as i32,
-        128,
-        128,
-        0,
-        glow::RGB,
-        glow::UNSIGNED_BYTE,
-        Some(atlas.texture.as_slice()),
-    );
-    gl.tex_parameter_i32(
-        glow::TEXTURE_2D,
-        glow::TEXTURE_MIN_FILTER,
-        glow::NEAREST as i32,
-    );
-    gl.tex_parameter_i32(
-        glow::TEXTURE_2D,
-        glow::TEXTURE_MAG_FILTER,
-        glow::NEAREST as i32,
-    );
-    // gl.bind_texture(glow::TEXTURE_2D, None);
-    let main_tatlas_tx = gl.create_texture()?;
-    gl.bind_texture(glow::TEXTURE_2D, Some(main_tatlas_tx));
-    gl.tex_image_2d(
-        glow::TEXTURE_2D,
-        0,
-        glow::RGBA as i32,
-        128,
-        128,
-        0,
-        glow::RGBA,
-        glow::UNSIGNED_BYTE,
-        Some(transparent_atlas.texture.as_slice()),
+    let main_tatlas_tx = Texture2D::new(&gl)?;
+    main_tatlas_tx.upload(
+        &tatlas_params,
+        transparent_atlas.size as u32,
+        transparent_atlas.size as u32,
+        &transparent_atlas.texture,
     );
-    gl.tex_parameter_i32(
-        glow::TEXTURE_2D,
-        glow::TEXTURE_MIN_FILTER,
-        glow::NEAREST as i32,
-    );
-    gl.tex_parameter_i32(
-        glow::TEXTURE_2D,
-        glow::TEXTURE_MAG_FILTER,
-        glow::NEAREST as i32,
-    );
-    gl.bind_texture(glow::TEXTURE_2D, None);
     //
-    let clear_colors = [[0.1, 0.2, 0.3], [0., 0., 0.]];
+    let clear_colors = [scene.clear_color, [0., 0., 0.]];
 
+    let (camera_position, camera_rotation) = match scene_graph.cameras().next() {
+        Some((node, rotation)) => (scene_graph.world_position(node), rotation),
+        None => (glm::vec3(0., 0., 0.), glm::vec2(0., 0.)),
+    };
+    let (light_node, light_power) = match scene_graph.lights().next() {
+        Some((node, power)) => (Some(node), power),
+        None => (None, 50.),
+    };
+    // world transforms of instances, updated at the start of every frame
+    let scene_graph = std::cell::RefCell::new(scene_graph);
     let mut state = GameState {
         gl: &gl,
         window,
         mouse,
-        position: glm::vec3(5.2, 3.3, 0.),
-        rotation: glm::vec2(-1.57, -1.),
+        position: camera_position,
+        rotation: camera_rotation,
         wasd: glm::ivec3(0, 0, 0),
-        light_power: 50.0,
+        light_power,
         light_intensity: glm::ivec3(1, 1, 1),
         cc_type: 0,
         cc_types: clear_colors.len() as u32,
@@ -305,13 +222,16 @@ unsafe fn main0(
         fast: false,
         culling: true,
         draw_calls: 0,
+        cull_stats: CullStats::default(),
+        gpu_culling: false,
+        occlusion: false,
+        show_occluded: false,
         draw_depth: false,
     };
     let mut prev_time = 0.;
     let mut current_time;
     let mut delta_time;
     let mut draw_calls: u32;
-    let _light_position = glm::vec3(4., 3., 3.);
 
     let default_material = Material {
         name: "<DEFAULT_MATERIAL>".to_string(),
@@ -331,122 +251,318 @@ unsafe fn main0(
         shininess_texture: None,
     };
 
-    'render: loop {
-        #[allow(unused_assignments, unused)]
-        {
-            current_time = start.elapsed().unwrap().as_secs_f32();
-            delta_time = current_time - prev_time;
-            prev_time = current_time;
-            // let fps = (1. / delta_time) as u32;
-            // let ms = (delta_time * 1000.).floor() as u32;
-            // println!("{:?} FPS | {:?}ms", fps, ms);
+    // culled on the GPU, uploaded again whenever nodes move
+    let opaque_instances = |scene_graph: &SceneGraph| {
+        scene_graph
+            .meshes()
+            .filter(|(i, _, _)| models.opaque.contains(i))
+            .map(|(i, mtx, tint)| (i, *mtx, *tint))
+            .collect::<Vec<_>>()
+    };
+    let gpu_culling = GpuCulling::new(
+        &gl,
+        caps,
+        multi_draw.as_ref(),
+        &models,
+        vertices,
+        indices,
+        &opaque_instances(&scene_graph.borrow()),
+        |i| {
+            GpuMaterial::new(
+                models.material_ids[i]
+                    .map(|mid| &materials[mid])
+                    .unwrap_or(&default_material),
+            )
+        },
+    )?;
+    println!(
+        "GPU culling: {}",
+        if gpu_culling.is_some() {
+            "available"
+        } else {
+            "unavailable"
         }
+    );
 
-        let (z_near, z_far) = (0.1, 100.0);
-        let ComputedMatrices {
-            view: view_mat,
-            projection: proj_mat,
-            right,
-            front,
-        } = compute_matrices(
-            state.position,
-            state.rotation,
-            fov,
-            aspect_ratio,
-            z_near,
-            z_far,
-        );
-        let vp_mat = proj_mat * view_mat;
-        draw_calls = 0;
+    // occlusion culling tests against depth of the previous frame
+    let depth_pyramid = match &gpu_culling {
+        Some(_) => Some(DepthPyramid::new(&gl, width, height)?),
+        None => None,
+    };
+    let mut prev_vp_mat = MAT4_ONE;
+    // the previous frame's depth is unusable right after a resize
+    let mut frames_since_resize = 0;
 
-        // render
-        // solid
-        {
-            if state.culling {
-                gl.enable(glow::CULL_FACE);
+    // render passes and their targets
+    let mut graph = RenderGraph::<FrameData>::new();
+    graph.target("opaque", |_| {
+        RenderTarget::builder()
+            .color(TextureParams {
+                internal_format: glow::RGBA16F,
+                format: glow::RGBA,
+                data_type: glow::HALF_FLOAT,
+                min_filter: Some(glow::LINEAR),
+                mag_filter: Some(glow::LINEAR),
+            })
+            // read with texelFetch by the depth pyramid
+            .depth(TextureParams {
+                internal_format: glow::DEPTH_COMPONENT,
+                format: glow::DEPTH_COMPONENT,
+                data_type: glow::FLOAT,
+                min_filter: Some(glow::NEAREST),
+                mag_filter: Some(glow::NEAREST),
+            })
+    });
+    graph.transient_target("transparent", |targets| {
+        RenderTarget::builder()
+            // accum
+            .color(TextureParams {
+                internal_format: glow::RGBA16F,
+                format: glow::RGBA,
+                data_type: glow::HALF_FLOAT,
+                min_filter: Some(glow::LINEAR),
+                mag_filter: Some(glow::LINEAR),
+            })
+            // reveal
+            .color(TextureParams {
+                internal_format: glow::R8,
+                format: glow::RED,
+                data_type: glow::FLOAT,
+                min_filter: Some(glow::LINEAR),
+                mag_filter: Some(glow::LINEAR),
+            })
+            .shared_depth(targets.get("opaque").unwrap().depth().unwrap())
+    });
+    graph
+        .pass("hiz")
+        .history_input("opaque", glow::DEPTH_ATTACHMENT, 4)
+        .side_effects()
+        .execute(|_, frame| {
+            if let Some(pyramid) = depth_pyramid.as_ref().filter(|_| frame.occlusion) {
+                pyramid.build(4);
             }
-            gl.enable(glow::DEPTH_TEST);
-            gl.depth_func(glow::LESS);
-            gl.depth_mask(true);
-            gl.disable(glow::BLEND);
-            let cc = clear_colors[state.cc_type as usize];
+            0
+        });
+    graph
+        .pass("solid")
+        .output("opaque")
+        .state(PipelineState {
+            cull_face: true,
+            ..Default::default()
+        })
+        .execute(|gl, frame| {
+            let cc = frame.clear_color;
             gl.clear_color(cc[0], cc[1], cc[2], 0.);
-            // bind opaque buffer
-            gl.bind_framebuffer(glow::FRAMEBUFFER, Some(opaque_fbo));
             gl.clear(glow::COLOR_BUFFER_BIT | glow::DEPTH_BUFFER_BIT);
 
-            gl.use_program(Some(shaders.solid));
-            gl.uniform_1_f32(solid_u.near.as_ref(), z_near);
-            gl.uniform_1_f32(solid_u.far.as_ref(), z_far);
+            // GPU culling writes draw instances itself
+            let culling = gpu_culling.as_ref().filter(|_| frame.gpu_culling);
+            if let Some(culling) = culling {
+                culling.cull(
+                    &frame.frustum,
+                    frame.camera_position,
+                    frame.lod_scale,
+                    frame.z_near,
+                    depth_pyramid
+                        .as_ref()
+                        .filter(|_| frame.occlusion)
+                        .map(|x| (x, &frame.prev_vp_mat)),
+                    &main_vao.instances,
+                );
+                // visible count arrives a frame or more late, reading it doesn't wait for the GPU
+                let tested = culling.instance_count() as u32;
+                frame.add_cull_stats(tested, tested - culling.last_visible().min(tested));
+            }
+
+            let (program, solid_u) = match (&multi_draw, &shaders.multi_draw) {
+                (Some(_), Some(s)) => (&s.solid, &s.solid_u),
+                _ => (&shaders.solid, &solid_u),
+            };
+            program.bind();
+            gl.uniform_1_f32(solid_u.near.as_ref(), frame.z_near);
+            gl.uniform_1_f32(solid_u.far.as_ref(), frame.z_far);
+            frame.set_light_uniforms(
+                gl,
+                [
+                    &solid_u.camera_position,
+                    &solid_u.light_position,
+                    &solid_u.light_power,
+                    &solid_u.light_intensity,
+                ],
+            );
+
+            let mut batches = Vec::new();
+            if culling.is_none() {
+                let mut instances = Vec::new();
+                batches = frame.collect_instances(
+                    &models,
+                    vertices,
+                    &models.opaque,
+                    &scene_graph.borrow(),
+                    &mut instances,
+                );
+                main_vao.instances.upload(&instances, glow::STREAM_DRAW);
+            }
+            gl.uniform_matrix_4_f32_slice(
+                solid_u.vp.as_ref(),
+                false,
+                &memcast::mat4_as_array(frame.vp_mat),
+            );
+            main_vao.vao.bind();
+            main_vao.elements.bind();
+            let material = |i: usize| {
+                models.material_ids[i]
+                    .map(|mid| &materials[mid])
+                    .unwrap_or(&default_material)
+            };
 
+            if let Some(multi_draw) = &multi_draw {
+                main_atlas_tx.bind(1);
+                gl.uniform_1_i32(solid_u.diffuse_texture.as_ref(), 1);
+                gl.uniform_3_i32(
+                    solid_u.opts.as_ref(),
+                    if frame.draw_depth { 1 } else { 0 },
+                    0,
+                    0,
+                );
+                if let Some(culling) = culling {
+                    return culling.draw(multi_draw, solid_u.draw_offset.as_ref());
+                }
+                return multi_draw_batches(
+                    gl,
+                    multi_draw,
+                    &batches,
+                    &indices.ranges,
+                    |i| GpuMaterial::new(material(i)),
+                    solid_u.draw_offset.as_ref(),
+                );
+            }
+
+            let mut draw_calls = 0;
             let mut prev_mid = None;
-            for i in &models.opaque {
-                let Some(mtxs) = model_transforms.get(i) else { continue };
-                let i = *i;
+            for batch in &batches {
+                let i = batch.model;
                 let mid = models.material_ids[i];
                 if (mid != prev_mid) || (i == 0) {
                     prev_mid = mid;
-                    let mat = mid.map(|mid| &materials[mid]).unwrap_or(&default_material);
+                    let mat = material(i);
                     gl.uniform_3_f32_slice(solid_u.ambient_color.as_ref(), &mat.ambient);
                     gl.uniform_3_f32_slice(solid_u.diffuse_color.as_ref(), &mat.diffuse);
                     gl.uniform_3_f32_slice(solid_u.specular_color.as_ref(), &mat.specular);
+                    gl.uniform_1_f32(solid_u.shininess.as_ref(), mat.shininess);
                 }
-                gl.active_texture(glow::TEXTURE1);
-                gl.bind_texture(glow::TEXTURE_2D, Some(main_atlas_tx));
+                main_atlas_tx.bind(1);
                 gl.uniform_1_i32(solid_u.diffuse_texture.as_ref(), 1);
                 gl.uniform_3_i32(
                     solid_u.opts.as_ref(),
-                    if state.draw_depth { 1 } else { 0 },
+                    if frame.draw_depth { 1 } else { 0 },
                     0,
                     0,
                 );
-                for &mtx in mtxs {
-                    gl.uniform_matrix_4_f32_slice(
-                        solid_u.mvp.as_ref(),
-                        false,
-                        &memcast::mat4_as_array(vp_mat * mtx),
-                    );
-                    gl.bind_vertex_array(Some(main_vao));
-                    gl.bind_buffer(glow::ELEMENT_ARRAY_BUFFER, Some(main_elements));
-                    gl.draw_elements(
-                        glow::TRIANGLES,
-                        models.counts[i] as i32,
-                        glow::UNSIGNED_INT,
-                        models.offsets[i] as i32 * std::mem::size_of::<u32>() as i32,
-                    );
-                    draw_calls += 1;
-                }
+                batch.draw(gl, &indices.ranges[batch.range]);
+                draw_calls += 1;
             }
-        }
-        // transparent
-        {
-            gl.disable(glow::CULL_FACE);
-            gl.depth_mask(false);
-            gl.enable(glow::BLEND);
-            gl.blend_func_draw_buffer(0, glow::ONE, glow::ONE);
-            gl.blend_func_draw_buffer(1, glow::ZERO, glow::ONE_MINUS_SRC_COLOR);
-            gl.blend_equation(glow::FUNC_ADD);
-
-            gl.bind_framebuffer(glow::FRAMEBUFFER, Some(transparent_fbo));
+            draw_calls
+        });
+    graph
+        .pass("bounds")
+        .output("opaque")
+        .state(PipelineState {
+            depth_test: None,
+            depth_write: false,
+            ..Default::default()
+        })
+        .execute(|_, frame| {
+            let culling = gpu_culling.as_ref().filter(|_| frame.gpu_culling);
+            match culling {
+                Some(culling) if frame.occlusion && frame.show_occluded => {
+                    culling.draw_occluded(&frame.vp_mat);
+                    1
+                },
+                _ => 0,
+            }
+        });
+    graph
+        .pass("transparent")
+        .reads("opaque") // shared depth
+        .output("transparent")
+        .state(PipelineState {
+            depth_write: false,
+            blend: Blend::PerBuffer(vec![
+                (glow::ONE, glow::ONE),
+                (glow::ZERO, glow::ONE_MINUS_SRC_COLOR),
+            ]),
+            ..Default::default()
+        })
+        .execute(|gl, frame| {
             gl.clear_buffer_f32_slice(glow::COLOR, 0, &[0., 0., 0., 0.]);
             gl.clear_buffer_f32_slice(glow::COLOR, 1, &[1., 1., 1., 1.]);
 
-            gl.use_program(Some(shaders.transparent));
-            gl.uniform_1_f32(transparent_u.near.as_ref(), z_near);
-            gl.uniform_1_f32(transparent_u.far.as_ref(), z_far);
+            let (program, transparent_u) = match (&multi_draw, &shaders.multi_draw) {
+                (Some(_), Some(s)) => (&s.transparent, &s.transparent_u),
+                _ => (&shaders.transparent, &transparent_u),
+            };
+            program.bind();
+            gl.uniform_1_f32(transparent_u.near.as_ref(), frame.z_near);
+            gl.uniform_1_f32(transparent_u.far.as_ref(), frame.z_far);
+            frame.set_light_uniforms(
+                gl,
+                [
+                    &transparent_u.camera_position,
+                    &transparent_u.light_position,
+                    &transparent_u.light_power,
+                    &transparent_u.light_intensity,
+                ],
+            );
+
+            let mut instances = Vec::new();
+            let batches = frame.collect_instances(
+                &models,
+                vertices,
+                &models.transparent,
+                &scene_graph.borrow(),
+                &mut instances,
+            );
+            main_vao.instances.upload(&instances, glow::STREAM_DRAW);
+            gl.uniform_matrix_4_f32_slice(
+                transparent_u.vp.as_ref(),
+                false,
+                &memcast::mat4_as_array(frame.vp_mat),
+            );
+            main_vao.vao.bind();
+            main_vao.elements.bind();
+            let material = |i: usize| {
+                models.material_ids[i]
+                    .map(|mid| &materials[mid])
+                    .unwrap_or(&default_material)
+            };
 
+            if let Some(multi_draw) = &multi_draw {
+                main_tatlas_tx.bind(1);
+                gl.uniform_1_i32(transparent_u.diffuse_texture.as_ref(), 1);
+                return multi_draw_batches(
+                    gl,
+                    multi_draw,
+                    &batches,
+                    &indices.ranges,
+                    |i| GpuMaterial::new(material(i)),
+                    transparent_u.draw_offset.as_ref(),
+                );
+            }
+
+            let mut draw_calls = 0;
             let mut prev_mid = None;
-            for i in &models.transparent {
-                let Some(mtxs) = model_transforms.get(i) else { continue };
-                let i = *i;
+            for batch in &batches {
+                let i = batch.model;
                 let mid = models.material_ids[i];
                 if (mid != prev_mid) || (i == 0) {
                     prev_mid = mid;
-                    let mat = mid.map(|mid| &materials[mid]).unwrap_or(&default_material);
+                    let mat = material(i);
                     gl.uniform_3_f32_slice(transparent_u.ambient_color.as_ref(), &mat.ambient);
                     gl.uniform_3_f32_slice(transparent_u.diffuse_color.as_ref(), &mat.diffuse);
                     gl.uniform_3_f32_slice(transparent_u.specular_color.as_ref(), &mat.specular);
                     gl.uniform_1_f32(transparent_u.dissolve.as_ref(), mat.dissolve);
+                    gl.uniform_1_f32(transparent_u.shininess.as_ref(), mat.shininess);
                     let o_ambient = mat.ambient_texture.is_some() as i32;
                     let o_diffuse = mat.diffuse_texture.is_some() as i32;
                     let o_specular = mat.specular_texture.is_some() as i32;
@@ -457,70 +573,114 @@ unsafe fn main0(
                         o_specular,
                     );
                 }
-                gl.active_texture(glow::TEXTURE1);
-                gl.bind_texture(glow::TEXTURE_2D, Some(main_tatlas_tx));
+                main_tatlas_tx.bind(1);
                 gl.uniform_1_i32(transparent_u.diffuse_texture.as_ref(), 1);
-
-                for &mtx in mtxs {
-                    gl.uniform_matrix_4_f32_slice(
-                        transparent_u.mvp.as_ref(),
-                        false,
-                        &memcast::mat4_as_array(vp_mat * mtx),
-                    );
-
-                    gl.bind_vertex_array(Some(main_vao));
-                    gl.bind_buffer(glow::ELEMENT_ARRAY_BUFFER, Some(main_elements));
-                    gl.draw_elements(
-                        glow::TRIANGLES,
-                        models.counts[i] as i32,
-                        glow::UNSIGNED_INT,
-                        models.offsets[i] as i32 * std::mem::size_of::<u32>() as i32,
-                    );
-                    draw_calls += 1;
-                }
+                batch.draw(gl, &indices.ranges[batch.range]);
+                draw_calls += 1;
             }
-        }
-
-        // composite
-        {
-            gl.depth_func(glow::ALWAYS);
-            gl.enable(glow::BLEND);
-            gl.blend_func(glow::SRC_ALPHA, glow::ONE_MINUS_SRC_ALPHA);
-
-            gl.bind_framebuffer(glow::FRAMEBUFFER, Some(opaque_fbo));
-
-            gl.use_program(Some(shaders.composite));
+            draw_calls
+        });
+    graph
+        .pass("composite")
+        .input("transparent", glow::COLOR_ATTACHMENT0, 0) // accum
+        .input("transparent", glow::COLOR_ATTACHMENT1, 1) // reveal
+        .output("opaque")
+        .state(PipelineState {
+            depth_test: Some(glow::ALWAYS),
+            depth_write: false,
+            blend: Blend::Func(glow::SRC_ALPHA, glow::ONE_MINUS_SRC_ALPHA),
+            ..Default::default()
+        })
+        .execute(|gl, _| {
+            shaders.composite.bind();
             // draw screen quad
-            gl.active_texture(glow::TEXTURE0);
-            gl.bind_texture(glow::TEXTURE_2D, Some(accum_tx));
-            gl.active_texture(glow::TEXTURE1);
-            gl.bind_texture(glow::TEXTURE_2D, Some(reveal_tx));
-            gl.bind_vertex_array(Some(screen_vao));
+            screen_vao.vao.bind();
             gl.draw_arrays(glow::TRIANGLES, 0, 6);
-            draw_calls += 1;
-        }
-        // backbuffer
-        {
-            gl.disable(glow::DEPTH_TEST);
-            gl.depth_mask(true); // enable depth mask to later clear depth buffer
-            gl.disable(glow::BLEND);
-
-            // unbind framebuffer == now render to screen backbuffer
-            gl.bind_framebuffer(glow::FRAMEBUFFER, None);
+            1
+        });
+    graph
+        .pass("backbuffer")
+        .input("opaque", glow::COLOR_ATTACHMENT0, 0)
+        .state(PipelineState {
+            depth_test: None,
+            depth_write: true, // enable depth mask to later clear depth buffer
+            ..Default::default()
+        })
+        .execute(|gl, _| {
             gl.clear_color(0., 0., 0., 0.);
             gl.clear(glow::COLOR_BUFFER_BIT | glow::DEPTH_BUFFER_BIT | glow::STENCIL_BUFFER_BIT);
 
-            gl.use_program(Some(shaders.screen));
+            shaders.screen.bind();
             // draw final screen quad
-            gl.active_texture(glow::TEXTURE0);
-            gl.bind_texture(glow::TEXTURE_2D, Some(opaque_tx));
-            gl.bind_vertex_array(Some(screen_vao));
+            screen_vao.vao.bind();
             gl.draw_arrays(glow::TRIANGLES, 0, 6);
-            draw_calls += 1;
+            1
+        });
+    graph.compile(&gl, caps, width, height)?;
+
+    'render: loop {
+        #[allow(unused_assignments, unused)]
+        {
+            current_time = start.elapsed().unwrap().as_secs_f32();
+            delta_time = current_time - prev_time;
+            prev_time = current_time;
+            // let fps = (1. / delta_time) as u32;
+            // let ms = (delta_time * 1000.).floor() as u32;
+            // println!("{:?} FPS | {:?}ms", fps, ms);
         }
 
+        let (z_near, z_far) = (0.1, 100.0);
+        let ComputedMatrices {
+            view: view_mat,
+            projection: proj_mat,
+            right,
+            front,
+        } = compute_matrices(
+            state.position,
+            state.rotation,
+            fov,
+            aspect_ratio,
+            z_near,
+            z_far,
+        );
+        let vp_mat = proj_mat * view_mat;
+        let cc = clear_colors[state.cc_type as usize];
+        if scene_graph.borrow_mut().update() > 0 {
+            if let Some(culling) = &gpu_culling {
+                culling.update_instances(&opaque_instances(&scene_graph.borrow()))?;
+            }
+        }
+        let light_position = light_node.map_or(glm::vec3(4., 3., 3.), |node| {
+            scene_graph.borrow().world_position(node)
+        });
+        let gpu_culling_on = state.gpu_culling && gpu_culling.is_some();
+        let frame = FrameData {
+            vp_mat,
+            prev_vp_mat,
+            frustum: Frustum::from_matrix(&vp_mat),
+            cull_stats: Default::default(),
+            gpu_culling: gpu_culling_on,
+            occlusion: gpu_culling_on && state.occlusion && frames_since_resize > 0,
+            show_occluded: state.show_occluded,
+            camera_position: state.position,
+            light_position,
+            light_power: state.light_power,
+            light_intensity: state.light_intensity,
+            lod_scale: height as f32 / (2. * (fov / 2.).tan()),
+            z_near,
+            z_far,
+            clear_color: cc,
+            draw_depth: state.draw_depth,
+        };
+        graph.state_mut("solid").unwrap().cull_face = state.culling;
+        draw_calls = graph.execute(&gl, &frame)?;
+        prev_vp_mat = vp_mat;
+        frames_since_resize += 1;
+
         state.window.gl_swap_window();
+        gl.collect_garbage();
         state.draw_calls = draw_calls;
+        state.cull_stats = frame.cull_stats.get();
 
         let speed_fast: f32 = 5.0;
         let speed_slow: f32 = 2.0;
@@ -535,17 +695,11 @@ unsafe fn main0(
                 width = x as u32;
                 height = y as u32;
                 aspect_ratio = width as f32 / height as f32;
-                gl.viewport(0, 0, width as i32, height as i32);
-                for (tx, params) in [
-                    (opaque_tx, &opaque_params),
-                    (depth_tx, &depth_params),
-                    (accum_tx, &accum_params),
-                    (reveal_tx, &reveral_params),
-                ] {
-                    reset_texture(&gl, tx, params, width, height);
+                graph.resize(width, height)?;
+                if let Some(pyramid) = &depth_pyramid {
+                    pyramid.resize(width, height);
                 }
-                rebind_framebuffer(&gl, opaque_fbo, opaque_fbo_tx, opaque_fbo_db)?;
-                rebind_framebuffer(&gl, transparent_fbo, transparent_fbo_tx, transparent_fbo_db)?;
+                frames_since_resize = 0;
             }
             handle_event(event, &mut state);
             if !state.running {
@@ -560,284 +714,191 @@ unsafe fn main0(
     }
     Ok(())
 }
-#[derive(Debug)]
-pub struct Material {
-    pub name: String,
-    pub ambient: [f32; 3],
-    pub diffuse: [f32; 3],
-    pub specular: [f32; 3],
-    pub shininess: f32,
-    pub dissolve: f32,
-    pub optical_density: f32,
-    pub ambient_texture: Option<image::DynamicImage>,
-    pub diffuse_texture: Option<image::DynamicImage>,
-    pub specular_texture: Option<image::DynamicImage>,
-    pub normal_texture: Option<image::DynamicImage>,
-    pub shininess_texture: Option<image::DynamicImage>,
-    pub dissolve_texture: Option<image::DynamicImage>,
-    pub illumination_model: u8,
-    pub is_transparent: bool,
+/// Instanced draw of one detail level of a model
+struct InstanceBatch {
+    model: usize,
+    // index range of the detail level
+    range: usize,
+    first: u32,
+    count: u32,
 }
 
-fn has_alpha_channel(img: &image::DynamicImage) -> bool {
-    use image::DynamicImage;
-    matches!(
-        img,
-        DynamicImage::ImageLumaA8(_)
-            | DynamicImage::ImageLumaA16(_)
-            | DynamicImage::ImageRgba8(_)
-            | DynamicImage::ImageRgba16(_)
-            | DynamicImage::ImageRgba32F(_)
-    )
+impl InstanceBatch {
+    unsafe fn draw(&self, gl: &glow::Context, r: &DrawRange) {
+        gl.draw_elements_instanced_base_vertex_base_instance(
+            glow::TRIANGLES,
+            r.count as i32,
+            r.index_type.gl_type(),
+            r.offset as i32,
+            self.count as i32,
+            r.base_vertex as i32,
+            self.first,
+        );
+    }
 }
 
-fn load_texture_data(path: Option<&String>) -> Option<image::DynamicImage> {
-    use std::fs::File;
-    use std::io::BufReader;
-    let file = File::open(path?).ok()?;
-    let reader = BufReader::new(file);
-    image::load(reader, image::ImageFormat::Png).ok()
+/// Submit batches with one multi draw per index type, materials are
+/// indexed by draw id. Returns the number of draw calls
+unsafe fn multi_draw_batches(
+    gl: &glow::Context,
+    multi_draw: &MultiDraw,
+    batches: &[InstanceBatch],
+    ranges: &[DrawRange],
+    material: impl Fn(usize) -> GpuMaterial,
+    draw_offset: Option<&glow::UniformLocation>,
+) -> u32 {
+    let mut commands = Vec::with_capacity(batches.len());
+    let mut gpu_materials = Vec::with_capacity(batches.len());
+    // (index type, first command, command count)
+    let mut calls = Vec::new();
+    for index_type in [IndexType::U16, IndexType::U32] {
+        let first = commands.len();
+        for batch in batches {
+            let r = &ranges[batch.range];
+            if r.index_type != index_type {
+                continue;
+            }
+            commands.push(DrawElementsIndirectCommand {
+                count: r.count as u32,
+                instance_count: batch.count,
+                first_index: (r.offset / index_type.size()) as u32,
+                base_vertex: r.base_vertex as i32,
+                base_instance: batch.first,
+            });
+            gpu_materials.push(material(batch.model));
+        }
+        if commands.len() > first {
+            calls.push((index_type, first, commands.len() - first));
+        }
+    }
+    multi_draw.upload(&commands, &gpu_materials);
+    for &(index_type, first, count) in &calls {
+        gl.uniform_1_i32(draw_offset, first as i32);
+        multi_draw.draw(index_type, first, count);
+    }
+    calls.len() as u32
 }
 
-fn prepare_materials(materials: Vec<tobj::Material>) -> Vec<Material> {
-    let mut res = Vec::with_capacity(materials.len());
-    for mat in materials {
-        let ambient_texture = load_texture_data(mat.ambient_texture.as_ref());
-        let diffuse_texture = load_texture_data(mat.diffuse_texture.as_ref());
-        let specular_texture = load_texture_data(mat.specular_texture.as_ref());
-        let normal_texture = load_texture_data(mat.normal_texture.as_ref());
-        let dissolve_texture = load_texture_data(mat.dissolve_texture.as_ref());
-        let shininess_texture = load_texture_data(mat.shininess_texture.as_ref());
-        let ambient = mat.ambient.unwrap_or([1., 1., 1.]);
-        let diffuse = mat.diffuse.unwrap_or([1., 1., 1.]);
-        let specular = mat.specular.unwrap_or([1., 1., 1.]);
-        let shininess = mat.shininess.unwrap_or(200.);
-        let dissolve = mat.dissolve.unwrap_or(1.);
-        let is_transparent = dissolve < 1.
-            || diffuse_texture
-                .as_ref()
-                .map(has_alpha_channel)
-                .unwrap_or(false);
-        let illumination_model = mat.illumination_model.unwrap_or(0);
-        let optical_density = mat.optical_density.unwrap_or(1.);
-        res.push(Material {
-            name: mat.name,
-            ambient,
-            diffuse,
-            specular,
-            shininess,
-            dissolve,
-            is_transparent,
-            ambient_texture,
-            diffuse_texture,
-            specular_texture,
-            dissolve_texture,
-            shininess_texture,
-            normal_texture,
-            illumination_model,
-            optical_density,
-        });
-    }
-    res
+/// Instances tested against the frustum in a frame
+#[derive(Clone, Copy, Debug, Default)]
+struct CullStats {
+    tested: u32,
+    culled: u32,
 }
 
-#[derive(Debug)]
-pub struct BakedMeshData {
-    vertices: Vec<f32>,
-    uvs: Vec<f32>,
-    normals: Vec<f32>,
-    indices: Vec<u32>,
-    offsets: Vec<u32>,
-    counts: Vec<u32>,
-    material_ids: Vec<Option<usize>>,
-    opaque: Vec<usize>,
-    transparent: Vec<usize>,
+struct FrameData {
+    vp_mat: glm::Mat4,
+    // view projection of the previous frame, the depth pyramid was rendered with it
+    prev_vp_mat: glm::Mat4,
+    frustum: Frustum,
+    cull_stats: std::cell::Cell<CullStats>,
+    // cull opaque instances in a compute shader
+    gpu_culling: bool,
+    // also cull instances hidden in the previous frame's depth
+    occlusion: bool,
+    // draw boxes of occluded instances
+    show_occluded: bool,
+    camera_position: glm::Vec3,
+    light_position: glm::Vec3,
+    light_power: f32,
+    // ambient, diffuse and specular terms enabled
+    light_intensity: glm::IVec3,
+    // pixels per world unit at distance 1
+    lod_scale: f32,
+    z_near: f32,
+    z_far: f32,
+    clear_color: [f32; 3],
+    draw_depth: bool,
 }
 
-fn bake_meshes(
-    models: &mut [ModelData],
-    materials: &[Material],
-    atlas: &atlas::Atlas,
-    tatlas: &atlas::Atlas,
-    tx_materials: &std::collections::HashMap<usize, usize>,
-    ttx_materials: &std::collections::HashMap<usize, usize>,
-) -> BakedMeshData {
-    use std::collections::hash_map::Entry;
-    use std::collections::HashMap;
-
-    let mut vertices = Vec::new();
-    let mut uvs = Vec::new();
-    let mut normals = Vec::new();
-    let mut indices = Vec::new();
-    let mut counts = Vec::new();
-    let mut offsets = Vec::new();
-    let mut material_ids = Vec::new();
-    let mut opaque = Vec::new();
-    let mut transparent = Vec::new();
-    let mut idx = 0;
-    let mut offset = 0;
-    let mut prev_mid = None;
-
-    let mut cache = HashMap::new();
-    // TODO: test glMultiDrawElements to specify spans of same-material objects
-    // instead of sorting data
-    models.sort_by_cached_key(|model| {
-        if model.material_id.is_some() {
-            let mid = model.material_id.unwrap();
-            let is_transparent = materials[mid].is_transparent;
-            (true, is_transparent, mid)
-        } else {
-            (false, false, 0)
-        }
-    });
-    for (model_index, model) in models.iter().enumerate() {
-        cache.clear();
-        println!("{}", model.name);
-        {
-            let is_transparent =
-                model.material_id.is_some() && materials[model.material_id.unwrap()].is_transparent;
-            if is_transparent {
-                transparent.push(model_index);
-            } else {
-                opaque.push(model_index);
+impl FrameData {
+    /// Visible instances of `ids` batched by model and detail level.
+    /// Instance data (model matrix, tint and position decode scale) is appended to `data`
+    fn collect_instances(
+        &self,
+        models: &BakedMeshData,
+        vertices: &EncodedVertices,
+        ids: &[usize],
+        scene_graph: &SceneGraph,
+        data: &mut Vec<f32>,
+    ) -> Vec<InstanceBatch> {
+        let mut batches = Vec::new();
+        let mut visible = Vec::new();
+        for &i in ids {
+            visible.clear();
+            for (mtx, tint) in scene_graph.mesh_instances(i) {
+                let bounds = models.world_bounds(i, 0, mtx);
+                if self.is_visible(&bounds) {
+                    visible.push((self.lod_range(models, i, mtx, &bounds), mtx, tint));
+                }
             }
-        }
-        if material_ids.is_empty() || (prev_mid != model.material_id) {
-            prev_mid = model.material_id;
-            material_ids.push(model.material_id);
-        }
-
-        const F32S: usize = std::mem::size_of::<f32>();
-        let m = &model.mesh;
-        let len = m.indices.len();
-
-        let model_uvs0 = model
-            .material_id
-            .map(|mid| {
-                if materials[mid].is_transparent {
-                    (mid, &ttx_materials, &tatlas)
-                } else {
-                    (mid, &tx_materials, &atlas)
+            visible.sort_by_key(|x| x.0);
+            for group in visible.chunk_by(|a, b| a.0 == b.0) {
+                batches.push(InstanceBatch {
+                    model: i,
+                    range: group[0].0,
+                    first: (data.len() / INSTANCE_FLOATS) as u32,
+                    count: group.len() as u32,
+                });
+                for &(_, mtx, tint) in group {
+                    let decode = vertices.position_decode[i];
+                    data.extend(memcast::mat4_as_array(*mtx * decode));
+                    data.extend(tint);
+                    data.extend([decode[0][0], decode[1][1], decode[2][2], 0.]);
                 }
-            })
-            .and_then(|(mid, &a, &b)| {
-                a.get(&mid)
-                    .map(|&midx| atlas::adjust_uvs(&m.uvs, b.map[midx]))
-            });
-        let model_uvs = model_uvs0.as_ref().unwrap_or(&m.uvs);
-        // here xs and b_xs are pointing to the same location
-        // both f32 and bytes because f32 can't stand as hash key
-        // and bytes are not the data needed for result
-        let vs = memcast::slice_cast::<f32, [f32; 3]>(&m.vertices, len);
-        let us = memcast::slice_cast::<f32, [f32; 2]>(model_uvs, len);
-        let ns = memcast::slice_cast::<f32, [f32; 3]>(&m.normals, len);
-        let b_vs = memcast::slice_cast::<f32, [u8; 3 * F32S]>(&m.vertices, len);
-        let b_us = memcast::slice_cast::<f32, [u8; 2 * F32S]>(model_uvs, len);
-        let b_ns = memcast::slice_cast::<f32, [u8; 3 * F32S]>(&m.normals, len);
-
-        for i in &m.indices {
-            let i = *i as usize;
-            let key = (b_vs[i], b_us[i], b_ns[i]);
-            let entry = cache.entry(key);
-            // find vertex index in cache or else add new and update buffers
-            match entry {
-                Entry::Occupied(e) => {
-                    indices.push(*e.get());
-                },
-                Entry::Vacant(e) => {
-                    let (v, u, n) = (vs[i], us[i], ns[i]);
-                    e.insert(idx);
-                    indices.push(idx);
-                    vertices.extend(v);
-                    uvs.extend(u);
-                    normals.extend(n);
-                    idx += 1;
-                },
             }
         }
-        let model_length = m.indices.len() as u32;
-        counts.push(model_length);
-        offsets.push(offset);
-        offset += model_length;
+        batches
     }
 
-    vertices.shrink_to_fit();
-    uvs.shrink_to_fit();
-    normals.shrink_to_fit();
-    indices.shrink_to_fit();
-    counts.shrink_to_fit();
-    offsets.shrink_to_fit();
-    material_ids.shrink_to_fit();
-    opaque.shrink_to_fit();
-    transparent.shrink_to_fit();
-
-    BakedMeshData {
-        vertices,
-        uvs,
-        normals,
-        indices,
-        offsets,
-        counts,
-        material_ids,
-        opaque,
-        transparent,
+    /// Set camera_position, light_position, light_power and light_intensity
+    /// uniforms of the bound program
+    unsafe fn set_light_uniforms(
+        &self,
+        gl: &glow::Context,
+        [camera, position, power, intensity]: [&Option<glow::UniformLocation>; 4],
+    ) {
+        let c = self.camera_position;
+        gl.uniform_3_f32(camera.as_ref(), c.x, c.y, c.z);
+        let p = self.light_position;
+        gl.uniform_3_f32(position.as_ref(), p.x, p.y, p.z);
+        gl.uniform_1_f32(power.as_ref(), self.light_power);
+        let i = self.light_intensity;
+        gl.uniform_3_i32(intensity.as_ref(), i.x, i.y, i.z);
     }
-}
 
-#[derive(Debug)]
-struct MeshData {
-    vertices: Vec<f32>,
-    normals: Vec<f32>,
-    uvs: Vec<f32>,
-    indices: Vec<u32>,
-}
-
-#[derive(Debug)]
-struct ModelData {
-    mesh: MeshData,
-    material_id: Option<usize>,
-    name: String,
-}
+    /// Frustum test of world space bounds, counted in `cull_stats`
+    fn is_visible(&self, bounds: &Bounds) -> bool {
+        let visible = self.frustum.intersects(bounds);
+        self.add_cull_stats(1, !visible as u32);
+        visible
+    }
 
-#[derive(Debug)]
-struct LoadedModels {
-    models: Vec<ModelData>,
-    materials: Vec<tobj::Material>,
-}
+    fn add_cull_stats(&self, tested: u32, culled: u32) {
+        let mut stats = self.cull_stats.get();
+        stats.tested += tested;
+        stats.culled += culled;
+        self.cull_stats.set(stats);
+    }
 
-fn prepare_objs(paths: &[&std::path::Path]) -> Result<LoadedModels, String> {
-    let load_opts = tobj::LoadOptions {
-        single_index: true,
-        triangulate: true,
-        ..Default::default()
-    };
-    let mut loaded_models = Vec::with_capacity(paths.len());
-    let mut loaded_materials = Vec::with_capacity(paths.len());
-    for path in paths {
-        let (models, materials) = load_obj(path, &load_opts).map_err(|e| e.to_string())?;
-        let mut materials = materials.map_err(|e| e.to_string())?;
-        let len = loaded_materials.len();
-        for model in models {
-            let mesh = MeshData {
-                vertices: model.mesh.positions,
-                normals: model.mesh.normals,
-                uvs: model.mesh.texcoords,
-                indices: model.mesh.indices,
-            };
-            let mid = model.mesh.material_id;
-            let res_model = ModelData {
-                mesh,
-                material_id: mid.map(|i| i + len),
-                name: model.name,
-            };
-            loaded_models.push(res_model);
-        }
-        loaded_materials.append(&mut materials);
+    /// Index range of model `i` drawn with `mtx`, at the coarsest
+    /// detail level with error under a pixel. `bounds` are the world bounds of the model
+    fn lod_range(
+        &self,
+        models: &BakedMeshData,
+        i: usize,
+        mtx: &glm::Mat4,
+        bounds: &Bounds,
+    ) -> usize {
+        let scale = (0..3)
+            .map(|c| glm::length(glm::vec3(mtx[c][0], mtx[c][1], mtx[c][2])))
+            .fold(0., f32::max);
+        // distance to the closest point of the bounding sphere
+        let distance =
+            (glm::length(bounds.center - self.camera_position) - bounds.radius).max(self.z_near);
+        let level = models.select_lod(i, self.lod_scale * scale / distance, 1.);
+        models.lod_range(i, level)
     }
-    Ok(LoadedModels {
-        models: loaded_models,
-        materials: loaded_materials,
-    })
 }
 
 struct GameState<'a> {
@@ -858,13 +919,13 @@ struct GameState<'a> {
     running: bool,
     culling: bool,
     draw_calls: u32,
+    cull_stats: CullStats,
+    gpu_culling: bool,
+    occlusion: bool,
+    show_occluded: bool,
     draw_depth: bool,
 }
 
-fn debug_message_callback(_source: u32, _typ: u32, id: u32, _severity: u32, message: &str) {
-    eprintln!("GL error {:0x}: {}", id, message);
-}
-
 fn handle_event(event: sdl2::event::Event, state: &mut GameState) {
     use sdl2::event::Event;
     use sdl2::keyboard::Scancode;
@@ -929,6 +990,18 @@ fn handle_event(event: sdl2::event::Event, state: &mut GameState) {
             println!("Draw depth: {}", state.draw_depth);
             println!("Culling: {}", state.culling);
             println!("Draw calls: {}", state.draw_calls);
+            println!("GPU culling: {}", state.gpu_culling);
+            println!("Occlusion culling: {}", state.occlusion);
+            println!("Show occluded: {}", state.show_occluded);
+            {
+                let CullStats { tested, culled } = state.cull_stats;
+                println!(
+                    "Frustum culling: {} tested, {} culled, {} drawn",
+                    tested,
+                    culled,
+                    tested - culled
+                );
+            }
         },
 
         Event::KeyDown {
@@ -954,6 +1027,9 @@ fn handle_event(event: sdl2::event::Event, state: &mut GameState) {
                 Scancode::LShift => state.wasd.y -= 1,
                 Scancode::Tab => state.fast = true,
                 Scancode::G => state.culling = !state.culling,
+                Scancode::C => state.gpu_culling = !state.gpu_culling,
+                Scancode::H => state.occlusion = !state.occlusion,
+                Scancode::B => state.show_occluded = !state.show_occluded,
                 Scancode::T => intensity!(x),
                 Scancode::Y => intensity!(y),
                 Scancode::U => intensity!(z),
@@ -1,20 +1,17 @@
 #![allow(dead_code)]
 
 pub fn as_bytes<T>(data: &[T]) -> &[u8] {
-    unsafe {
-        std::slice::from_raw_parts(
-            data.as_ptr() as *const u8,
-            data.len() * core::mem::size_of::<T>(),
-        )
-    }
-}
-
-pub fn slice_cast<T, U>(data: &[T], len: usize) -> &[U] {
-    unsafe { std::slice::from_raw_parts(data.as_ptr() as *const U, len) }
+    unsafe { std::slice::from_raw_parts(data.as_ptr() as *const u8, std::mem::size_of_val(data)) }
 }
 
-pub fn slice_cast_mut<T, U>(data: &mut [T], len: usize) -> &mut [U] {
-    unsafe { std::slice::from_raw_parts_mut(data.as_mut_ptr() as *mut U, len) }
+/// View flat data as arrays of `N` elements,
+/// `None` if the length is not a multiple of `N`
+pub fn as_arrays<T, const N: usize>(data: &[T]) -> Option<&[[T; N]]> {
+    if N == 0 || !data.len().is_multiple_of(N) {
+        return None;
+    }
+    // [T; N] has the same alignment as T and the length is checked above
+    Some(unsafe { std::slice::from_raw_parts(data.as_ptr() as *const [T; N], data.len() / N) })
 }
 
 pub fn mat2_as_array(data: glm::Mat2) -> [f32; 4] {
@@ -0,0 +1,672 @@
+//! Versioned little-endian cache of `BakedMeshData`.
+//!
+//! Layout:
+//! - header: magic, version, section count, key, checksum
+//! - section table: id, element size, offset and length in bytes for every section
+//! - section data, each section aligned to `SECTION_ALIGN`
+//!
+//! The checksum covers all section data. The key is provided by the caller
+//! and must change whenever baking parameters do (e.g. atlas layout).
+//! Source files are stored with their size and modification time,
+//! so the cache is stale as soon as any of them changes.
+//! Vertex and index buffers are also stored encoded, so they can be
+//! uploaded straight from the mapping
+use crate::assets::BakedMeshData;
+use crate::bounds::{Bounds, BOUNDS_FLOATS};
+use crate::index_buffer::{DrawRange, EncodedIndices, IndexType};
+use crate::memcast;
+use crate::vertex_layout::{EncodedVertices, VertexLayout};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+const MAGIC: [u8; 8] = *b"GMESHBIN";
+pub const CACHE_VERSION: u32 = 10;
+const SECTION_ALIGN: usize = 16;
+const HEADER_SIZE: usize = 32;
+const SECTION_ENTRY_SIZE: usize = 24;
+const NONE_ID: u32 = u32::MAX;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u32)]
+enum Section {
+    Vertices = 1,
+    Uvs = 2,
+    Normals = 3,
+    Indices = 4,
+    Offsets = 5,
+    Counts = 6,
+    MaterialIds = 7,
+    Opaque = 8,
+    Transparent = 9,
+    Sources = 10,
+    Tangents = 11,
+    LodRanges = 12,
+    LodErrors = 13,
+    VertexOffsets = 14,
+    Bounds = 15,
+    // all streams of `EncodedVertices` one after another
+    VertexStreams = 16,
+    IndexData = 17,
+    // index size, offset, count and base vertex of every `DrawRange`
+    DrawRanges = 18,
+    PositionDecode = 19,
+}
+
+/// Buffer contents, encoded in memory or mapped from a cache file
+pub enum BufferData {
+    Owned(Vec<u8>),
+    Mapped(Arc<memmap2::Mmap>, Range<usize>),
+}
+
+impl std::ops::Deref for BufferData {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            BufferData::Owned(data) => data,
+            BufferData::Mapped(map, range) => &map[range.clone()],
+        }
+    }
+}
+
+pub fn fnv1a(seed: u64, bytes: &[u8]) -> u64 {
+    let mut hash = seed;
+    for b in bytes {
+        hash ^= *b as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+pub const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+
+/// Hash of values that affect baking but are not source files
+pub fn cache_key(parts: &[&[u8]]) -> u64 {
+    parts.iter().fold(FNV_OFFSET, |hash, part| {
+        fnv1a(fnv1a(hash, &(part.len() as u64).to_le_bytes()), part)
+    })
+}
+
+/// Files the baked meshes depend on: models, material libraries and textures
+pub fn model_sources(paths: &[&Path]) -> Vec<PathBuf> {
+    let mut res = Vec::new();
+    for path in paths {
+        res.push(path.to_path_buf());
+        let ext = path
+            .extension()
+            .and_then(|x| x.to_str())
+            .map(|x| x.to_ascii_lowercase());
+        match ext.as_deref() {
+            Some("obj") => res.extend(obj_sources(path)),
+            Some("gltf" | "glb") => res.extend(crate::gltf_import::gltf_sources(path)),
+            _ => {},
+        }
+    }
+    res
+}
+
+fn obj_sources(path: &Path) -> Vec<PathBuf> {
+    let dir = path.parent().unwrap_or(Path::new("."));
+    let Ok(obj) = std::fs::read_to_string(path) else {
+        return Vec::new();
+    };
+    let mut res = Vec::new();
+    for mtl in obj.lines().filter_map(|x| x.trim().strip_prefix("mtllib ")) {
+        let mtl_path = dir.join(mtl.trim());
+        if let Ok(mtl) = std::fs::read_to_string(&mtl_path) {
+            // texture paths are used as written in the .mtl
+            for line in mtl.lines() {
+                let mut words = line.split_whitespace();
+                let Some(statement) = words.next() else {
+                    continue;
+                };
+                let is_texture = statement.starts_with("map_")
+                    || ["bump", "norm", "disp", "refl"].contains(&statement);
+                if let (true, Some(texture)) = (is_texture, words.last()) {
+                    res.push(PathBuf::from(texture));
+                }
+            }
+        }
+        res.push(mtl_path);
+    }
+    res
+}
+
+/// (size, modification time in ns), zeroes for missing files
+fn source_stamp(path: &Path) -> (u64, u64) {
+    let Ok(meta) = std::fs::metadata(path) else {
+        return (0, 0);
+    };
+    let mtime = meta
+        .modified()
+        .ok()
+        .and_then(|x| x.duration_since(std::time::UNIX_EPOCH).ok())
+        .map(|x| x.as_nanos() as u64)
+        .unwrap_or(0);
+    (meta.len(), mtime)
+}
+
+fn encode_sources(sources: &[PathBuf]) -> Vec<u8> {
+    let mut res = Vec::new();
+    res.extend((sources.len() as u32).to_le_bytes());
+    for path in sources {
+        let name = path.to_string_lossy();
+        let (size, mtime) = source_stamp(path);
+        res.extend((name.len() as u32).to_le_bytes());
+        res.extend(name.as_bytes());
+        res.extend(size.to_le_bytes());
+        res.extend(mtime.to_le_bytes());
+    }
+    res
+}
+
+fn sources_changed(data: &[u8]) -> Option<bool> {
+    let mut reader = Reader { data, pos: 0 };
+    let count = reader.u32()?;
+    for _ in 0..count {
+        let len = reader.u32()? as usize;
+        let name = std::str::from_utf8(reader.bytes(len)?).ok()?;
+        let stamp = (reader.u64()?, reader.u64()?);
+        if source_stamp(Path::new(name)) != stamp {
+            return Some(true);
+        }
+    }
+    Some(false)
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        let res = self.data.get(self.pos..self.pos.checked_add(len)?)?;
+        self.pos += len;
+        Some(res)
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.bytes(4)?.try_into().ok()?))
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        Some(u64::from_le_bytes(self.bytes(8)?.try_into().ok()?))
+    }
+}
+
+fn u32s(data: impl IntoIterator<Item = usize>) -> Vec<u8> {
+    data.into_iter()
+        .flat_map(|x| (x as u32).to_le_bytes())
+        .collect()
+}
+
+fn f32s(data: &[f32]) -> Vec<u8> {
+    data.iter().flat_map(|x| x.to_le_bytes()).collect()
+}
+
+pub fn write(
+    path: &Path,
+    data: &BakedMeshData,
+    vertices: &EncodedVertices,
+    indices: &EncodedIndices,
+    sources: &[PathBuf],
+    key: u64,
+) -> Result<(), String> {
+    let sections = [
+        (Section::Vertices, 4, f32s(&data.vertices)),
+        (Section::Uvs, 4, f32s(&data.uvs)),
+        (Section::Normals, 4, f32s(&data.normals)),
+        (Section::Tangents, 4, f32s(&data.tangents)),
+        (
+            Section::Indices,
+            4,
+            u32s(data.indices.iter().map(|&x| x as usize)),
+        ),
+        (
+            Section::Offsets,
+            4,
+            u32s(data.offsets.iter().map(|&x| x as usize)),
+        ),
+        (
+            Section::Counts,
+            4,
+            u32s(data.counts.iter().map(|&x| x as usize)),
+        ),
+        (
+            Section::MaterialIds,
+            4,
+            u32s(
+                data.material_ids
+                    .iter()
+                    .map(|x| x.unwrap_or(NONE_ID as usize)),
+            ),
+        ),
+        (
+            Section::LodRanges,
+            4,
+            u32s(data.lod_ranges.iter().map(|&x| x as usize)),
+        ),
+        (Section::LodErrors, 4, f32s(&data.lod_errors)),
+        (
+            Section::Bounds,
+            4,
+            f32s(
+                &data
+                    .bounds
+                    .iter()
+                    .flat_map(|x| x.to_floats())
+                    .collect::<Vec<_>>(),
+            ),
+        ),
+        (
+            Section::VertexOffsets,
+            4,
+            u32s(data.vertex_offsets.iter().map(|&x| x as usize)),
+        ),
+        (Section::Opaque, 4, u32s(data.opaque.iter().copied())),
+        (
+            Section::Transparent,
+            4,
+            u32s(data.transparent.iter().copied()),
+        ),
+        (
+            Section::VertexStreams,
+            1,
+            vertices
+                .streams
+                .iter()
+                .flat_map(|x| x.iter().copied())
+                .collect(),
+        ),
+        (Section::IndexData, 1, indices.data.to_vec()),
+        (
+            Section::DrawRanges,
+            4,
+            u32s(indices.ranges.iter().flat_map(|x| {
+                [
+                    x.index_type.size(),
+                    x.offset,
+                    x.count,
+                    x.base_vertex as usize,
+                ]
+            })),
+        ),
+        (
+            Section::PositionDecode,
+            4,
+            f32s(
+                &vertices
+                    .position_decode
+                    .iter()
+                    .flat_map(|&x| memcast::mat4_as_array(x))
+                    .collect::<Vec<_>>(),
+            ),
+        ),
+        (Section::Sources, 1, encode_sources(sources)),
+    ];
+    let align = |x: usize| x.div_ceil(SECTION_ALIGN) * SECTION_ALIGN;
+    let mut table = Vec::with_capacity(sections.len() * SECTION_ENTRY_SIZE);
+    let mut body = Vec::new();
+    let mut offset = align(HEADER_SIZE + sections.len() * SECTION_ENTRY_SIZE);
+    let body_start = offset;
+    let mut checksum = FNV_OFFSET;
+    for (id, elem_size, bytes) in &sections {
+        table.extend((*id as u32).to_le_bytes());
+        table.extend((*elem_size as u32).to_le_bytes());
+        table.extend((offset as u64).to_le_bytes());
+        table.extend((bytes.len() as u64).to_le_bytes());
+        body.resize(offset - body_start, 0);
+        body.extend(bytes);
+        checksum = fnv1a(checksum, bytes);
+        offset = align(offset + bytes.len());
+    }
+    let mut file = Vec::with_capacity(body_start + body.len());
+    file.extend(MAGIC);
+    file.extend(CACHE_VERSION.to_le_bytes());
+    file.extend((sections.len() as u32).to_le_bytes());
+    file.extend(key.to_le_bytes());
+    file.extend(checksum.to_le_bytes());
+    file.extend(table);
+    file.resize(body_start, 0);
+    file.extend(body);
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+    }
+    std::fs::write(path, file).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+/// Memory-mapped cache file
+pub struct MappedMeshCache {
+    map: Arc<memmap2::Mmap>,
+    // (id, offset, length)
+    sections: Vec<(u32, usize, usize)>,
+}
+
+impl MappedMeshCache {
+    /// Open and validate cache file. Returns `Ok(None)` when the file is missing,
+    /// has different version or key, is corrupted, or any source has changed
+    pub fn open(path: &Path, key: u64) -> Result<Option<Self>, String> {
+        let Ok(file) = std::fs::File::open(path) else {
+            return Ok(None);
+        };
+        let map = unsafe { memmap2::Mmap::map(&file) }
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+        let mut reader = Reader { data: &map, pos: 0 };
+        macro_rules! check {
+            ($e:expr) => {
+                match $e {
+                    Some(x) => x,
+                    None => return Ok(None),
+                }
+            };
+        }
+        if check!(reader.bytes(MAGIC.len())) != MAGIC || check!(reader.u32()) != CACHE_VERSION {
+            return Ok(None);
+        }
+        let count = check!(reader.u32());
+        if check!(reader.u64()) != key {
+            return Ok(None);
+        }
+        let checksum = check!(reader.u64());
+        let mut sections = Vec::with_capacity(count as usize);
+        let mut actual_checksum = FNV_OFFSET;
+        for _ in 0..count {
+            let id = check!(reader.u32());
+            let _elem_size = check!(reader.u32());
+            let offset = check!(reader.u64()) as usize;
+            let len = check!(reader.u64()) as usize;
+            let end = check!(offset.checked_add(len));
+            let bytes = check!(map.get(offset..end));
+            actual_checksum = fnv1a(actual_checksum, bytes);
+            sections.push((id, offset, len));
+        }
+        if actual_checksum != checksum {
+            eprintln!("{}: checksum mismatch", path.display());
+            return Ok(None);
+        }
+        let cache = Self {
+            map: Arc::new(map),
+            sections,
+        };
+        if check!(sources_changed(cache.bytes(Section::Sources))) {
+            return Ok(None);
+        }
+        Ok(Some(cache))
+    }
+
+    fn range(&self, id: Section) -> Option<Range<usize>> {
+        self.sections
+            .iter()
+            .find(|x| x.0 == id as u32)
+            .map(|&(_, offset, len)| offset..offset + len)
+    }
+
+    fn bytes(&self, id: Section) -> &[u8] {
+        self.range(id).map_or(&[], |x| &self.map[x])
+    }
+
+    /// Zero-copy view of a 4-byte section.
+    /// `None` on big-endian targets
+    fn slice<T: Copy>(&self, id: Section) -> Option<&[T]> {
+        if cfg!(target_endian = "big") || std::mem::size_of::<T>() != 4 {
+            return None;
+        }
+        // sections are aligned, so prefix and suffix are empty
+        let (prefix, data, suffix) = unsafe { self.bytes(id).align_to::<T>() };
+        (prefix.is_empty() && suffix.is_empty()).then_some(data)
+    }
+
+    pub fn vertices(&self) -> Option<&[f32]> {
+        self.slice(Section::Vertices)
+    }
+
+    pub fn uvs(&self) -> Option<&[f32]> {
+        self.slice(Section::Uvs)
+    }
+
+    pub fn normals(&self) -> Option<&[f32]> {
+        self.slice(Section::Normals)
+    }
+
+    pub fn tangents(&self) -> Option<&[f32]> {
+        self.slice(Section::Tangents)
+    }
+
+    pub fn indices(&self) -> Option<&[u32]> {
+        self.slice(Section::Indices)
+    }
+
+    /// Vertex and index buffers encoded with `layout`, borrowing the mapping.
+    /// `None` if the stored buffers don't match the layout
+    pub fn encoded(&self, layout: &VertexLayout) -> Option<(EncodedVertices, EncodedIndices)> {
+        let count = self.bytes(Section::Vertices).len() / 12;
+        let data = self.range(Section::VertexStreams)?;
+        if data.len() != layout.vertex_size() * count {
+            return None;
+        }
+        let mut start = data.start;
+        let mut streams = Vec::with_capacity(layout.streams.len());
+        for stride in &layout.streams {
+            let end = start + stride * count;
+            streams.push(BufferData::Mapped(self.map.clone(), start..end));
+            start = end;
+        }
+        let position_decode = self
+            .slice::<f32>(Section::PositionDecode)?
+            .chunks_exact(16)
+            .map(|x| {
+                let col = |k: usize| glm::vec4(x[4 * k], x[4 * k + 1], x[4 * k + 2], x[4 * k + 3]);
+                glm::Mat4::new(col(0), col(1), col(2), col(3))
+            })
+            .collect();
+        let ranges = self
+            .slice::<u32>(Section::DrawRanges)?
+            .chunks_exact(4)
+            .map(|x| DrawRange {
+                index_type: if x[0] == 2 {
+                    IndexType::U16
+                } else {
+                    IndexType::U32
+                },
+                offset: x[1] as usize,
+                count: x[2] as usize,
+                base_vertex: x[3],
+            })
+            .collect();
+        let vertices = EncodedVertices {
+            layout: layout.clone(),
+            streams,
+            position_decode,
+        };
+        let indices = EncodedIndices {
+            data: BufferData::Mapped(self.map.clone(), self.range(Section::IndexData)?),
+            ranges,
+        };
+        Some((vertices, indices))
+    }
+
+    fn read_u32s(&self, id: Section) -> impl Iterator<Item = u32> + '_ {
+        self.bytes(id)
+            .chunks_exact(4)
+            .map(|x| u32::from_le_bytes([x[0], x[1], x[2], x[3]]))
+    }
+
+    fn read_f32s(&self, id: Section) -> Vec<f32> {
+        self.read_u32s(id).map(f32::from_bits).collect()
+    }
+
+    pub fn to_baked(&self) -> BakedMeshData {
+        let usizes = |id| self.read_u32s(id).map(|x| x as usize).collect();
+        let f32s =
+            |data: Option<&[f32]>, id| data.map_or_else(|| self.read_f32s(id), <[f32]>::to_vec);
+        BakedMeshData {
+            vertices: f32s(self.vertices(), Section::Vertices),
+            uvs: f32s(self.uvs(), Section::Uvs),
+            normals: f32s(self.normals(), Section::Normals),
+            tangents: f32s(self.tangents(), Section::Tangents),
+            indices: self.indices().map_or_else(
+                || self.read_u32s(Section::Indices).collect(),
+                <[u32]>::to_vec,
+            ),
+            offsets: self.read_u32s(Section::Offsets).collect(),
+            counts: self.read_u32s(Section::Counts).collect(),
+            bounds: self
+                .read_f32s(Section::Bounds)
+                .chunks_exact(BOUNDS_FLOATS)
+                .map(Bounds::from_floats)
+                .collect(),
+            material_ids: self
+                .read_u32s(Section::MaterialIds)
+                .map(|x| (x != NONE_ID).then_some(x as usize))
+                .collect(),
+            lod_ranges: self.read_u32s(Section::LodRanges).collect(),
+            lod_errors: self.read_f32s(Section::LodErrors),
+            vertex_offsets: self.read_u32s(Section::VertexOffsets).collect(),
+            opaque: usizes(Section::Opaque),
+            transparent: usizes(Section::Transparent),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index_buffer::encode_indices;
+    use crate::vertex_layout::encode_vertices;
+
+    // quad with one coarser level and a triangle without material
+    fn baked() -> BakedMeshData {
+        let vertices = vec![
+            0., 0., 0., 1., 0., 0., 1., 1., 0., 0., 1., 0., //
+            2., 0., 1., 3., 0., 1., 2., 1., 1.,
+        ];
+        let indices = vec![0, 1, 2, 0, 2, 3, 4, 5, 6, 0, 1, 2];
+        let bounds = [0..6, 6..9, 9..12]
+            .map(|x| Bounds::from_indices(&vertices, &indices[x]))
+            .to_vec();
+        BakedMeshData {
+            uvs: (0..14).map(|x| x as f32 / 16.).collect(),
+            normals: [0., 0., 1.].repeat(7),
+            tangents: [1., 0., 0., -1.].repeat(7),
+            vertices,
+            indices,
+            offsets: vec![0, 6, 9],
+            counts: vec![6, 3, 3],
+            bounds,
+            material_ids: vec![Some(2), None],
+            lod_ranges: vec![0, 1, 1],
+            lod_errors: vec![0.5],
+            vertex_offsets: vec![0, 4, 7],
+            opaque: vec![0],
+            transparent: vec![1],
+        }
+    }
+
+    // cache file and one source in a fresh directory
+    fn write_cache(name: &str, key: u64) -> (PathBuf, PathBuf) {
+        let dir = std::env::temp_dir().join(format!("mesh_cache_{}_{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let source = dir.join("model.obj");
+        std::fs::write(&source, "v 0 0 0\n").unwrap();
+        let path = dir.join("meshes.bin");
+        let data = baked();
+        let layout = VertexLayout::default();
+        let vertices = encode_vertices(&data, &layout);
+        let indices = encode_indices(&data);
+        write(
+            &path,
+            &data,
+            &vertices,
+            &indices,
+            std::slice::from_ref(&source),
+            key,
+        )
+        .unwrap();
+        (path, source)
+    }
+
+    fn patch(path: &Path, f: impl FnOnce(&mut Vec<u8>)) {
+        let mut file = std::fs::read(path).unwrap();
+        f(&mut file);
+        std::fs::write(path, file).unwrap();
+    }
+
+    #[test]
+    fn round_trip() {
+        let (path, _) = write_cache("round_trip", 7);
+        let cache = MappedMeshCache::open(&path, 7).unwrap().unwrap();
+        let (a, b) = (baked(), cache.to_baked());
+        assert_eq!(a.vertices, b.vertices);
+        assert_eq!(a.uvs, b.uvs);
+        assert_eq!(a.normals, b.normals);
+        assert_eq!(a.tangents, b.tangents);
+        assert_eq!(a.indices, b.indices);
+        assert_eq!(a.offsets, b.offsets);
+        assert_eq!(a.counts, b.counts);
+        assert_eq!(a.bounds, b.bounds);
+        assert_eq!(a.material_ids, b.material_ids);
+        assert_eq!(a.lod_ranges, b.lod_ranges);
+        assert_eq!(a.lod_errors, b.lod_errors);
+        assert_eq!(a.vertex_offsets, b.vertex_offsets);
+        assert_eq!(a.opaque, b.opaque);
+        assert_eq!(a.transparent, b.transparent);
+
+        let layout = VertexLayout::default();
+        let (vertices, indices) = cache.encoded(&layout).unwrap();
+        let (expected_vertices, expected_indices) =
+            (encode_vertices(&a, &layout), encode_indices(&a));
+        assert_eq!(vertices.streams.len(), expected_vertices.streams.len());
+        for (x, y) in vertices.streams.iter().zip(&expected_vertices.streams) {
+            assert!(matches!(x, BufferData::Mapped(..)));
+            assert_eq!(x[..], y[..]);
+        }
+        assert_eq!(vertices.position_decode, expected_vertices.position_decode);
+        assert_eq!(indices.data[..], expected_indices.data[..]);
+        assert_eq!(
+            format!("{:?}", indices.ranges),
+            format!("{:?}", expected_indices.ranges)
+        );
+        assert!(cache.encoded(&VertexLayout::full()).is_none());
+    }
+
+    #[test]
+    fn rejects_other_key() {
+        let (path, _) = write_cache("key", 7);
+        assert!(MappedMeshCache::open(&path, 8).unwrap().is_none());
+    }
+
+    #[test]
+    fn rejects_other_version() {
+        let (path, _) = write_cache("version", 7);
+        patch(&path, |x| {
+            x[8..12].copy_from_slice(&(CACHE_VERSION - 1).to_le_bytes())
+        });
+        assert!(MappedMeshCache::open(&path, 7).unwrap().is_none());
+    }
+
+    #[test]
+    fn rejects_bad_checksum() {
+        let (path, _) = write_cache("checksum", 7);
+        patch(&path, |x| *x.last_mut().unwrap() ^= 1);
+        assert!(MappedMeshCache::open(&path, 7).unwrap().is_none());
+    }
+
+    #[test]
+    fn rejects_truncated_file() {
+        let (path, _) = write_cache("truncated", 7);
+        patch(&path, |x| x.truncate(x.len() / 2));
+        assert!(MappedMeshCache::open(&path, 7).unwrap().is_none());
+        patch(&path, |x| x.truncate(HEADER_SIZE / 2));
+        assert!(MappedMeshCache::open(&path, 7).unwrap().is_none());
+    }
+
+    #[test]
+    fn rejects_stale_source() {
+        let (path, source) = write_cache("stale", 7);
+        assert!(MappedMeshCache::open(&path, 7).unwrap().is_some());
+        std::fs::write(&source, "v 0 0 0\nv 1 0 0\n").unwrap();
+        assert!(MappedMeshCache::open(&path, 7).unwrap().is_none());
+    }
+}
@@ -0,0 +1,79 @@
+//! Stable model identifiers. Baking sorts models by material, so indices
+//! into baked data change whenever assets do; ids and names don't
+use crate::assets::ModelData;
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Model in load order: assets in the order given, models in file order.
+/// Adding assets at the end keeps ids of the existing ones
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ModelId(pub u32);
+
+/// Maps ids and names to indices of baked models
+#[derive(Debug, Default)]
+pub struct ModelRegistry {
+    // baked index of every id
+    indices: Vec<usize>,
+    // "file.obj#Name" of every id
+    names: Vec<String>,
+    // "file.obj#Name" and "file.obj", also with the path as given
+    lookup: HashMap<String, Vec<ModelId>>,
+}
+
+impl ModelRegistry {
+    /// `models` in baked order, loaded from `paths`
+    pub fn new(models: &[ModelData], paths: &[&Path]) -> Self {
+        let mut res = Self {
+            indices: vec![0; models.len()],
+            names: vec![String::new(); models.len()],
+            lookup: HashMap::new(),
+        };
+        for (i, model) in models.iter().enumerate() {
+            res.indices[model.id.0 as usize] = i;
+        }
+        // register in id order so lookups list ids in load order
+        for id in 0..models.len() {
+            let model = &models[res.indices[id]];
+            let path = paths[model.source];
+            let file = path
+                .file_name()
+                .map(|x| x.to_string_lossy())
+                .unwrap_or_default();
+            res.names[id] = format!("{}#{}", file, model.name);
+            let mut keys = vec![file.to_string(), res.names[id].clone()];
+            let full = path.display().to_string();
+            if full != file {
+                keys.push(format!("{}#{}", full, model.name));
+                keys.push(full);
+            }
+            for key in keys {
+                res.lookup.entry(key).or_default().push(ModelId(id as u32));
+            }
+        }
+        res
+    }
+
+    pub fn len(&self) -> usize {
+        self.indices.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.indices.is_empty()
+    }
+
+    /// Index into baked data, `None` for unknown ids
+    pub fn index(&self, id: ModelId) -> Option<usize> {
+        self.indices.get(id.0 as usize).copied()
+    }
+
+    /// "file.obj#Name" of `id`
+    pub fn name(&self, id: ModelId) -> Option<&str> {
+        self.names.get(id.0 as usize).map(|x| x.as_str())
+    }
+
+    /// Models named "file.obj#Name", or all models of "file.obj".
+    /// Several models share a name when an object has several materials
+    pub fn find(&self, name: &str) -> &[ModelId] {
+        self.lookup.get(name).map_or(&[], |x| x.as_slice())
+    }
+}
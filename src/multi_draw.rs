@@ -0,0 +1,132 @@
+use crate::gl_caps::GlCaps;
+use crate::gl_objects::{Buffer, GlRef};
+use game::assets::Material;
+use game::index_buffer::IndexType;
+
+/// Layout of `glMultiDrawElementsIndirect` commands
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct DrawElementsIndirectCommand {
+    pub count: u32,
+    pub instance_count: u32,
+    // in indices, not bytes
+    pub first_index: u32,
+    pub base_vertex: i32,
+    pub base_instance: u32,
+}
+
+/// Material of one draw in the materials storage buffer, std430 layout
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct GpuMaterial {
+    pub ambient: [f32; 4],
+    pub diffuse: [f32; 4],
+    pub specular: [f32; 4],
+    // x = dissolve, y = shininess
+    pub params: [f32; 4],
+    // xyz = has ambient, diffuse and specular texture
+    pub opts: [i32; 4],
+}
+
+impl GpuMaterial {
+    pub fn new(mat: &Material) -> Self {
+        let rgb = |c: [f32; 3]| [c[0], c[1], c[2], 1.];
+        Self {
+            ambient: rgb(mat.ambient),
+            diffuse: rgb(mat.diffuse),
+            specular: rgb(mat.specular),
+            params: [mat.dissolve, mat.shininess, 0., 0.],
+            opts: [
+                mat.ambient_texture.is_some() as i32,
+                mat.diffuse_texture.is_some() as i32,
+                mat.specular_texture.is_some() as i32,
+                0,
+            ],
+        }
+    }
+}
+
+// not wrapped by glow
+type MultiDrawElementsIndirectFn = unsafe extern "system" fn(
+    mode: u32,
+    element_type: u32,
+    indirect: *const std::ffi::c_void,
+    draw_count: i32,
+    stride: i32,
+);
+
+/// Storage binding of the materials buffer in shaders
+pub const MATERIALS_BINDING: u32 = 0;
+
+/// Indirect command and material buffers refilled by every pass
+pub struct MultiDraw {
+    func: MultiDrawElementsIndirectFn,
+    commands: Buffer<DrawElementsIndirectCommand>,
+    materials: Buffer<GpuMaterial>,
+}
+
+impl MultiDraw {
+    /// `None` without multi draw indirect, shader draw parameters or storage buffers
+    pub unsafe fn new(
+        gl: &GlRef,
+        caps: &GlCaps,
+        video: &sdl2::VideoSubsystem,
+    ) -> Result<Option<Self>, String> {
+        // gl_DrawIDARB is used by shaders even on 4.6 contexts
+        if !caps.multi_draw_indirect
+            || !caps.has_extension("GL_ARB_shader_draw_parameters")
+            || !caps.shader_storage_buffers
+        {
+            return Ok(None);
+        }
+        let ptr = video.gl_get_proc_address("glMultiDrawElementsIndirect");
+        if ptr.is_null() {
+            return Ok(None);
+        }
+        Ok(Some(Self {
+            func: std::mem::transmute::<*const (), MultiDrawElementsIndirectFn>(ptr),
+            commands: Buffer::new(gl, glow::DRAW_INDIRECT_BUFFER)?,
+            materials: Buffer::new(gl, glow::SHADER_STORAGE_BUFFER)?,
+        }))
+    }
+
+    /// Upload commands with their materials and bind materials for shaders
+    pub unsafe fn upload(
+        &self,
+        commands: &[DrawElementsIndirectCommand],
+        materials: &[GpuMaterial],
+    ) {
+        self.commands.upload(commands, glow::STREAM_DRAW);
+        self.materials.upload(materials, glow::STREAM_DRAW);
+        self.materials
+            .bind_base(glow::SHADER_STORAGE_BUFFER, MATERIALS_BINDING);
+    }
+
+    /// Draw `count` uploaded commands starting at `first`, all of `index_type`.
+    /// `gl_DrawIDARB` starts at 0 in every call
+    pub unsafe fn draw(&self, index_type: IndexType, first: usize, count: usize) {
+        self.draw_from(&self.commands, index_type, first, count);
+    }
+
+    /// Same as `draw` with commands written elsewhere, e.g. by a compute shader
+    pub unsafe fn draw_from(
+        &self,
+        commands: &Buffer<DrawElementsIndirectCommand>,
+        index_type: IndexType,
+        first: usize,
+        count: usize,
+    ) {
+        const STRIDE: usize = std::mem::size_of::<DrawElementsIndirectCommand>();
+        if count == 0 {
+            return;
+        }
+        commands.bind();
+        (self.func)(
+            glow::TRIANGLES,
+            index_type.gl_type(),
+            (first * STRIDE) as *const std::ffi::c_void,
+            count as i32,
+            STRIDE as i32,
+        );
+    }
+}
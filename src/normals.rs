@@ -0,0 +1,163 @@
+use crate::assets::MeshData;
+use glm::Vec3;
+use std::collections::HashMap;
+use std::path::Path;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NormalMode {
+    /// One normal per face
+    Flat,
+    /// Area and angle weighted average of faces sharing a position.
+    /// Faces with normals further apart than the crease angle (degrees)
+    /// are not smoothed together
+    Smooth { crease_angle: Option<f32> },
+}
+
+impl Default for NormalMode {
+    fn default() -> Self {
+        NormalMode::Smooth { crease_angle: None }
+    }
+}
+
+impl NormalMode {
+    /// Bytes identifying the mode, for cache keys
+    pub fn key(&self) -> Vec<u8> {
+        match self {
+            NormalMode::Flat => vec![0],
+            NormalMode::Smooth { crease_angle } => {
+                let mut res = vec![1];
+                res.extend(crease_angle.unwrap_or(-1.).to_le_bytes());
+                res
+            },
+        }
+    }
+}
+
+/// Smoothing group of every face of an .obj file in file order
+/// as (group, triangle count). Group 0 is `s off`
+pub fn obj_smoothing_groups(path: &Path) -> Result<Vec<(u32, usize)>, String> {
+    let obj = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let mut group = 0;
+    let mut res = Vec::new();
+    for line in obj.lines() {
+        let mut words = line.split_whitespace();
+        match words.next() {
+            Some("s") => {
+                group = match words.next() {
+                    Some("off") | None => 0,
+                    Some(x) => x.parse().unwrap_or(0),
+                };
+            },
+            Some("f") => {
+                let corners = words.count();
+                if corners >= 3 {
+                    res.push((group, corners - 2));
+                }
+            },
+            _ => {},
+        }
+    }
+    Ok(res)
+}
+
+fn vec3_at(data: &[f32], i: usize) -> Vec3 {
+    glm::vec3(data[3 * i], data[3 * i + 1], data[3 * i + 2])
+}
+
+fn corner_angle(a: Vec3, b: Vec3, c: Vec3) -> f32 {
+    let (e0, e1) = (b - a, c - a);
+    let len = glm::length(e0) * glm::length(e1);
+    if len <= f32::EPSILON {
+        return 0.;
+    }
+    (glm::dot(e0, e1) / len).clamp(-1., 1.).acos()
+}
+
+/// Replace normals of the mesh with generated ones. Output is unindexed,
+/// every triangle corner gets its own vertex. `groups` holds the smoothing
+/// group of every triangle, `None` smooths all faces together
+pub fn generate_normals(mesh: &mut MeshData, mode: NormalMode, groups: Option<&[u32]>) {
+    let tris = mesh.indices.len() / 3;
+    let pos = |corner: usize| vec3_at(&mesh.vertices, mesh.indices[corner] as usize);
+    // cross product length is twice the area, so it is already area weighted
+    let face_normals = (0..tris)
+        .map(|t| glm::cross(pos(3 * t + 1) - pos(3 * t), pos(3 * t + 2) - pos(3 * t)))
+        .collect::<Vec<_>>();
+    let unit = |n: Vec3| {
+        let len = glm::length(n);
+        if len > f32::EPSILON {
+            n * (1. / len)
+        } else {
+            glm::vec3(0., 1., 0.)
+        }
+    };
+    let group = |t: usize| groups.and_then(|g| g.get(t).copied()).unwrap_or(1);
+
+    let mut normals = Vec::with_capacity(mesh.indices.len() * 3);
+    match mode {
+        NormalMode::Flat => {
+            for n in &face_normals {
+                let n = *unit(*n).as_array();
+                normals.extend([n, n, n].iter().flatten());
+            }
+        },
+        NormalMode::Smooth { crease_angle } => {
+            let min_cos = crease_angle.map(|x| x.to_radians().cos()).unwrap_or(-2.);
+            // weld positions, uv seams split vertices in single index meshes
+            let mut welded = HashMap::new();
+            let corner_ids = mesh
+                .indices
+                .iter()
+                .map(|&i| {
+                    let p = pos_bits(&mesh.vertices, i as usize);
+                    let len = welded.len();
+                    *welded.entry(p).or_insert(len)
+                })
+                .collect::<Vec<_>>();
+            let mut incident = vec![Vec::new(); welded.len()];
+            for (corner, &id) in corner_ids.iter().enumerate() {
+                incident[id].push(corner);
+            }
+            let weighted = (0..mesh.indices.len())
+                .map(|corner| {
+                    let t = corner / 3;
+                    let base = 3 * t;
+                    let (a, b, c) = match corner - base {
+                        0 => (base, base + 1, base + 2),
+                        1 => (base + 1, base + 2, base),
+                        _ => (base + 2, base, base + 1),
+                    };
+                    face_normals[t] * corner_angle(pos(a), pos(b), pos(c))
+                })
+                .collect::<Vec<_>>();
+            for corner in 0..mesh.indices.len() {
+                let t = corner / 3;
+                let n = unit(face_normals[t]);
+                if group(t) == 0 {
+                    normals.extend(*n.as_array());
+                    continue;
+                }
+                let mut sum = glm::vec3(0., 0., 0.);
+                for &other in &incident[corner_ids[corner]] {
+                    let u = other / 3;
+                    if group(u) == group(t) && glm::dot(n, unit(face_normals[u])) >= min_cos {
+                        sum = sum + weighted[other];
+                    }
+                }
+                normals.extend(*unit(sum).as_array());
+            }
+        },
+    }
+
+    mesh.normals.clear();
+    mesh.unindex();
+    mesh.normals = normals;
+}
+
+fn pos_bits(data: &[f32], i: usize) -> [u32; 3] {
+    [
+        data[3 * i].to_bits(),
+        data[3 * i + 1].to_bits(),
+        data[3 * i + 2].to_bits(),
+    ]
+}
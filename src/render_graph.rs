@@ -0,0 +1,500 @@
+#![allow(dead_code)]
+use crate::gl_caps::GlCaps;
+use crate::gl_objects::GlRef;
+use crate::gl_utils::GLTextureAttachment;
+use crate::render_target::{resize_targets, RenderTarget, RenderTargetDesc};
+use glow::HasContext;
+
+pub type GLDepthFunc = u32;
+pub type GLBlendFactor = u32;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Blend {
+    Disabled,
+    Func(GLBlendFactor, GLBlendFactor),
+    /// (src, dst) factors for each draw buffer
+    PerBuffer(Vec<(GLBlendFactor, GLBlendFactor)>),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PipelineState {
+    pub depth_test: Option<GLDepthFunc>,
+    pub depth_write: bool,
+    pub cull_face: bool,
+    pub blend: Blend,
+}
+
+impl Default for PipelineState {
+    fn default() -> Self {
+        Self {
+            depth_test: Some(glow::LESS),
+            depth_write: true,
+            cull_face: false,
+            blend: Blend::Disabled,
+        }
+    }
+}
+
+impl PipelineState {
+    pub unsafe fn apply(&self, gl: &glow::Context) {
+        if let Some(func) = self.depth_test {
+            gl.enable(glow::DEPTH_TEST);
+            gl.depth_func(func);
+        } else {
+            gl.disable(glow::DEPTH_TEST);
+        }
+        gl.depth_mask(self.depth_write);
+        if self.cull_face {
+            gl.enable(glow::CULL_FACE);
+        } else {
+            gl.disable(glow::CULL_FACE);
+        }
+        match &self.blend {
+            Blend::Disabled => gl.disable(glow::BLEND),
+            Blend::Func(src, dst) => {
+                gl.enable(glow::BLEND);
+                gl.blend_func(*src, *dst);
+                gl.blend_equation(glow::FUNC_ADD);
+            },
+            Blend::PerBuffer(funcs) => {
+                gl.enable(glow::BLEND);
+                for (i, (src, dst)) in funcs.iter().enumerate() {
+                    gl.blend_func_draw_buffer(i as u32, *src, *dst);
+                }
+                gl.blend_equation(glow::FUNC_ADD);
+            },
+        }
+    }
+}
+
+struct PassRead {
+    target: &'static str,
+    // (attachment, texture unit) to bind while the pass runs
+    binding: Option<(GLTextureAttachment, u32)>,
+    // read contents left by the previous frame
+    history: bool,
+}
+
+type PassFn<'a, F> = Box<dyn FnMut(&glow::Context, &F) -> u32 + 'a>;
+
+struct Pass<'a, F> {
+    name: &'static str,
+    reads: Vec<PassRead>,
+    output: Option<&'static str>,
+    state: PipelineState,
+    side_effects: bool,
+    enabled: bool,
+    execute: PassFn<'a, F>,
+}
+
+pub struct PassBuilder<'g, 'a, F> {
+    graph: &'g mut RenderGraph<'a, F>,
+    name: &'static str,
+    reads: Vec<PassRead>,
+    output: Option<&'static str>,
+    state: PipelineState,
+    side_effects: bool,
+}
+
+impl<'g, 'a, F> PassBuilder<'g, 'a, F> {
+    /// Bind `attachment` of `target` to texture unit `unit` while the pass runs
+    pub fn input(
+        mut self,
+        target: &'static str,
+        attachment: GLTextureAttachment,
+        unit: u32,
+    ) -> Self {
+        self.reads.push(PassRead {
+            target,
+            binding: Some((attachment, unit)),
+            history: false,
+        });
+        self
+    }
+
+    /// Like `input`, but reads what the previous frame left in `target`,
+    /// so the pass runs before any pass writing to it
+    pub fn history_input(
+        mut self,
+        target: &'static str,
+        attachment: GLTextureAttachment,
+        unit: u32,
+    ) -> Self {
+        self.reads.push(PassRead {
+            target,
+            binding: Some((attachment, unit)),
+            history: true,
+        });
+        self
+    }
+
+    /// Depend on `target` without binding anything,
+    /// e.g. when a shared depth attachment is tested against
+    pub fn reads(mut self, target: &'static str) -> Self {
+        self.reads.push(PassRead {
+            target,
+            binding: None,
+            history: false,
+        });
+        self
+    }
+
+    /// Render into `target`. Passes without output render to the backbuffer
+    pub fn output(mut self, target: &'static str) -> Self {
+        self.output = Some(target);
+        self
+    }
+
+    pub fn state(mut self, state: PipelineState) -> Self {
+        self.state = state;
+        self
+    }
+
+    /// Never cull the pass, e.g. when it writes buffers instead of targets
+    pub fn side_effects(mut self) -> Self {
+        self.side_effects = true;
+        self
+    }
+
+    /// Add the pass to the graph. `execute` returns number of draw calls issued
+    pub fn execute(self, execute: impl FnMut(&glow::Context, &F) -> u32 + 'a) {
+        self.graph.passes.push(Pass {
+            name: self.name,
+            reads: self.reads,
+            output: self.output,
+            state: self.state,
+            side_effects: self.side_effects,
+            enabled: true,
+            execute: Box::new(execute),
+        });
+        self.graph.order = None;
+    }
+}
+
+type TargetFactory<'a> = Box<dyn Fn(&RenderGraphTargets) -> RenderTargetDesc + 'a>;
+
+struct TargetDecl<'a> {
+    name: &'static str,
+    desc: TargetFactory<'a>,
+    transient: bool,
+}
+
+pub struct RenderGraphTargets {
+    targets: Vec<(&'static str, RenderTarget)>,
+}
+
+impl RenderGraphTargets {
+    pub fn get(&self, name: &str) -> Option<&RenderTarget> {
+        self.targets.iter().find(|x| x.0 == name).map(|x| &x.1)
+    }
+}
+
+pub struct RenderGraph<'a, F> {
+    passes: Vec<Pass<'a, F>>,
+    order: Option<Vec<usize>>,
+    target_descs: Vec<TargetDecl<'a>>,
+    targets: RenderGraphTargets,
+    size: (u32, u32),
+}
+
+impl<'a, F> RenderGraph<'a, F> {
+    pub fn new() -> Self {
+        Self {
+            passes: Vec::new(),
+            order: None,
+            target_descs: Vec::new(),
+            targets: RenderGraphTargets {
+                targets: Vec::new(),
+            },
+            size: (0, 0),
+        }
+    }
+
+    /// Declare a render target owned by the graph.
+    /// Targets are created in declaration order, so `desc` can share
+    /// attachments of previously declared targets
+    pub fn target(
+        &mut self,
+        name: &'static str,
+        desc: impl Fn(&RenderGraphTargets) -> RenderTargetDesc + 'a,
+    ) {
+        self.target_descs.push(TargetDecl {
+            name,
+            desc: Box::new(desc),
+            transient: false,
+        });
+    }
+
+    /// Declare a target only used between its first and last pass of a frame.
+    /// Transient targets of the same layout whose passes don't overlap share
+    /// textures, so contents are undefined when the first pass starts.
+    /// Textures are still allocated in `compile`, not every frame
+    pub fn transient_target(
+        &mut self,
+        name: &'static str,
+        desc: impl Fn(&RenderGraphTargets) -> RenderTargetDesc + 'a,
+    ) {
+        self.target_descs.push(TargetDecl {
+            name,
+            desc: Box::new(desc),
+            transient: true,
+        });
+    }
+
+    pub fn pass<'g>(&'g mut self, name: &'static str) -> PassBuilder<'g, 'a, F> {
+        PassBuilder {
+            graph: self,
+            name,
+            reads: Vec::new(),
+            output: None,
+            state: PipelineState::default(),
+            side_effects: false,
+        }
+    }
+
+    pub fn targets(&self) -> &RenderGraphTargets {
+        &self.targets
+    }
+
+    pub fn state_mut(&mut self, pass: &str) -> Option<&mut PipelineState> {
+        self.passes
+            .iter_mut()
+            .find(|x| x.name == pass)
+            .map(|x| &mut x.state)
+    }
+
+    pub fn set_enabled(&mut self, pass: &str, enabled: bool) {
+        if let Some(pass) = self.passes.iter_mut().find(|x| x.name == pass) {
+            pass.enabled = enabled;
+        }
+    }
+
+    /// Pass names in execution order
+    pub fn order(&self) -> Vec<&'static str> {
+        self.order
+            .iter()
+            .flatten()
+            .map(|&i| self.passes[i].name)
+            .collect()
+    }
+
+    /// Order passes treating every write as a new version of the target.
+    /// A reader depends on the last writer declared before it,
+    /// or on the final version when all writers are declared later.
+    /// Writers depend on the previous writer and on readers of the previous version.
+    /// Passes that contribute neither to the backbuffer nor to a side-effect pass are culled
+    fn sort(&self) -> Result<Vec<usize>, String> {
+        use std::collections::HashMap;
+        let n = self.passes.len();
+        let mut edges = vec![Vec::new(); n];
+        // writer -> reader and writer -> next writer, used for culling
+        let mut data_edges = vec![Vec::new(); n];
+        let mut writers: HashMap<&str, Vec<usize>> = HashMap::new();
+        for (i, pass) in self.passes.iter().enumerate() {
+            if let Some(target) = pass.output {
+                writers.entry(target).or_default().push(i);
+            }
+        }
+        // readers of each (target, writer) version
+        let mut version_readers: HashMap<(&str, usize), Vec<usize>> = HashMap::new();
+        for (b, pass) in self.passes.iter().enumerate() {
+            for read in &pass.reads {
+                let ws = writers
+                    .get(read.target)
+                    .map(|x| x.as_slice())
+                    .unwrap_or(&[]);
+                if read.history {
+                    if let Some(&first) = ws.first() {
+                        if first != b {
+                            edges[b].push(first);
+                        }
+                    }
+                    continue;
+                }
+                let writer = ws.iter().rev().find(|&&w| w < b).or(ws.last());
+                if let Some(&a) = writer {
+                    if a != b {
+                        edges[a].push(b);
+                        data_edges[a].push(b);
+                        version_readers.entry((read.target, a)).or_default().push(b);
+                    }
+                }
+            }
+        }
+        for (target, ws) in &writers {
+            for pair in ws.windows(2) {
+                let (a, b) = (pair[0], pair[1]);
+                edges[a].push(b);
+                data_edges[a].push(b);
+                for &r in version_readers.get(&(target, a)).into_iter().flatten() {
+                    if r != b {
+                        edges[r].push(b);
+                    }
+                }
+            }
+        }
+        let mut incoming = vec![0; n];
+        for &b in edges.iter().flatten() {
+            incoming[b] += 1;
+        }
+        let mut order = Vec::with_capacity(n);
+        let mut ready = (0..n).filter(|&i| incoming[i] == 0).collect::<Vec<_>>();
+        while !ready.is_empty() {
+            // keep declaration order between independent passes
+            ready.sort_unstable_by(|a, b| b.cmp(a));
+            let i = ready.pop().unwrap();
+            order.push(i);
+            for &j in &edges[i] {
+                incoming[j] -= 1;
+                if incoming[j] == 0 {
+                    ready.push(j);
+                }
+            }
+        }
+        if order.len() != n {
+            let cycle = (0..n)
+                .filter(|i| !order.contains(i))
+                .map(|i| self.passes[i].name)
+                .collect::<Vec<_>>();
+            return Err(format!("Render graph has a cycle between {:?}", cycle));
+        }
+        let mut alive = self
+            .passes
+            .iter()
+            .map(|x| x.output.is_none() || x.side_effects)
+            .collect::<Vec<_>>();
+        for &a in order.iter().rev() {
+            if data_edges[a].iter().any(|&b| alive[b]) {
+                alive[a] = true;
+            }
+        }
+        order.retain(|&i| alive[i]);
+        Ok(order)
+    }
+
+    /// Validate passes, order them and allocate targets for the window size
+    pub unsafe fn compile(
+        &mut self,
+        gl: &GlRef,
+        caps: &GlCaps,
+        width: u32,
+        height: u32,
+    ) -> Result<(), String> {
+        for pass in &self.passes {
+            let names = pass.reads.iter().map(|x| x.target).chain(pass.output);
+            for name in names {
+                if !self.target_descs.iter().any(|x| x.name == name) {
+                    return Err(format!(
+                        "Pass {} uses undeclared target {}",
+                        pass.name, name
+                    ));
+                }
+            }
+        }
+        let order = self.sort()?;
+        // first and last position in `order` of passes using each target
+        let mut lifetimes = std::collections::HashMap::new();
+        for (k, &i) in order.iter().enumerate() {
+            let pass = &self.passes[i];
+            for read in pass.reads.iter().filter(|x| x.history) {
+                if self
+                    .target_descs
+                    .iter()
+                    .any(|x| x.name == read.target && x.transient)
+                {
+                    return Err(format!(
+                        "Pass {} reads history of transient target {}",
+                        pass.name, read.target
+                    ));
+                }
+            }
+            for name in pass.reads.iter().map(|x| x.target).chain(pass.output) {
+                let lifetime = lifetimes.entry(name).or_insert((k, k));
+                lifetime.1 = k;
+            }
+        }
+        self.order = Some(order);
+        self.targets.targets.clear();
+        // (layout, index of the target owning the textures, lifetimes sharing them)
+        let mut shared = Vec::new();
+        for decl in &self.target_descs {
+            let mut desc = (decl.desc)(&self.targets);
+            let lifetime = lifetimes.get(decl.name).filter(|_| decl.transient);
+            if let Some(&(first, last)) = lifetime {
+                let layout = desc.layout();
+                let free = shared
+                    .iter_mut()
+                    .find(|(l, _, uses): &&mut (_, usize, Vec<_>)| {
+                        *l == layout && uses.iter().all(|&(a, b)| b < first || last < a)
+                    });
+                match free {
+                    Some((_, owner, uses)) => {
+                        desc = desc.alias(&self.targets.targets[*owner].1);
+                        uses.push((first, last));
+                    },
+                    None => shared.push((layout, self.targets.targets.len(), vec![(first, last)])),
+                }
+            }
+            let target = desc.build(gl, caps, width, height)?;
+            self.targets.targets.push((decl.name, target));
+        }
+        self.size = (width, height);
+        Ok(())
+    }
+
+    pub unsafe fn resize(&mut self, width: u32, height: u32) -> Result<(), String> {
+        self.size = (width, height);
+        let mut targets = self
+            .targets
+            .targets
+            .iter_mut()
+            .map(|x| &mut x.1)
+            .collect::<Vec<_>>();
+        resize_targets(&mut targets, width, height)
+    }
+
+    /// Run all enabled passes, returns number of draw calls
+    pub unsafe fn execute(&mut self, gl: &glow::Context, frame: &F) -> Result<u32, String> {
+        let Some(order) = &self.order else {
+            return Err("Render graph is not compiled".to_string());
+        };
+        let mut draw_calls = 0;
+        for &i in order {
+            let pass = &mut self.passes[i];
+            if !pass.enabled {
+                continue;
+            }
+            let (width, height) = match pass.output {
+                Some(name) => {
+                    let target = self.targets.get(name).unwrap();
+                    target.bind();
+                    target.size()
+                },
+                None => {
+                    gl.bind_framebuffer(glow::FRAMEBUFFER, None);
+                    self.size
+                },
+            };
+            gl.viewport(0, 0, width as i32, height as i32);
+            pass.state.apply(gl);
+            for read in &pass.reads {
+                let Some((attachment, unit)) = read.binding else {
+                    continue;
+                };
+                let tx = self
+                    .targets
+                    .get(read.target)
+                    .and_then(|x| x.texture(attachment))
+                    .ok_or_else(|| {
+                        format!(
+                            "Pass {}: target {} has no attachment 0x{:x}",
+                            pass.name, read.target, attachment
+                        )
+                    })?;
+                tx.bind(unit);
+            }
+            draw_calls += (pass.execute)(gl, frame);
+        }
+        Ok(draw_calls)
+    }
+}
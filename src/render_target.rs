@@ -0,0 +1,220 @@
+#![allow(dead_code)]
+use crate::gl_caps::GlCaps;
+use crate::gl_objects::{Framebuffer, GlRef, Texture2D};
+use crate::gl_utils::{GLDrawBuffer, GLTextureAttachment, TextureParams};
+use std::rc::Rc;
+
+enum Attachment {
+    Owned(TextureParams),
+    Shared(Rc<Texture2D>),
+}
+
+/// Description of a framebuffer and its texture attachments.
+/// Size is relative to the window size
+pub struct RenderTargetDesc {
+    attachments: Vec<(GLTextureAttachment, Attachment)>,
+    colors: u32,
+    scale: f32,
+}
+
+impl RenderTargetDesc {
+    /// Add color attachment with the next free `COLOR_ATTACHMENTi`
+    pub fn color(mut self, params: TextureParams) -> Self {
+        let attachment = glow::COLOR_ATTACHMENT0 + self.colors;
+        self.colors += 1;
+        self.attachments
+            .push((attachment, Attachment::Owned(params)));
+        self
+    }
+
+    pub fn depth(mut self, params: TextureParams) -> Self {
+        self.attachments
+            .push((glow::DEPTH_ATTACHMENT, Attachment::Owned(params)));
+        self
+    }
+
+    /// Attach texture owned by another render target.
+    /// It is not reallocated by this target on resize
+    pub fn shared(mut self, attachment: GLTextureAttachment, tx: &Rc<Texture2D>) -> Self {
+        if (glow::COLOR_ATTACHMENT0..glow::COLOR_ATTACHMENT0 + 32).contains(&attachment) {
+            self.colors = self.colors.max(attachment - glow::COLOR_ATTACHMENT0 + 1);
+        }
+        self.attachments
+            .push((attachment, Attachment::Shared(tx.clone())));
+        self
+    }
+
+    pub fn shared_depth(self, tx: &Rc<Texture2D>) -> Self {
+        self.shared(glow::DEPTH_ATTACHMENT, tx)
+    }
+
+    /// Size of the target relative to the window
+    pub fn scale(mut self, scale: f32) -> Self {
+        self.scale = scale;
+        self
+    }
+
+    /// Owned attachments and scale. Targets with the same layout can share textures
+    pub fn layout(&self) -> (Vec<(GLTextureAttachment, TextureParams)>, f32) {
+        let owned = self
+            .attachments
+            .iter()
+            .filter_map(|(attachment, desc)| match desc {
+                Attachment::Owned(params) => Some((*attachment, *params)),
+                Attachment::Shared(_) => None,
+            })
+            .collect();
+        (owned, self.scale)
+    }
+
+    /// Use textures of `target` for owned attachments instead of allocating them.
+    /// `target` must have the same layout
+    pub fn alias(mut self, target: &RenderTarget) -> Self {
+        for (attachment, desc) in &mut self.attachments {
+            if let (Attachment::Owned(_), Some(tx)) = (&desc, target.texture(*attachment)) {
+                *desc = Attachment::Shared(tx.clone());
+            }
+        }
+        self
+    }
+
+    pub unsafe fn build(
+        self,
+        gl: &GlRef,
+        caps: &GlCaps,
+        width: u32,
+        height: u32,
+    ) -> Result<RenderTarget, String> {
+        caps.check_draw_buffers(self.colors)?;
+        let size = scaled_size(self.scale, width, height);
+        let mut textures = Vec::with_capacity(self.attachments.len());
+        for (attachment, desc) in self.attachments {
+            let (params, tx) = match desc {
+                Attachment::Owned(params) => (
+                    Some(params),
+                    Rc::new(Texture2D::with_storage(gl, &params, size.0, size.1)?),
+                ),
+                Attachment::Shared(tx) => (None, tx),
+            };
+            textures.push(TargetTexture {
+                attachment,
+                params,
+                tx,
+            });
+        }
+        let target = RenderTarget {
+            fbo: Framebuffer::new(gl)?,
+            textures,
+            draw_buffers: (0..self.colors)
+                .map(|i| glow::COLOR_ATTACHMENT0 + i)
+                .collect(),
+            scale: self.scale,
+            size,
+        };
+        target.reattach()?;
+        Ok(target)
+    }
+}
+
+fn scaled_size(scale: f32, width: u32, height: u32) -> (u32, u32) {
+    let f = |x: u32| ((x as f32 * scale).round() as u32).max(1);
+    (f(width), f(height))
+}
+
+struct TargetTexture {
+    attachment: GLTextureAttachment,
+    // None for shared textures
+    params: Option<TextureParams>,
+    tx: Rc<Texture2D>,
+}
+
+pub struct RenderTarget {
+    fbo: Framebuffer,
+    textures: Vec<TargetTexture>,
+    draw_buffers: Vec<GLDrawBuffer>,
+    scale: f32,
+    size: (u32, u32),
+}
+
+impl RenderTarget {
+    pub fn builder() -> RenderTargetDesc {
+        RenderTargetDesc {
+            attachments: Vec::new(),
+            colors: 0,
+            scale: 1.0,
+        }
+    }
+
+    pub fn texture(&self, attachment: GLTextureAttachment) -> Option<&Rc<Texture2D>> {
+        self.textures
+            .iter()
+            .find(|x| x.attachment == attachment)
+            .map(|x| &x.tx)
+    }
+
+    pub fn color(&self, index: u32) -> Option<&Rc<Texture2D>> {
+        self.texture(glow::COLOR_ATTACHMENT0 + index)
+    }
+
+    pub fn depth(&self) -> Option<&Rc<Texture2D>> {
+        self.texture(glow::DEPTH_ATTACHMENT)
+    }
+
+    pub fn size(&self) -> (u32, u32) {
+        self.size
+    }
+
+    pub fn framebuffer(&self) -> &Framebuffer {
+        &self.fbo
+    }
+
+    pub unsafe fn bind(&self) {
+        self.fbo.bind();
+    }
+
+    unsafe fn reallocate(&mut self, width: u32, height: u32) {
+        self.size = scaled_size(self.scale, width, height);
+        for t in &self.textures {
+            if let Some(params) = &t.params {
+                t.tx.reset(params, self.size.0, self.size.1);
+            }
+        }
+    }
+
+    unsafe fn reattach(&self) -> Result<(), String> {
+        let textures = self
+            .textures
+            .iter()
+            .map(|t| (t.attachment, t.tx.as_ref()))
+            .collect::<Vec<_>>();
+        let draw_buffers = if self.draw_buffers.is_empty() {
+            None
+        } else {
+            Some(self.draw_buffers.as_slice())
+        };
+        self.fbo.attach(&textures, draw_buffers)
+    }
+
+    /// Recreate attachments for the new window size.
+    /// Use `resize_targets` when targets share textures
+    pub unsafe fn resize(&mut self, width: u32, height: u32) -> Result<(), String> {
+        self.reallocate(width, height);
+        self.reattach()
+    }
+}
+
+/// Resize all targets, reallocating every owned texture before reattaching,
+/// so shared attachments are always complete
+pub unsafe fn resize_targets(
+    targets: &mut [&mut RenderTarget],
+    width: u32,
+    height: u32,
+) -> Result<(), String> {
+    for target in targets.iter_mut() {
+        target.reallocate(width, height);
+    }
+    for target in targets.iter() {
+        target.reattach()?;
+    }
+    Ok(())
+}
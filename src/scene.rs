@@ -0,0 +1,280 @@
+//! Scene files: assets to load, model instances, camera, clear colour and lights.
+//!
+//! ```toml
+//! assets = ["./data/objects/dice.obj"]
+//! clear_color = [0.1, 0.2, 0.3]
+//!
+//! [camera]
+//! position = [5.2, 3.3, 0.0]
+//! rotation = [-1.57, -1.0] # yaw and pitch in radians
+//!
+//! [[lights]]
+//! position = [4.0, 3.0, 3.0]
+//! power = 50.0
+//!
+//! [[instances]]
+//! name = "table" # optional, referenced by parent
+//! model = "dice.obj#Dice" # asset file name or path, then model name, or a model id
+//! position = [0.0, 0.0, 0.0]
+//! rotation = [0.0, 45.0, 0.0] # degrees
+//!
+//! [[instances]]
+//! parent = "table" # declared earlier, transforms are relative to it
+//! model = "dice.obj"
+//! position = [0.0, 1.0, 0.0]
+//! ```
+use crate::glmc::Transform;
+use crate::model_registry::{ModelId, ModelRegistry};
+use crate::scene_graph::{Attachment, SceneGraph};
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use toml::Spanned;
+
+pub struct Camera {
+    pub position: glm::Vec3,
+    // yaw and pitch in radians
+    pub rotation: glm::Vec2,
+}
+
+pub struct Light {
+    pub position: glm::Vec3,
+    pub power: f32,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(untagged)]
+pub enum ModelRef {
+    Id(u32),
+    // "asset#model", or just the asset for all of its models
+    Name(String),
+}
+
+pub struct Instance {
+    pub name: Option<String>,
+    pub parent: Option<String>,
+    // `None` for grouping nodes
+    pub model: Option<ModelRef>,
+    // relative to the parent
+    pub transform: Transform,
+    pub tint: [f32; 4],
+    // line of the instance, for errors
+    line: usize,
+}
+
+pub struct Scene {
+    // file name for errors
+    pub name: String,
+    pub assets: Vec<PathBuf>,
+    pub instances: Vec<Instance>,
+    pub camera: Camera,
+    pub clear_color: [f32; 3],
+    pub lights: Vec<Light>,
+}
+
+// file layout
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SceneFile {
+    assets: Vec<String>,
+    #[serde(default)]
+    instances: Vec<Spanned<InstanceFile>>,
+    #[serde(default)]
+    camera: CameraFile,
+    #[serde(default = "default_clear_color")]
+    clear_color: [f32; 3],
+    #[serde(default)]
+    lights: Vec<LightFile>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct InstanceFile {
+    name: Option<String>,
+    parent: Option<String>,
+    model: Option<ModelRef>,
+    #[serde(default)]
+    position: [f32; 3],
+    #[serde(default)]
+    rotation: [f32; 3],
+    #[serde(default = "ones")]
+    scale: [f32; 3],
+    #[serde(default = "ones")]
+    tint: [f32; 4],
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct CameraFile {
+    #[serde(default)]
+    position: [f32; 3],
+    #[serde(default)]
+    rotation: [f32; 2],
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LightFile {
+    position: [f32; 3],
+    #[serde(default = "default_power")]
+    power: f32,
+}
+
+fn ones<const N: usize>() -> [f32; N] {
+    [1.; N]
+}
+
+fn default_clear_color() -> [f32; 3] {
+    [0.1, 0.2, 0.3]
+}
+
+fn default_power() -> f32 {
+    50.
+}
+
+fn vec3(v: [f32; 3]) -> glm::Vec3 {
+    glm::vec3(v[0], v[1], v[2])
+}
+
+/// 1-based line and column of byte `offset` in `text`
+fn line_column(text: &str, offset: usize) -> (usize, usize) {
+    let before = &text[..offset.min(text.len())];
+    let line = before.matches('\n').count() + 1;
+    let column = before.chars().rev().take_while(|&c| c != '\n').count() + 1;
+    (line, column)
+}
+
+impl Scene {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let text =
+            std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        Self::parse(&text, &path.display().to_string())
+    }
+
+    /// Parse scene `text`, errors start with `name` and the line of the problem
+    pub fn parse(text: &str, name: &str) -> Result<Self, String> {
+        let file: SceneFile = toml::from_str(text).map_err(|e| match e.span() {
+            Some(span) => {
+                let (line, column) = line_column(text, span.start);
+                format!("{}:{}:{}: {}", name, line, column, e.message())
+            },
+            None => format!("{}: {}", name, e.message()),
+        })?;
+        Ok(Self {
+            name: name.to_string(),
+            assets: file.assets.into_iter().map(PathBuf::from).collect(),
+            instances: file
+                .instances
+                .into_iter()
+                .map(|x| {
+                    let line = line_column(text, x.span().start).0;
+                    let x = x.into_inner();
+                    Instance {
+                        name: x.name,
+                        parent: x.parent,
+                        model: x.model,
+                        transform: Transform::new(
+                            vec3(x.position),
+                            vec3(x.rotation),
+                            vec3(x.scale),
+                        ),
+                        tint: x.tint,
+                        line,
+                    }
+                })
+                .collect(),
+            camera: Camera {
+                position: vec3(file.camera.position),
+                rotation: glm::vec2(file.camera.rotation[0], file.camera.rotation[1]),
+            },
+            clear_color: file.clear_color,
+            lights: file
+                .lights
+                .into_iter()
+                .map(|x| Light {
+                    position: vec3(x.position),
+                    power: x.power,
+                })
+                .collect(),
+        })
+    }
+
+    /// Asset paths as expected by `bake_assets`
+    pub fn asset_paths(&self) -> Vec<&Path> {
+        self.assets.iter().map(|x| x.as_path()).collect()
+    }
+
+    /// Models referenced by `instance`, errors point to its line
+    pub fn resolve(
+        &self,
+        instance: &Instance,
+        registry: &ModelRegistry,
+    ) -> Result<Vec<ModelId>, String> {
+        let err = |msg: String| format!("{}:{}: {}", self.name, instance.line, msg);
+        let Some(model) = &instance.model else {
+            return Ok(Vec::new());
+        };
+        match model {
+            ModelRef::Id(id) => match registry.index(ModelId(*id)) {
+                Some(_) => Ok(vec![ModelId(*id)]),
+                None => Err(err(format!(
+                    "model id {} out of range, {} models loaded",
+                    id,
+                    registry.len()
+                ))),
+            },
+            ModelRef::Name(name) => match registry.find(name) {
+                [] => Err(err(format!("no model {}", name))),
+                ids => Ok(ids.to_vec()),
+            },
+        }
+    }
+
+    /// Graph with a node per instance, light and the camera.
+    /// Meshes are attached with baked model indices
+    pub fn build_graph(&self, registry: &ModelRegistry) -> Result<SceneGraph, String> {
+        use std::collections::HashMap;
+        let mut graph = SceneGraph::new();
+        let at = |position| Transform::new(position, glm::vec3(0., 0., 0.), glm::vec3(1., 1., 1.));
+        let camera = graph.add(None, at(self.camera.position));
+        graph.attach(
+            camera,
+            Attachment::Camera {
+                rotation: self.camera.rotation,
+            },
+        );
+        for light in &self.lights {
+            let node = graph.add(None, at(light.position));
+            graph.attach(node, Attachment::Light { power: light.power });
+        }
+        let mut names = HashMap::new();
+        for instance in &self.instances {
+            let err = |msg: String| format!("{}:{}: {}", self.name, instance.line, msg);
+            let parent = match &instance.parent {
+                Some(name) => Some(*names.get(name.as_str()).ok_or_else(|| {
+                    err(format!(
+                        "parent {} is not declared before this instance",
+                        name
+                    ))
+                })?),
+                None => None,
+            };
+            let node = graph.add(parent, instance.transform);
+            if let Some(name) = &instance.name {
+                if names.insert(name.as_str(), node).is_some() {
+                    return Err(err(format!("instance name {} is already used", name)));
+                }
+            }
+            for id in self.resolve(instance, registry)? {
+                graph.attach(
+                    node,
+                    Attachment::Mesh {
+                        model: registry.index(id).unwrap(),
+                        tint: instance.tint,
+                    },
+                );
+            }
+        }
+        graph.update();
+        Ok(graph)
+    }
+}
@@ -0,0 +1,211 @@
+//! Hierarchy of transformed nodes with meshes, lights and cameras attached
+use crate::glmc::{model_mat_from, Transform, MAT4_ONE};
+use std::collections::HashMap;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct NodeId(u32);
+
+#[derive(Clone, Copy, Debug)]
+pub enum Attachment {
+    /// Baked model index drawn with the node's world matrix
+    Mesh { model: usize, tint: [f32; 4] },
+    /// Point light at the node's origin
+    Light { power: f32 },
+    /// Camera at the node's origin, yaw and pitch in radians
+    Camera { rotation: glm::Vec2 },
+}
+
+struct Node {
+    parent: Option<NodeId>,
+    children: Vec<NodeId>,
+    local: glm::Mat4,
+    // parent world * local, valid when not dirty
+    world: glm::Mat4,
+    dirty: bool,
+    attachments: Vec<Attachment>,
+}
+
+#[derive(Default)]
+pub struct SceneGraph {
+    nodes: Vec<Node>,
+    // (node, tint) of every model
+    meshes: HashMap<usize, Vec<(NodeId, [f32; 4])>>,
+}
+
+impl SceneGraph {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    /// Add a node with `local` transform relative to `parent`
+    pub fn add(&mut self, parent: Option<NodeId>, local: Transform) -> NodeId {
+        self.add_matrix(parent, model_mat_from(local))
+    }
+
+    /// Same as `add` with a local matrix, e.g. from an imported scene
+    pub fn add_matrix(&mut self, parent: Option<NodeId>, local: glm::Mat4) -> NodeId {
+        let id = NodeId(self.nodes.len() as u32);
+        self.nodes.push(Node {
+            parent,
+            children: Vec::new(),
+            local,
+            world: local,
+            dirty: true,
+            attachments: Vec::new(),
+        });
+        if let Some(parent) = parent {
+            self.node_mut(parent).children.push(id);
+        }
+        id
+    }
+
+    fn node(&self, id: NodeId) -> &Node {
+        &self.nodes[id.0 as usize]
+    }
+
+    fn node_mut(&mut self, id: NodeId) -> &mut Node {
+        &mut self.nodes[id.0 as usize]
+    }
+
+    pub fn parent(&self, id: NodeId) -> Option<NodeId> {
+        self.node(id).parent
+    }
+
+    pub fn children(&self, id: NodeId) -> &[NodeId] {
+        &self.node(id).children
+    }
+
+    /// Move `id` under `parent`, fails if `parent` is `id` or one of its descendants
+    pub fn set_parent(&mut self, id: NodeId, parent: Option<NodeId>) -> Result<(), String> {
+        let mut p = parent;
+        while let Some(x) = p {
+            if x == id {
+                return Err(format!("node {} can't be its own ancestor", id.0));
+            }
+            p = self.node(x).parent;
+        }
+        if let Some(old) = self.node(id).parent {
+            self.node_mut(old).children.retain(|&x| x != id);
+        }
+        if let Some(parent) = parent {
+            self.node_mut(parent).children.push(id);
+        }
+        let node = self.node_mut(id);
+        node.parent = parent;
+        node.dirty = true;
+        Ok(())
+    }
+
+    pub fn local(&self, id: NodeId) -> &glm::Mat4 {
+        &self.node(id).local
+    }
+
+    pub fn set_local(&mut self, id: NodeId, local: Transform) {
+        self.set_local_matrix(id, model_mat_from(local));
+    }
+
+    /// Descendants pick up the change in the next `update`
+    pub fn set_local_matrix(&mut self, id: NodeId, local: glm::Mat4) {
+        let node = self.node_mut(id);
+        node.local = local;
+        node.dirty = true;
+    }
+
+    /// World matrix as of the last `update`
+    pub fn world(&self, id: NodeId) -> &glm::Mat4 {
+        &self.node(id).world
+    }
+
+    /// Origin of the node in world space as of the last `update`
+    pub fn world_position(&self, id: NodeId) -> glm::Vec3 {
+        let c = self.world(id)[3];
+        glm::vec3(c.x, c.y, c.z)
+    }
+
+    /// Recompute world matrices of dirty nodes and their descendants,
+    /// returns number of nodes updated
+    pub fn update(&mut self) -> usize {
+        let mut updated = 0;
+        let mut stack = (0..self.nodes.len())
+            .filter(|&i| self.nodes[i].parent.is_none())
+            .map(|i| (NodeId(i as u32), false))
+            .collect::<Vec<_>>();
+        while let Some((id, parent_dirty)) = stack.pop() {
+            let node = self.node(id);
+            let dirty = node.dirty || parent_dirty;
+            if dirty {
+                let parent_world = node.parent.map_or(MAT4_ONE, |p| *self.world(p));
+                let node = self.node_mut(id);
+                node.world = parent_world * node.local;
+                node.dirty = false;
+                updated += 1;
+            }
+            stack.extend(self.node(id).children.iter().map(|&x| (x, dirty)));
+        }
+        updated
+    }
+
+    pub fn attach(&mut self, id: NodeId, attachment: Attachment) {
+        if let Attachment::Mesh { model, tint } = attachment {
+            self.meshes.entry(model).or_default().push((id, tint));
+        }
+        self.node_mut(id).attachments.push(attachment);
+    }
+
+    pub fn attachments(&self, id: NodeId) -> &[Attachment] {
+        &self.node(id).attachments
+    }
+
+    /// (world matrix, tint) of every instance of `model`
+    pub fn mesh_instances(&self, model: usize) -> impl Iterator<Item = (&glm::Mat4, &[f32; 4])> {
+        self.meshes
+            .get(&model)
+            .into_iter()
+            .flatten()
+            .map(|(id, tint)| (self.world(*id), tint))
+    }
+
+    /// (model, world matrix, tint) of all mesh instances
+    pub fn meshes(&self) -> impl Iterator<Item = (usize, &glm::Mat4, &[f32; 4])> {
+        self.meshes.iter().flat_map(move |(&model, x)| {
+            x.iter()
+                .map(move |(id, tint)| (model, self.world(*id), tint))
+        })
+    }
+
+    /// (node, power) of all lights in node order
+    pub fn lights(&self) -> impl Iterator<Item = (NodeId, f32)> + '_ {
+        self.attached(|a| match a {
+            Attachment::Light { power } => Some(*power),
+            _ => None,
+        })
+    }
+
+    /// (node, rotation) of all cameras in node order
+    pub fn cameras(&self) -> impl Iterator<Item = (NodeId, glm::Vec2)> + '_ {
+        self.attached(|a| match a {
+            Attachment::Camera { rotation } => Some(*rotation),
+            _ => None,
+        })
+    }
+
+    fn attached<T: 'static>(
+        &self,
+        f: fn(&Attachment) -> Option<T>,
+    ) -> impl Iterator<Item = (NodeId, T)> + '_ {
+        self.nodes.iter().enumerate().flat_map(move |(i, node)| {
+            node.attachments
+                .iter()
+                .filter_map(f)
+                .map(move |x| (NodeId(i as u32), x))
+        })
+    }
+}
@@ -0,0 +1,408 @@
+//! Quadric error metric simplification by collapsing edges onto existing vertices,
+//! so every level of detail shares the vertex buffer of the full mesh
+
+use std::collections::HashMap;
+
+/// Sum of weighted plane quadrics and of their weights
+#[derive(Clone, Copy, Default)]
+struct Quadric([f64; 10], f64);
+
+impl Quadric {
+    /// Squared distance to plane `ax + by + cz + d = 0`, scaled by `weight`
+    fn plane(n: [f64; 3], d: f64, weight: f64) -> Self {
+        let [a, b, c] = n;
+        let w = weight;
+        Self(
+            [
+                a * a * w,
+                a * b * w,
+                a * c * w,
+                a * d * w,
+                b * b * w,
+                b * c * w,
+                b * d * w,
+                c * c * w,
+                c * d * w,
+                d * d * w,
+            ],
+            w,
+        )
+    }
+
+    fn add(&mut self, other: &Self) {
+        for (a, b) in self.0.iter_mut().zip(other.0) {
+            *a += b;
+        }
+        self.1 += other.1;
+    }
+
+    /// Weighted mean of squared distances to the planes,
+    /// so the error doesn't grow with triangle area
+    fn error(&self, p: [f64; 3]) -> f64 {
+        if self.1 <= 0. {
+            return 0.;
+        }
+        let q = &self.0;
+        let [x, y, z] = p;
+        let e = q[0] * x * x
+            + 2. * q[1] * x * y
+            + 2. * q[2] * x * z
+            + 2. * q[3] * x
+            + q[4] * y * y
+            + 2. * q[5] * y * z
+            + 2. * q[6] * y
+            + q[7] * z * z
+            + 2. * q[8] * z
+            + q[9];
+        e.max(0.) / self.1
+    }
+}
+
+fn sub(a: [f64; 3], b: [f64; 3]) -> [f64; 3] {
+    [a[0] - b[0], a[1] - b[1], a[2] - b[2]]
+}
+
+fn cross(a: [f64; 3], b: [f64; 3]) -> [f64; 3] {
+    [
+        a[1] * b[2] - a[2] * b[1],
+        a[2] * b[0] - a[0] * b[2],
+        a[0] * b[1] - a[1] * b[0],
+    ]
+}
+
+fn dot(a: [f64; 3], b: [f64; 3]) -> f64 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+/// How a vertex may move in a collapse
+#[derive(Clone, Copy, PartialEq)]
+enum Kind {
+    Free,
+    /// On an open border, e.g. between materials, or on a seam that turns
+    Locked,
+    /// On a straight uv or normal seam between two welded positions.
+    /// All vertices at the position collapse along the seam together
+    Seam(usize, usize),
+}
+
+/// Welded position of every vertex, vertices on seams share it
+fn weld(positions: &[[f64; 3]]) -> Vec<usize> {
+    let mut welded = HashMap::new();
+    positions
+        .iter()
+        .map(|p| {
+            let len = welded.len();
+            *welded.entry(p.map(f64::to_bits)).or_insert(len)
+        })
+        .collect()
+}
+
+/// Kind of every vertex, and vertices of every welded position used by `indices`
+fn classify(positions: &[[f64; 3]], ids: &[usize], indices: &[u32]) -> (Vec<Kind>, Vec<Vec<u32>>) {
+    let welded = ids.iter().max().map_or(0, |x| x + 1);
+    let mut copies = vec![Vec::new(); welded];
+    // triangle count and distinct vertex pairs of every welded edge
+    let mut edges = HashMap::<_, (usize, Vec<(u32, u32)>)>::new();
+    for tri in indices.chunks_exact(3) {
+        for k in 0..3 {
+            let (a, b) = (tri[k], tri[(k + 1) % 3]);
+            let (ia, ib) = (ids[a as usize], ids[b as usize]);
+            let (key, pair) = if ia <= ib {
+                ((ia, ib), (a, b))
+            } else {
+                ((ib, ia), (b, a))
+            };
+            let entry = edges.entry(key).or_default();
+            entry.0 += 1;
+            if !entry.1.contains(&pair) {
+                entry.1.push(pair);
+            }
+            if !copies[ia].contains(&a) {
+                copies[ia].push(a);
+            }
+        }
+    }
+    // edges of a single triangle are borders,
+    // edges between different vertex pairs are seams
+    let mut border = vec![false; welded];
+    let mut seams = vec![Vec::new(); welded];
+    for (&(a, b), (count, pairs)) in &edges {
+        if *count == 1 {
+            border[a] = true;
+            border[b] = true;
+        } else if pairs.len() > 1 {
+            seams[a].push(b);
+            seams[b].push(a);
+        }
+    }
+    let at = |id: usize| positions[copies[id][0] as usize];
+    let kinds = ids
+        .iter()
+        .map(|&id| {
+            if border[id] {
+                return Kind::Locked;
+            }
+            if copies[id].len() < 2 {
+                return Kind::Free;
+            }
+            let [a, b] = seams[id][..] else {
+                return Kind::Locked;
+            };
+            let (d1, d2) = (sub(at(a), at(id)), sub(at(b), at(id)));
+            let c = cross(d1, d2);
+            let straight = dot(d1, d2) < 0. && dot(c, c) <= 1e-12 * dot(d1, d1) * dot(d2, d2);
+            if straight {
+                Kind::Seam(a, b)
+            } else {
+                Kind::Locked
+            }
+        })
+        .collect();
+    (kinds, copies)
+}
+
+/// Collapse of every vertex in `from` onto a neighbour at welded position `to`,
+/// `None` if some vertex has no such neighbour
+fn seam_collapses(
+    indices: &[u32],
+    vertex_tris: &[Vec<usize>],
+    ids: &[usize],
+    from: &[u32],
+    to: usize,
+) -> Option<Vec<(usize, usize)>> {
+    from.iter()
+        .map(|&u| {
+            vertex_tris[u as usize]
+                .iter()
+                .flat_map(|&t| &indices[3 * t..3 * t + 3])
+                .find(|&&i| ids[i as usize] == to)
+                .map(|&v| (u as usize, v as usize))
+        })
+        .collect()
+}
+
+/// Simplify triangles over `vertices` (xyz) to about `target` indices.
+/// Returns new indices and the largest collapse error as a distance,
+/// stops early when no collapse under `max_error` is left
+pub fn simplify(
+    vertices: &[f32],
+    indices: &[u32],
+    target: usize,
+    max_error: f32,
+) -> (Vec<u32>, f32) {
+    let positions = vertices
+        .chunks_exact(3)
+        .map(|v| [v[0] as f64, v[1] as f64, v[2] as f64])
+        .collect::<Vec<_>>();
+    let ids = weld(&positions);
+    let mut quadrics = vec![Quadric::default(); positions.len()];
+    for tri in indices.chunks_exact(3) {
+        let p = [0, 1, 2].map(|k| positions[tri[k] as usize]);
+        let n = cross(sub(p[1], p[0]), sub(p[2], p[0]));
+        let area2 = dot(n, n).sqrt();
+        if area2 <= f64::EPSILON {
+            continue;
+        }
+        let n = n.map(|x| x / area2);
+        let q = Quadric::plane(n, -dot(n, p[0]), area2 * 0.5);
+        for &i in tri {
+            quadrics[i as usize].add(&q);
+        }
+    }
+
+    let max_cost = (max_error as f64).powi(2);
+    let mut result = indices.to_vec();
+    let mut worst = 0f64;
+    let mut remap = (0..positions.len() as u32).collect::<Vec<_>>();
+    while result.len() > target {
+        let (kinds, copies) = classify(&positions, &ids, &result);
+        // triangles around every vertex
+        let mut vertex_tris = vec![Vec::new(); positions.len()];
+        for (t, tri) in result.chunks_exact(3).enumerate() {
+            for &i in tri {
+                vertex_tris[i as usize].push(t);
+            }
+        }
+        // candidate collapses u -> v of every edge in both directions,
+        // as (cost, collapses) since seams move several vertices
+        let mut candidates = Vec::new();
+        for tri in result.chunks_exact(3) {
+            for k in 0..3 {
+                let (a, b) = (tri[k] as usize, tri[(k + 1) % 3] as usize);
+                for (u, v) in [(a, b), (b, a)] {
+                    let collapses = match kinds[u] {
+                        Kind::Free => vec![(u, v)],
+                        Kind::Seam(a, b) if ids[v] == a || ids[v] == b => {
+                            let from = &copies[ids[u]];
+                            match seam_collapses(&result, &vertex_tris, &ids, from, ids[v]) {
+                                Some(x) => x,
+                                None => continue,
+                            }
+                        },
+                        _ => continue,
+                    };
+                    let mut q = Quadric::default();
+                    let mut targets = Vec::new();
+                    for &(u, v) in &collapses {
+                        q.add(&quadrics[u]);
+                        if !targets.contains(&v) {
+                            targets.push(v);
+                            q.add(&quadrics[v]);
+                        }
+                    }
+                    let cost = q.error(positions[v]);
+                    if cost <= max_cost {
+                        candidates.push((cost, collapses));
+                    }
+                }
+            }
+        }
+        if candidates.is_empty() {
+            break;
+        }
+        candidates.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+        // collapse independent edges, each vertex is touched once per pass
+        let mut touched = vec![false; positions.len()];
+        let mut removed = 0;
+        let to_remove = (result.len() - target) / 3;
+        for (cost, collapses) in candidates {
+            if removed >= to_remove {
+                break;
+            }
+            let blocked = collapses.iter().any(|&(u, v)| {
+                touched[u] || touched[v] || flips(&positions, &result, &vertex_tris[u], u, v)
+            });
+            if blocked {
+                continue;
+            }
+            for (u, v) in collapses {
+                touched[u] = true;
+                touched[v] = true;
+                for &t in &vertex_tris[u] {
+                    for &i in &result[3 * t..3 * t + 3] {
+                        touched[i as usize] = true;
+                    }
+                    removed += result[3 * t..3 * t + 3].contains(&(v as u32)) as usize;
+                }
+                remap[u] = v as u32;
+                let q = quadrics[u];
+                quadrics[v].add(&q);
+            }
+            worst = worst.max(cost);
+        }
+        if removed == 0 {
+            break;
+        }
+        // apply collapses and drop degenerate triangles
+        let mut next = Vec::with_capacity(result.len());
+        for tri in result.chunks_exact(3) {
+            let t = [0, 1, 2].map(|k| remap[tri[k] as usize]);
+            if t[0] != t[1] && t[1] != t[2] && t[0] != t[2] {
+                next.extend(t);
+            }
+        }
+        result = next;
+    }
+    (result, worst.sqrt() as f32)
+}
+
+/// Collapse of `u` onto `v` turns some triangle around `u` over
+fn flips(positions: &[[f64; 3]], indices: &[u32], tris: &[usize], u: usize, v: usize) -> bool {
+    for &t in tris {
+        let tri = &indices[3 * t..3 * t + 3];
+        if tri.contains(&(v as u32)) {
+            // collapsed away
+            continue;
+        }
+        let p = [0, 1, 2].map(|k| positions[tri[k] as usize]);
+        let moved = [0, 1, 2].map(|k| {
+            if tri[k] as usize == u {
+                positions[v]
+            } else {
+                p[k]
+            }
+        });
+        let n0 = cross(sub(p[1], p[0]), sub(p[2], p[0]));
+        let n1 = cross(sub(moved[1], moved[0]), sub(moved[2], moved[0]));
+        if dot(n0, n1) <= 0. {
+            return true;
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // square fan around a free apex at height `h`, border corners stay put
+    fn pyramid(h: f32, scale: f32) -> (Vec<f32>, Vec<u32>) {
+        let vertices = [
+            [0., 0., h],
+            [1., 1., 0.],
+            [-1., 1., 0.],
+            [-1., -1., 0.],
+            [1., -1., 0.],
+        ]
+        .iter()
+        .flatten()
+        .map(|x| x * scale)
+        .collect();
+        (vertices, vec![0, 1, 2, 0, 2, 3, 0, 3, 4, 0, 4, 1])
+    }
+
+    #[test]
+    fn collapse_error_is_a_distance() {
+        let (vertices, indices) = pyramid(1., 1.);
+        let (lod, error) = simplify(&vertices, &indices, 6, 10.);
+        assert_eq!(lod.len(), 6);
+        // apex onto a corner: that corner's two faces keep it in their planes,
+        // the other two are 2h / sqrt(1 + h^2) away, mean over the 6 faces
+        // of apex and corner
+        let (h, corner_faces, faces) = (1f32, 2., 6.);
+        let distance2 = (2. * h).powi(2) / (1. + h * h);
+        let expected = (corner_faces * distance2 / faces).sqrt();
+        assert!((error - expected).abs() < 1e-5, "{} != {}", error, expected);
+    }
+
+    #[test]
+    fn straight_seams_collapse_without_cracks() {
+        // flat 4x2 grid, columns 0..=2 and 2..=4 have their own vertices
+        // as if their normals differed, so column 2 is a seam
+        let mut vertices = Vec::new();
+        let mut index = HashMap::new();
+        for (side, columns) in [(0, 0..=2), (1, 2..=4)] {
+            for x in columns {
+                for y in 0..=2 {
+                    index.insert((side, x, y), vertices.len() as u32 / 3);
+                    vertices.extend([x as f32, y as f32, 0.]);
+                }
+            }
+        }
+        let mut indices = Vec::new();
+        for x in 0..4 {
+            let side = (x >= 2) as usize;
+            for y in 0..2 {
+                let i = |dx, dy| index[&(side, x + dx, y + dy)];
+                indices.extend([i(0, 0), i(1, 0), i(1, 1), i(0, 0), i(1, 1), i(0, 1)]);
+            }
+        }
+        let (lod, error) = simplify(&vertices, &indices, 0, 1.);
+        assert_eq!(error, 0.);
+        // middle of the seam moves with both of its vertices or not at all
+        let seam = [index[&(0, 2, 1)], index[&(1, 2, 1)]].map(|i| lod.contains(&i));
+        assert_eq!(seam, [false, false]);
+        // every inner position collapsed, two triangles each
+        assert_eq!(lod.len(), indices.len() - 3 * 6);
+    }
+
+    #[test]
+    fn collapse_error_scales_with_mesh() {
+        let (vertices, indices) = pyramid(0.5, 1.);
+        let (_, error) = simplify(&vertices, &indices, 6, 100.);
+        let (vertices, indices) = pyramid(0.5, 10.);
+        let (_, scaled) = simplify(&vertices, &indices, 6, 100.);
+        assert!((scaled / error - 10.).abs() < 1e-3, "{} {}", error, scaled);
+    }
+}
@@ -0,0 +1,116 @@
+use glm::{Vec2, Vec3};
+use std::collections::HashMap;
+
+fn vec3_at(data: &[f32], i: usize) -> Vec3 {
+    glm::vec3(data[3 * i], data[3 * i + 1], data[3 * i + 2])
+}
+
+fn vec2_at(data: &[f32], i: usize) -> Vec2 {
+    if data.len() < 2 * (i + 1) {
+        return glm::vec2(0., 0.);
+    }
+    glm::vec2(data[2 * i], data[2 * i + 1])
+}
+
+fn bits<const N: usize>(data: &[f32], i: usize) -> [u32; N] {
+    std::array::from_fn(|k| data.get(N * i + k).map(|x| x.to_bits()).unwrap_or(0))
+}
+
+/// Any unit vector perpendicular to `n`
+fn perpendicular(n: Vec3) -> Vec3 {
+    let axis = if n.x.abs() < 0.9 {
+        glm::vec3(1., 0., 0.)
+    } else {
+        glm::vec3(0., 1., 0.)
+    };
+    glm::normalize(glm::cross(n, axis))
+}
+
+/// `v` projected onto the plane of unit normal `n` and normalized,
+/// zero when `v` is (nearly) parallel to `n`
+fn project_normalize(v: Vec3, n: Vec3) -> Vec3 {
+    let v = v - n * glm::dot(n, v);
+    if glm::length(v) > f32::EPSILON {
+        glm::normalize(v)
+    } else {
+        glm::vec3(0., 0., 0.)
+    }
+}
+
+/// Tangents with handedness sign in w for every index, 4 floats each.
+///
+/// Follows MikkTSpace: per-face tangents are projected onto the plane of
+/// every corner's normal, normalized, weighted by corner angle and
+/// averaged over corners sharing position, normal, uv and handedness,
+/// then orthogonalized against the vertex normal again.
+/// Bitangent is `cross(normal, tangent.xyz) * tangent.w`
+pub fn generate_tangents(
+    vertices: &[f32],
+    normals: &[f32],
+    uvs: &[f32],
+    indices: &[u32],
+) -> Vec<f32> {
+    let tris = indices.len() / 3;
+    let mut sums: HashMap<_, (Vec3, Vec3)> = HashMap::new();
+    let mut corner_keys = Vec::with_capacity(indices.len());
+    for t in 0..tris {
+        let idx = [0, 1, 2].map(|k| indices[3 * t + k] as usize);
+        let p = idx.map(|i| vec3_at(vertices, i));
+        let uv = idx.map(|i| vec2_at(uvs, i));
+        let (e1, e2) = (p[1] - p[0], p[2] - p[0]);
+        let (d1, d2) = (uv[1] - uv[0], uv[2] - uv[0]);
+        let det = d1.x * d2.y - d2.x * d1.y;
+        // degenerate uv mapping gives no direction
+        let (tangent, bitangent) = if det.abs() > f32::EPSILON {
+            let r = 1. / det;
+            ((e1 * d2.y - e2 * d1.y) * r, (e2 * d1.x - e1 * d2.x) * r)
+        } else {
+            (glm::vec3(0., 0., 0.), glm::vec3(0., 0., 0.))
+        };
+        let face_normal = glm::cross(e1, e2);
+        let sign = glm::dot(glm::cross(face_normal, tangent), bitangent) < 0.;
+        for k in 0..3 {
+            let i = idx[k];
+            let n = vec3_at(normals, i);
+            let key = (
+                bits::<3>(vertices, i),
+                bits::<3>(normals, i),
+                bits::<2>(uvs, i),
+                sign,
+            );
+            let (a, b) = (p[(k + 1) % 3] - p[k], p[(k + 2) % 3] - p[k]);
+            let len = glm::length(a) * glm::length(b);
+            let angle = if len > f32::EPSILON {
+                (glm::dot(a, b) / len).clamp(-1., 1.).acos()
+            } else {
+                0.
+            };
+            let entry = sums
+                .entry(key)
+                .or_insert((glm::vec3(0., 0., 0.), glm::vec3(0., 0., 0.)));
+            entry.0 = entry.0 + project_normalize(tangent, n) * angle;
+            entry.1 = entry.1 + project_normalize(bitangent, n) * angle;
+            corner_keys.push(key);
+        }
+    }
+
+    let mut res = Vec::with_capacity(indices.len() * 4);
+    for (corner, key) in corner_keys.iter().enumerate() {
+        let n = vec3_at(normals, indices[corner] as usize);
+        let (t, b) = sums[key];
+        // Gram-Schmidt
+        let t = t - n * glm::dot(n, t);
+        let t = if glm::length(t) > f32::EPSILON {
+            glm::normalize(t)
+        } else {
+            perpendicular(n)
+        };
+        let w = if glm::dot(glm::cross(n, t), b) < 0. {
+            -1.
+        } else {
+            1.
+        };
+        res.extend([t.x, t.y, t.z, w]);
+    }
+    res
+}
@@ -0,0 +1,85 @@
+use crate::assets::MeshData;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum UvProjection {
+    /// Project onto the plane of the two largest bounding box extents
+    Planar,
+    /// Project every face onto the box side facing its normal
+    #[default]
+    Box,
+}
+
+impl UvProjection {
+    /// Bytes identifying the projection, for cache keys
+    pub fn key(&self) -> Vec<u8> {
+        vec![*self as u8]
+    }
+}
+
+fn bounds(vertices: &[f32]) -> ([f32; 3], [f32; 3]) {
+    let mut min = [f32::MAX; 3];
+    let mut max = [f32::MIN; 3];
+    for v in vertices.chunks_exact(3) {
+        for k in 0..3 {
+            min[k] = min[k].min(v[k]);
+            max[k] = max[k].max(v[k]);
+        }
+    }
+    (min, max)
+}
+
+/// Generate uvs in [0, 1] scaled uniformly by the largest extent of the mesh.
+/// Box projection unindexes the mesh, since faces of different sides
+/// need different uvs for shared positions
+pub fn generate_uvs(mesh: &mut MeshData, projection: UvProjection) {
+    let (min, max) = bounds(&mesh.vertices);
+    let extent = [0, 1, 2].map(|k| (max[k] - min[k]).max(0.));
+    let size = extent.iter().copied().fold(0., f32::max);
+    let scale = if size > f32::EPSILON { 1. / size } else { 1. };
+    let project =
+        |v: &[f32], (a, b): (usize, usize)| [(v[a] - min[a]) * scale, (v[b] - min[b]) * scale];
+    match projection {
+        UvProjection::Planar => {
+            // drop the axis with the smallest extent
+            let drop = (0..3)
+                .min_by(|&a, &b| extent[a].total_cmp(&extent[b]))
+                .unwrap();
+            let axes = match drop {
+                0 => (2, 1),
+                1 => (0, 2),
+                _ => (0, 1),
+            };
+            mesh.uvs = mesh
+                .vertices
+                .chunks_exact(3)
+                .flat_map(|v| project(v, axes))
+                .collect();
+        },
+        UvProjection::Box => {
+            mesh.uvs.clear();
+            mesh.unindex();
+            let mut uvs = Vec::with_capacity(mesh.vertices.len() / 3 * 2);
+            for tri in mesh.vertices.chunks_exact(9) {
+                let (p0, p1, p2) = (&tri[0..3], &tri[3..6], &tri[6..9]);
+                let e1 = [0, 1, 2].map(|k| p1[k] - p0[k]);
+                let e2 = [0, 1, 2].map(|k| p2[k] - p0[k]);
+                let n = [
+                    (e1[1] * e2[2] - e1[2] * e2[1]).abs(),
+                    (e1[2] * e2[0] - e1[0] * e2[2]).abs(),
+                    (e1[0] * e2[1] - e1[1] * e2[0]).abs(),
+                ];
+                let axes = if n[0] >= n[1] && n[0] >= n[2] {
+                    (2, 1)
+                } else if n[1] >= n[2] {
+                    (0, 2)
+                } else {
+                    (0, 1)
+                };
+                for v in [p0, p1, p2] {
+                    uvs.extend(project(v, axes));
+                }
+            }
+            mesh.uvs = uvs;
+        },
+    }
+}
@@ -0,0 +1,182 @@
+//! Triangle and vertex reordering for the post-transform vertex cache
+//! and vertex fetch, using Tom Forsyth's linear-speed algorithm
+
+// LRU cache size the reordering is tuned for
+const CACHE_SIZE: usize = 32;
+const CACHE_DECAY_POWER: f32 = 1.5;
+const LAST_TRI_SCORE: f32 = 0.75;
+const VALENCE_BOOST_SCALE: f32 = 2.0;
+const VALENCE_BOOST_POWER: f32 = 0.5;
+
+/// FIFO cache size used for statistics, close to real hardware
+pub const STATS_CACHE_SIZE: usize = 16;
+
+fn vertex_score(cache_pos: Option<usize>, remaining: usize) -> f32 {
+    if remaining == 0 {
+        return -1.;
+    }
+    let cache_score = match cache_pos {
+        // the last triangle's vertices are scored the same
+        // so the next triangle doesn't prefer any edge of it
+        Some(pos) if pos < 3 => LAST_TRI_SCORE,
+        Some(pos) => {
+            let scale = 1. / (CACHE_SIZE - 3) as f32;
+            (1. - (pos - 3) as f32 * scale).powf(CACHE_DECAY_POWER)
+        },
+        None => 0.,
+    };
+    // favour vertices with few remaining triangles to finish them off
+    cache_score + VALENCE_BOOST_SCALE * (remaining as f32).powf(-VALENCE_BOOST_POWER)
+}
+
+/// Reorder triangles for the post-transform cache.
+/// Indices must be in `0..vertex_count`
+pub fn optimize_vertex_cache(indices: &mut [u32], vertex_count: usize) {
+    let tri_count = indices.len() / 3;
+    if tri_count == 0 {
+        return;
+    }
+    // triangles using every vertex
+    let mut remaining = vec![0usize; vertex_count];
+    for &i in indices.iter() {
+        remaining[i as usize] += 1;
+    }
+    let mut offsets = Vec::with_capacity(vertex_count + 1);
+    let mut sum = 0;
+    for &count in &remaining {
+        offsets.push(sum);
+        sum += count;
+    }
+    offsets.push(sum);
+    let mut vertex_tris = vec![0usize; sum];
+    let mut fill = offsets.clone();
+    for (corner, &i) in indices.iter().enumerate() {
+        vertex_tris[fill[i as usize]] = corner / 3;
+        fill[i as usize] += 1;
+    }
+
+    let mut cache_pos = vec![None; vertex_count];
+    let mut scores = (0..vertex_count)
+        .map(|v| vertex_score(None, remaining[v]))
+        .collect::<Vec<_>>();
+    let tri_score = |t: usize, scores: &[f32]| {
+        (0..3)
+            .map(|k| scores[indices[3 * t + k] as usize])
+            .sum::<f32>()
+    };
+    let mut tri_scores = (0..tri_count)
+        .map(|t| tri_score(t, &scores))
+        .collect::<Vec<_>>();
+    let mut emitted = vec![false; tri_count];
+    let mut order = Vec::with_capacity(tri_count);
+    let mut cache: Vec<u32> = Vec::with_capacity(CACHE_SIZE + 3);
+    let mut best = None;
+    // first triangle that may not be emitted yet, for the fallback scan
+    let mut scan_from = 0;
+
+    while order.len() < tri_count {
+        let t = match best {
+            Some(t) => t,
+            None => {
+                while emitted[scan_from] {
+                    scan_from += 1;
+                }
+                (scan_from..tri_count)
+                    .filter(|&t| !emitted[t])
+                    .max_by(|&a, &b| tri_scores[a].total_cmp(&tri_scores[b]))
+                    .unwrap()
+            },
+        };
+        emitted[t] = true;
+        order.push(t);
+        let tri = [0, 1, 2].map(|k| indices[3 * t + k]);
+        for &v in &tri {
+            let v = v as usize;
+            remaining[v] -= 1;
+            let tris = &mut vertex_tris[offsets[v]..offsets[v + 1]];
+            if let Some(k) = tris[..remaining[v] + 1].iter().position(|&x| x == t) {
+                tris.swap(k, remaining[v]);
+            }
+        }
+        // move triangle vertices to the front of the cache
+        let mut new_cache = tri.to_vec();
+        new_cache.extend(cache.iter().filter(|v| !tri.contains(v)));
+        for &v in new_cache.iter().skip(CACHE_SIZE) {
+            cache_pos[v as usize] = None;
+        }
+        let touched = new_cache.clone();
+        new_cache.truncate(CACHE_SIZE);
+        for (pos, &v) in new_cache.iter().enumerate() {
+            cache_pos[v as usize] = Some(pos);
+        }
+        cache = new_cache;
+
+        best = None;
+        let mut best_score = -1.;
+        for &v in &touched {
+            let v = v as usize;
+            scores[v] = vertex_score(cache_pos[v], remaining[v]);
+        }
+        for &v in &touched {
+            let v = v as usize;
+            for &u in &vertex_tris[offsets[v]..offsets[v] + remaining[v]] {
+                tri_scores[u] = tri_score(u, &scores);
+                if tri_scores[u] > best_score {
+                    best_score = tri_scores[u];
+                    best = Some(u);
+                }
+            }
+        }
+    }
+
+    let old = indices.to_vec();
+    for (i, t) in order.into_iter().enumerate() {
+        indices[3 * i..3 * i + 3].copy_from_slice(&old[3 * t..3 * t + 3]);
+    }
+}
+
+/// New vertex order so vertices are fetched in the order they are first used.
+/// Indices are rewritten, the result maps new positions to old vertices
+pub fn optimize_vertex_fetch(indices: &mut [u32], vertex_count: usize) -> Vec<u32> {
+    let mut remap = vec![u32::MAX; vertex_count];
+    let mut order = Vec::with_capacity(vertex_count);
+    for i in indices.iter_mut() {
+        let new = &mut remap[*i as usize];
+        if *new == u32::MAX {
+            *new = order.len() as u32;
+            order.push(*i);
+        }
+        *i = *new;
+    }
+    order
+}
+
+/// Reorder attribute with `n` components per vertex by the result of `optimize_vertex_fetch`
+pub fn remap_attribute(data: &mut [f32], order: &[u32], n: usize) {
+    let old = data.to_vec();
+    for (new, &old_index) in order.iter().enumerate() {
+        let old_index = old_index as usize;
+        data[n * new..n * (new + 1)].copy_from_slice(&old[n * old_index..n * (old_index + 1)]);
+    }
+}
+
+/// (ACMR, ATVR): transformed vertices per triangle and per unique vertex
+/// for a FIFO cache of `cache_size`
+pub fn cache_stats(indices: &[u32], vertex_count: usize, cache_size: usize) -> (f32, f32) {
+    let mut cache = std::collections::VecDeque::with_capacity(cache_size);
+    let mut misses = 0;
+    for i in indices {
+        if !cache.contains(i) {
+            misses += 1;
+            if cache.len() == cache_size {
+                cache.pop_front();
+            }
+            cache.push_back(*i);
+        }
+    }
+    let tris = (indices.len() / 3).max(1);
+    (
+        misses as f32 / tris as f32,
+        misses as f32 / vertex_count.max(1) as f32,
+    )
+}
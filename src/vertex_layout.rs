@@ -0,0 +1,412 @@
+use crate::assets::BakedMeshData;
+use crate::glmc::MAT4_ONE;
+use crate::mesh_cache::BufferData;
+
+/// Vertex attributes of baked meshes, values are shader locations
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Attribute {
+    Position = 0,
+    Uv = 1,
+    Normal = 2,
+    Tangent = 3,
+}
+
+impl Attribute {
+    pub fn components(&self) -> usize {
+        match self {
+            Attribute::Position | Attribute::Normal => 3,
+            Attribute::Uv => 2,
+            Attribute::Tangent => 4,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Attribute::Position => "position",
+            Attribute::Uv => "uv",
+            Attribute::Normal => "normal",
+            Attribute::Tangent => "tangent",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    F32,
+    F16,
+    /// Signed normalized 10-10-10-2, for unit vectors with sign in w
+    Snorm10,
+    /// Unsigned normalized 16 bit. Positions are relative to the model bounds
+    /// and decoded with `EncodedVertices::position_decode`, uvs cover the atlas
+    Unorm16,
+}
+
+impl Format {
+    /// Size in bytes, padded to 4 bytes
+    pub fn size(&self, components: usize) -> usize {
+        match self {
+            Format::F32 => 4 * components,
+            Format::F16 | Format::Unorm16 => (2 * components).div_ceil(4) * 4,
+            Format::Snorm10 => 4,
+        }
+    }
+
+    /// (GL type, components, normalized) for `vertex_attrib_pointer_f32`
+    pub fn gl_type(&self, components: usize) -> (u32, i32, bool) {
+        match self {
+            Format::F32 => (glow::FLOAT, components as i32, false),
+            Format::F16 => (glow::HALF_FLOAT, components as i32, false),
+            Format::Snorm10 => (glow::INT_2_10_10_10_REV, 4, true),
+            Format::Unorm16 => (glow::UNSIGNED_SHORT, components as i32, true),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AttributeDesc {
+    pub attribute: Attribute,
+    pub format: Format,
+    // index into `VertexLayout::streams`
+    pub stream: usize,
+    pub offset: usize,
+}
+
+/// How baked vertex attributes are stored in vertex buffers
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VertexLayout {
+    pub attributes: Vec<AttributeDesc>,
+    // stride of every buffer
+    pub streams: Vec<usize>,
+}
+
+impl VertexLayout {
+    /// All attributes in one buffer
+    pub fn interleaved(attributes: &[(Attribute, Format)]) -> Result<Self, String> {
+        let mut offset = 0;
+        let mut res = Vec::with_capacity(attributes.len());
+        for &(attribute, format) in attributes {
+            check_format(attribute, format)?;
+            res.push(AttributeDesc {
+                attribute,
+                format,
+                stream: 0,
+                offset,
+            });
+            offset += format.size(attribute.components());
+        }
+        Ok(Self {
+            attributes: res,
+            streams: vec![offset],
+        })
+    }
+
+    /// Every attribute in its own buffer
+    pub fn separate(attributes: &[(Attribute, Format)]) -> Result<Self, String> {
+        let mut res = Self {
+            attributes: Vec::with_capacity(attributes.len()),
+            streams: Vec::with_capacity(attributes.len()),
+        };
+        for &(attribute, format) in attributes {
+            check_format(attribute, format)?;
+            res.attributes.push(AttributeDesc {
+                attribute,
+                format,
+                stream: res.streams.len(),
+                offset: 0,
+            });
+            res.streams.push(format.size(attribute.components()));
+        }
+        Ok(res)
+    }
+
+    /// Separate float buffers, 48 bytes per vertex
+    pub fn full() -> Self {
+        Self::separate(&[
+            (Attribute::Position, Format::F32),
+            (Attribute::Uv, Format::F32),
+            (Attribute::Normal, Format::F32),
+            (Attribute::Tangent, Format::F32),
+        ])
+        .unwrap()
+    }
+
+    /// Interleaved quantised attributes, 20 bytes per vertex
+    pub fn compact() -> Self {
+        Self::interleaved(&[
+            (Attribute::Position, Format::Unorm16),
+            (Attribute::Uv, Format::F16),
+            (Attribute::Normal, Format::Snorm10),
+            (Attribute::Tangent, Format::Snorm10),
+        ])
+        .unwrap()
+    }
+
+    /// Bytes identifying the layout, for cache keys
+    pub fn key(&self) -> Vec<u8> {
+        let mut res = Vec::new();
+        for x in &self.attributes {
+            res.extend([x.attribute as u8, x.format as u8]);
+            res.extend((x.stream as u64).to_le_bytes());
+            res.extend((x.offset as u64).to_le_bytes());
+        }
+        for &stride in &self.streams {
+            res.extend((stride as u64).to_le_bytes());
+        }
+        res
+    }
+
+    /// F16 uvs step by 1/2048 in [0.5, 1], so from a 2048 atlas up texel centers
+    /// can't be addressed. Same layout with such uvs stored as Unorm16 instead
+    pub fn for_atlas(&self, atlas_size: usize) -> Self {
+        let mut res = self.clone();
+        if atlas_size >= 2048 {
+            for desc in &mut res.attributes {
+                if desc.attribute == Attribute::Uv && desc.format == Format::F16 {
+                    desc.format = Format::Unorm16;
+                }
+            }
+        }
+        res
+    }
+
+    pub fn vertex_size(&self) -> usize {
+        self.streams.iter().sum()
+    }
+
+    fn find(&self, attribute: Attribute) -> Option<&AttributeDesc> {
+        self.attributes.iter().find(|x| x.attribute == attribute)
+    }
+}
+
+impl Default for VertexLayout {
+    fn default() -> Self {
+        Self::compact()
+    }
+}
+
+fn check_format(attribute: Attribute, format: Format) -> Result<(), String> {
+    let ok = match format {
+        Format::F32 | Format::F16 => true,
+        Format::Snorm10 => matches!(attribute, Attribute::Normal | Attribute::Tangent),
+        Format::Unorm16 => matches!(attribute, Attribute::Position | Attribute::Uv),
+    };
+    if ok {
+        Ok(())
+    } else {
+        Err(format!(
+            "{:?} format is not supported for {}",
+            format,
+            attribute.name()
+        ))
+    }
+}
+
+/// Nearest half float, overflow becomes infinity
+pub fn f32_to_f16(x: f32) -> u16 {
+    let bits = x.to_bits();
+    let sign = ((bits >> 16) & 0x8000) as u16;
+    let exp = ((bits >> 23) & 0xff) as i32;
+    let mantissa = bits & 0x7f_ffff;
+    if exp == 0xff {
+        // inf or nan
+        return sign | 0x7c00 | if mantissa != 0 { 0x200 } else { 0 };
+    }
+    let exp = exp - 127 + 15;
+    if exp >= 0x1f {
+        return sign | 0x7c00;
+    }
+    if exp <= 0 {
+        if exp < -10 {
+            return sign;
+        }
+        // subnormal
+        let mantissa = mantissa | 0x80_0000;
+        let shift = (14 - exp) as u32;
+        let half = mantissa >> shift;
+        let round = (mantissa >> (shift - 1)) & 1;
+        return sign | (half + round) as u16;
+    }
+    let half = ((exp as u32) << 10) | (mantissa >> 13);
+    // round to nearest, carry into exponent is fine
+    let round = (mantissa >> 12) & 1;
+    sign | (half + round) as u16
+}
+
+fn snorm10(v: &[f32]) -> u32 {
+    let q = |x: f32, max: f32| (x.clamp(-1., 1.) * max).round() as i32;
+    let w = v.get(3).map(|&w| q(w, 1.)).unwrap_or(0);
+    (q(v[0], 511.) as u32 & 0x3ff)
+        | ((q(v[1], 511.) as u32 & 0x3ff) << 10)
+        | ((q(v[2], 511.) as u32 & 0x3ff) << 20)
+        | ((w as u32 & 0x3) << 30)
+}
+
+/// Vertex buffers in a `VertexLayout`
+pub struct EncodedVertices {
+    pub layout: VertexLayout,
+    pub streams: Vec<BufferData>,
+    // matrix from stored to model space positions of every model
+    pub position_decode: Vec<glm::Mat4>,
+}
+
+pub fn encode_vertices(data: &BakedMeshData, layout: &VertexLayout) -> EncodedVertices {
+    let count = data.vertices.len() / 3;
+    let mut streams = layout
+        .streams
+        .iter()
+        .map(|stride| vec![0u8; stride * count])
+        .collect::<Vec<_>>();
+    let mut position_decode = Vec::with_capacity(data.model_count());
+    let quantised = layout
+        .find(Attribute::Position)
+        .map(|x| x.format == Format::Unorm16)
+        .unwrap_or(false);
+    // (offset, scale) of positions of every vertex
+    let mut bounds = vec![([0f32; 3], [1f32; 3]); count];
+    for model in 0..data.model_count() {
+        let range = data.vertex_offsets[model] as usize..data.vertex_offsets[model + 1] as usize;
+        if !quantised {
+            position_decode.push(MAT4_ONE);
+            continue;
+        }
+        let (min, max) = data.vertices[3 * range.start..3 * range.end]
+            .chunks_exact(3)
+            .fold(([f32::MAX; 3], [f32::MIN; 3]), |(min, max), v| {
+                (
+                    [0, 1, 2].map(|k| min[k].min(v[k])),
+                    [0, 1, 2].map(|k| max[k].max(v[k])),
+                )
+            });
+        let scale = [0, 1, 2].map(|k| (max[k] - min[k]).max(f32::MIN_POSITIVE));
+        for b in &mut bounds[range] {
+            *b = (min, scale);
+        }
+        let mut decode = glm::ext::translate(&MAT4_ONE, glm::vec3(min[0], min[1], min[2]));
+        decode = glm::ext::scale(&decode, glm::vec3(scale[0], scale[1], scale[2]));
+        position_decode.push(decode);
+    }
+
+    for desc in &layout.attributes {
+        let components = desc.attribute.components();
+        let src = match desc.attribute {
+            Attribute::Position => &data.vertices,
+            Attribute::Uv => &data.uvs,
+            Attribute::Normal => &data.normals,
+            Attribute::Tangent => &data.tangents,
+        };
+        let stride = layout.streams[desc.stream];
+        let stream = &mut streams[desc.stream];
+        for (i, v) in src.chunks_exact(components).enumerate() {
+            let dst = &mut stream[i * stride + desc.offset..];
+            match desc.format {
+                Format::F32 => {
+                    for (k, x) in v.iter().enumerate() {
+                        dst[4 * k..4 * k + 4].copy_from_slice(&x.to_le_bytes());
+                    }
+                },
+                Format::F16 => {
+                    for (k, x) in v.iter().enumerate() {
+                        dst[2 * k..2 * k + 2].copy_from_slice(&f32_to_f16(*x).to_le_bytes());
+                    }
+                },
+                Format::Snorm10 => dst[..4].copy_from_slice(&snorm10(v).to_le_bytes()),
+                Format::Unorm16 => {
+                    let (min, scale) = match desc.attribute {
+                        Attribute::Position => bounds[i],
+                        _ => ([0.; 3], [1.; 3]),
+                    };
+                    for (k, x) in v.iter().enumerate() {
+                        let q = ((x - min[k]) / scale[k]).clamp(0., 1.) * 65535.;
+                        dst[2 * k..2 * k + 2].copy_from_slice(&(q.round() as u16).to_le_bytes());
+                    }
+                },
+            }
+        }
+    }
+    EncodedVertices {
+        layout: layout.clone(),
+        streams: streams.into_iter().map(BufferData::Owned).collect(),
+        position_decode,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // GL decoding of one signed 10 bit component
+    fn snorm10_component(packed: u32, k: u32) -> f32 {
+        let x = ((packed >> (10 * k)) & 0x3ff) as i32;
+        let x = if x >= 512 { x - 1024 } else { x };
+        (x as f32 / 511.).max(-1.)
+    }
+
+    #[test]
+    fn f16_exact_values() {
+        assert_eq!(f32_to_f16(0.), 0);
+        assert_eq!(f32_to_f16(-0.), 0x8000);
+        assert_eq!(f32_to_f16(1.), 0x3c00);
+        assert_eq!(f32_to_f16(-1.), 0xbc00);
+        assert_eq!(f32_to_f16(0.5), 0x3800);
+        assert_eq!(f32_to_f16(65504.), 0x7bff);
+    }
+
+    #[test]
+    fn f16_rounds_to_nearest() {
+        let ulp = 2f32.powi(-10);
+        assert_eq!(f32_to_f16(1. + 0.25 * ulp), 0x3c00);
+        assert_eq!(f32_to_f16(1. + 0.75 * ulp), 0x3c01);
+        assert_eq!(f32_to_f16(1. - 0.2 * ulp), 0x3c00);
+        // rounding up a full mantissa carries into the exponent
+        assert_eq!(f32_to_f16(2048. - 0.25), 0x6800);
+    }
+
+    #[test]
+    fn f16_denormals() {
+        assert_eq!(f32_to_f16(2f32.powi(-14)), 0x0400);
+        assert_eq!(f32_to_f16(2f32.powi(-15)), 0x0200);
+        assert_eq!(f32_to_f16(2f32.powi(-24)), 0x0001);
+        assert_eq!(f32_to_f16(-2f32.powi(-24)), 0x8001);
+        assert_eq!(f32_to_f16(3. * 2f32.powi(-24)), 0x0003);
+        assert_eq!(f32_to_f16(2f32.powi(-26)), 0);
+        assert_eq!(f32_to_f16(1e-10), 0);
+    }
+
+    #[test]
+    fn f16_overflow_is_infinity() {
+        assert_eq!(f32_to_f16(65520.), 0x7c00);
+        assert_eq!(f32_to_f16(1e6), 0x7c00);
+        assert_eq!(f32_to_f16(-1e6), 0xfc00);
+        assert_eq!(f32_to_f16(f32::INFINITY), 0x7c00);
+        assert_eq!(f32_to_f16(f32::NEG_INFINITY), 0xfc00);
+        let nan = f32_to_f16(f32::NAN);
+        assert_eq!(nan & 0x7c00, 0x7c00);
+        assert_ne!(nan & 0x3ff, 0);
+    }
+
+    #[test]
+    fn snorm10_unit_values() {
+        let one = snorm10(&[1., 1., 1., 1.]);
+        assert_eq!(one, 511 | 511 << 10 | 511 << 20 | 1 << 30);
+        let minus_one = snorm10(&[-1., -1., -1., -1.]);
+        assert_eq!(minus_one, 0x201 | 0x201 << 10 | 0x201 << 20 | 3 << 30);
+        for k in 0..3 {
+            assert_eq!(snorm10_component(one, k), 1.);
+            assert_eq!(snorm10_component(minus_one, k), -1.);
+        }
+        // out of range clamps, missing w is 0
+        assert_eq!(snorm10(&[2., -2., 0.]), 511 | 0x201 << 10);
+        assert_eq!(snorm10_component(snorm10(&[0.5, 0., 0.]), 0), 256. / 511.);
+    }
+
+    #[test]
+    fn large_atlas_uvs_are_unorm16() {
+        let uv = |layout: &VertexLayout| layout.find(Attribute::Uv).unwrap().format;
+        let layout = VertexLayout::compact();
+        assert_eq!(uv(&layout.for_atlas(1024)), Format::F16);
+        let large = layout.for_atlas(4096);
+        assert_eq!(uv(&large), Format::Unorm16);
+        assert_eq!(large.streams, layout.streams);
+        assert_eq!(uv(&VertexLayout::full().for_atlas(4096)), Format::F32);
+    }
+}